@@ -1,5 +1,16 @@
-pub mod c;
-pub mod r;
-pub mod u;
-pub mod d;
-pub mod make;
\ No newline at end of file
+pub mod c;
+pub mod r;
+pub mod u;
+pub mod d;
+pub mod make;
+pub mod record;
+pub mod wal;
+pub mod builder;
+pub mod check;
+pub mod cache;
+pub mod index;
+pub mod export;
+pub mod buffer;
+pub mod stats;
+#[cfg(feature = "tokio")]
+pub mod async_api;
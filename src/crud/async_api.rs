@@ -0,0 +1,128 @@
+//! Async versions of the hot read/write paths, for callers on a tokio
+//! runtime. Each one clones the `DATABASE` handle and runs the blocking call
+//! on tokio's blocking thread pool, so the executor never waits on `std::fs`.
+
+use std::collections::HashMap;
+
+use crate::crud::make::{Data, DATABASE, Row};
+use crate::crud::u::CMP;
+use crate::error::Result;
+use crate::QueryBuilder;
+
+/// Runs `f` with an owned copy of `db` on the blocking pool.
+async fn blocking<T, F>(db: &DATABASE, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(DATABASE) -> T + Send + 'static,
+{
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || f(db))
+        .await
+        .expect("blocking database task panicked")
+}
+
+impl DATABASE {
+    pub async fn add_row_async(&self, table_name: String, row: Row, overwrite: bool) -> Result<()> {
+        blocking(self, move |db| db.add_row(table_name, row, overwrite)).await
+    }
+
+    pub async fn add_rows_async(&self, table_name: String, rows: Vec<Row>, overwrite: bool) -> Result<()> {
+        blocking(self, move |db| db.add_rows(table_name, rows, overwrite)).await
+    }
+
+    pub async fn get_by_id_async(&self, table_name: String, id: String) -> Result<Option<Row>> {
+        blocking(self, move |db| db.get_by_id(table_name, id)).await
+    }
+
+    pub async fn get_all_async(&self, table_name: String) -> Result<HashMap<String, Row>> {
+        blocking(self, move |db| db.get_all(table_name)).await
+    }
+
+    pub async fn get_where_async(
+        &self,
+        table_name: String,
+        field_name: String,
+        field_value: Data,
+        multi: bool,
+        cmp: CMP,
+    ) -> Result<Vec<(String, Row)>> {
+        blocking(self, move |db| db.get_where(table_name, field_name, field_value, multi, cmp)).await
+    }
+
+    pub async fn update_row_by_id_async(&self, table_name: String, id: String, new_row: Row) -> Result<Option<Row>> {
+        blocking(self, move |db| db.update_row_by_id(table_name, id, new_row)).await
+    }
+
+    pub async fn delete_row_by_id_async(&self, table_name: String, id: String) -> Result<Option<Row>> {
+        blocking(self, move |db| db.delete_row_by_id(table_name, id)).await
+    }
+}
+
+impl QueryBuilder<'_> {
+    pub async fn execute_async(&self) -> Vec<Row> {
+        // closure filters borrow from the caller and can't move to another
+        // thread, so such queries run right here
+        if !self.filters.is_empty() {
+            return self.execute();
+        }
+
+        let table = self.table.clone();
+        let conditions = self.conditions.clone();
+        let limit = self.limit;
+        let sort_field = self.sort_field.clone();
+        let sort_ascending = self.sort_ascending;
+
+        blocking(self.db, move |db| {
+            QueryBuilder {
+                db: &db,
+                table,
+                conditions,
+                filters: vec![],
+                limit,
+                sort_field,
+                sort_ascending,
+            }
+            .execute()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crud::make::Type;
+    use crate::Operator;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_inserts_and_reads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..5 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(20.0 + i as f64), String::new()));
+            db.add_row_async("users".to_string(), row, false).await.unwrap();
+        }
+
+        let row = db.get_by_id_async("users".to_string(), "u3".to_string()).await.unwrap().unwrap();
+        assert_eq!(row["age"].0, Data::NUMBER(23.0));
+
+        let older = db
+            .query("users".to_string())
+            .where_("age", Operator::Gte, Data::NUMBER(22.0))
+            .execute_async()
+            .await;
+        assert_eq!(older.len(), 3);
+
+        assert!(db.delete_row_by_id_async("users".to_string(), "u0".to_string()).await.unwrap().is_some());
+        assert_eq!(db.get_all_async("users".to_string()).await.unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::crud::make::{DATABASE, Row, TABLE};
+use crate::crud::stats::bump;
+use crate::error::{Error, Result};
+
+/// A shard held in memory by a `ShardBuffer`: the rows inserted into it since
+/// it was last written, each with its `overwrite` flag.
+struct Shard {
+    /// Keys of the rows the shard file held when it was loaded or last written.
+    stored: HashSet<String>,
+    pending: HashMap<String, (Row, bool)>,
+    last_used: u64,
+}
+
+/// Inserts into one table that keep up to `capacity` shards in memory, so a
+/// run of inserts landing in the same few shards reads each of them once and
+/// writes it once, on `flush`, instead of on every row. When a new shard is
+/// needed and the buffer is full, the least recently used one is written out
+/// and dropped. Dropping the buffer flushes it; call `flush` to see the error
+/// if that fails.
+///
+/// Rows aren't visible to the rest of the database until flushed. Writing a
+/// shard merges its buffered rows into the file as it is then, under the
+/// table's write lock, so writes made elsewhere meanwhile are kept. Unique
+/// constraints and foreign keys are checked at that point too, for all the
+/// rows being written at once; a violation fails the write and leaves them
+/// buffered.
+pub struct ShardBuffer<'a> {
+    db: &'a DATABASE,
+    table: String,
+    schema: TABLE,
+    capacity: usize,
+    shards: HashMap<PathBuf, Shard>,
+    clock: u64,
+    shard_writes: usize,
+}
+
+impl DATABASE {
+    /// A `ShardBuffer` for `table_name` holding at most `capacity` shards.
+    pub fn shard_buffer(&self, table_name: &str, capacity: usize) -> Result<ShardBuffer<'_>> {
+        self.check_writable()?;
+        if capacity == 0 {
+            return Err(Error::InvalidArgument("shard buffer capacity must be at least 1".to_string()));
+        }
+        Ok(ShardBuffer {
+            db: self,
+            table: table_name.to_string(),
+            schema: self.get_schema(table_name)?,
+            capacity,
+            shards: HashMap::new(),
+            clock: 0,
+            shard_writes: 0,
+        })
+    }
+}
+
+impl ShardBuffer<'_> {
+    /// Validates `row` against the schema like `add_row` and adds it to its
+    /// buffered shard. Without `overwrite`, an id already stored or buffered
+    /// is refused right away; constraints are checked when the row is written.
+    pub fn insert(&mut self, mut row: Row, overwrite: bool) -> Result<()> {
+        DATABASE::prepare_row(&mut row, &self.schema)?;
+        let row_id = self.schema.row_id(&row)?;
+        let id = DATABASE::string_to_numerical_uuid(&row_id);
+
+        let shard = self.shard(self.db.shard_path(&self.table, &row_id))?;
+        if !overwrite && (shard.stored.contains(&id) || shard.pending.contains_key(&id)) {
+            return Err(Error::UniqueViolation { id });
+        }
+        shard.pending.insert(id, (row, overwrite));
+        Ok(())
+    }
+
+    /// Writes every buffered shard holding rows not written yet.
+    pub fn flush(&mut self) -> Result<()> {
+        let lock = self.db.table_lock(&self.table);
+        let _guard = lock.write().unwrap();
+        let batch: Vec<(&String, &Row)> = self
+            .shards
+            .values()
+            .flat_map(|shard| shard.pending.iter().map(|(id, (row, _))| (id, row)))
+            .collect();
+        self.db.check_constraints(&self.table, &self.schema, &batch)?;
+
+        for (path, shard) in &mut self.shards {
+            if !shard.pending.is_empty() {
+                write_shard(self.db, path, shard)?;
+                self.shard_writes += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many shard files this buffer has written so far.
+    pub fn shard_writes(&self) -> usize {
+        self.shard_writes
+    }
+
+    /// The buffered shard at `path`, loading it (and first evicting the least
+    /// recently used shard if the buffer is full) if it isn't held yet.
+    fn shard(&mut self, path: PathBuf) -> Result<&mut Shard> {
+        self.clock += 1;
+        if !self.shards.contains_key(&path) {
+            if self.shards.len() == self.capacity {
+                let oldest = self
+                    .shards
+                    .iter()
+                    .min_by_key(|(_, shard)| shard.last_used)
+                    .map(|(path, _)| path.clone())
+                    .unwrap();
+                let shard = self.shards.get_mut(&oldest).unwrap();
+                if !shard.pending.is_empty() {
+                    let lock = self.db.table_lock(&self.table);
+                    let _guard = lock.write().unwrap();
+                    let batch: Vec<(&String, &Row)> = shard.pending.iter().map(|(id, (row, _))| (id, row)).collect();
+                    self.db.check_constraints(&self.table, &self.schema, &batch)?;
+                    write_shard(self.db, &oldest, shard)?;
+                    self.shard_writes += 1;
+                }
+                self.shards.remove(&oldest);
+            }
+
+            let stored = if path.exists() {
+                self.db.load_shard::<Row>(&path)?.into_keys().collect()
+            } else {
+                fs::create_dir_all(path.parent().unwrap())?;
+                HashSet::new()
+            };
+            self.shards.insert(path.clone(), Shard { stored, pending: HashMap::new(), last_used: 0 });
+        }
+
+        let shard = self.shards.get_mut(&path).unwrap();
+        shard.last_used = self.clock;
+        Ok(shard)
+    }
+}
+
+/// Merges `shard`'s pending rows into the shard file at `path` as it is now.
+/// Fails with `Error::UniqueViolation`, writing nothing, if a row inserted
+/// without `overwrite` has had its id written meanwhile. The caller holds the
+/// table's write lock and has checked the rows' constraints.
+fn write_shard(db: &DATABASE, path: &Path, shard: &mut Shard) -> Result<()> {
+    let mut rows = if path.exists() { db.load_shard::<Row>(path)? } else { HashMap::new() };
+    let (mut inserted, mut updated) = (0, 0);
+    for (id, (row, overwrite)) in &shard.pending {
+        if rows.contains_key(id) {
+            if !overwrite {
+                return Err(Error::UniqueViolation { id: id.clone() });
+            }
+            updated += 1;
+        } else {
+            inserted += 1;
+        }
+        rows.insert(id.clone(), row.clone());
+    }
+
+    db.save_shard(path, &rows)?;
+    shard.stored = rows.into_keys().collect();
+    shard.pending.clear();
+    bump(&db.counters.rows_inserted, inserted);
+    bump(&db.counters.rows_updated, updated);
+    Ok(())
+}
+
+impl Drop for ShardBuffer<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("failed to flush shard buffer for {}: {}", self.table, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crud::builder::DatabaseBuilder;
+    use crate::crud::make::{Data, Type};
+
+    use super::*;
+
+    fn user(id: usize) -> Row {
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING(format!("u{}", id)), String::new()));
+        row
+    }
+
+    #[test]
+    fn test_shard_buffer_writes_each_shard_once() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // wide shards: every row lands in one of a handful of files
+        let db = DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .shard_digits(9)
+            .build()
+            .unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let capacity = 16;
+        let mut buffer = db.shard_buffer("users", capacity).unwrap();
+        for i in 0..500 {
+            buffer.insert(user(i), false).unwrap();
+        }
+        // nothing is on disk until the flush
+        assert!(db.get_by_id("users".to_string(), "u0".to_string()).unwrap().is_none());
+        buffer.flush().unwrap();
+
+        let shards = db.shard_stats("users").iter().filter(|(_, rows)| *rows > 0).count();
+        // every shard fit in the buffer, so none was written before the flush
+        assert!(shards <= capacity, "{} shards", shards);
+        assert_eq!(buffer.shard_writes(), shards);
+        assert_eq!(db.count_rows("users".to_string()), 500);
+        assert!(matches!(buffer.insert(user(7), false), Err(Error::UniqueViolation { .. })));
+        drop(buffer);
+
+        // the same inserts one at a time write a shard per row
+        let before = db.stats().shard_writes;
+        for i in 500..1000 {
+            db.add_row("users".to_string(), user(i), false).unwrap();
+        }
+        assert_eq!(db.stats().shard_writes - before, 500);
+    }
+
+    #[test]
+    fn test_shard_buffer_evicts_and_flushes_on_drop() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .shard_digits(2)
+            .build()
+            .unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        {
+            let mut buffer = db.shard_buffer("users", 2).unwrap();
+            for i in 0..50 {
+                buffer.insert(user(i), false).unwrap();
+            }
+            // rows in evicted shards are already written
+            assert!(db.count_rows("users".to_string()) > 0);
+            assert!(db.shard_buffer("users", 0).is_err());
+        }
+        assert_eq!(db.count_rows("users".to_string()), 50);
+    }
+
+    #[test]
+    fn test_shard_buffer_keeps_writes_made_meanwhile() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .shard_digits(9)
+            .build()
+            .unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("email".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        db.add_unique("users", "unique_email", &["email"]).unwrap();
+        let user = |id: usize, email: &str| {
+            let mut row = user(id);
+            row.insert("email".to_string(), (Data::STRING(email.to_string()), String::new()));
+            row
+        };
+        // a row sharing u0's shard
+        let neighbour = (1..)
+            .find(|i| db.shard_path("users", &format!("u{}", i)) == db.shard_path("users", "u0"))
+            .unwrap();
+
+        let mut buffer = db.shard_buffer("users", 4).unwrap();
+        buffer.insert(user(0, "a@x"), false).unwrap();
+        db.add_row("users".to_string(), user(neighbour, "b@x"), false).unwrap();
+        let inserted = db.stats().rows_inserted;
+        buffer.flush().unwrap();
+        assert_eq!(db.stats().rows_inserted, inserted + 1);
+        assert!(db.get_by_id("users".to_string(), "u0".to_string()).unwrap().is_some());
+        assert!(db.get_by_id("users".to_string(), format!("u{}", neighbour)).unwrap().is_some());
+
+        // rows buffered together are checked together, and stay buffered
+        buffer.insert(user(1000, "c@x"), false).unwrap();
+        buffer.insert(user(1001, "c@x"), false).unwrap();
+        assert!(matches!(buffer.flush(), Err(Error::UniqueConstraint { .. })));
+        assert_eq!(db.stats().rows_inserted, inserted + 1);
+        assert_eq!(db.count_rows("users".to_string()), 2);
+        buffer.insert(user(1001, "d@x"), true).unwrap();
+        buffer.flush().unwrap();
+        assert_eq!(db.count_rows("users".to_string()), 4);
+    }
+}
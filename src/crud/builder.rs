@@ -0,0 +1,227 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::crud::cache::QueryCache;
+use crate::crud::make::{DATABASE, DEFAULT_SHARD_DIGITS};
+use crate::error::{Error, Result};
+
+/// Configures and opens a `DATABASE`.
+///
+/// ```ignore
+/// let db = DatabaseBuilder::new()
+///     .path("./dbfiles")
+///     .shard_digits(5)
+///     .create_if_missing(true)
+///     .build()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct DatabaseBuilder {
+    path: Option<String>,
+    shard_digits: usize,
+    pretty: bool,
+    create_if_missing: bool,
+    query_cache: bool,
+    read_only: bool,
+}
+
+impl Default for DatabaseBuilder {
+    fn default() -> Self {
+        Self {
+            path: None,
+            shard_digits: DEFAULT_SHARD_DIGITS,
+            pretty: false,
+            create_if_missing: true,
+            query_cache: false,
+            read_only: false,
+        }
+    }
+}
+
+impl DatabaseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Root directory of the database. Required.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// How many trailing digits of the id hash one shard file covers. Fewer
+    /// digits means more, smaller shards. Must match whatever the existing
+    /// data on disk was written with.
+    pub fn shard_digits(mut self, digits: usize) -> Self {
+        self.shard_digits = digits;
+        self
+    }
+
+    /// Pretty-print shard files. Off by default; compact files are about half
+    /// the size and much faster to write.
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// Create the database directory when it doesn't exist yet (default).
+    /// When off, `build` fails on a missing directory instead.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Cache the results of `QueryBuilder::execute` until the table is next
+    /// written to. Off by default. Only writes made through this handle or its
+    /// clones clear the cache, so leave it off if anything else writes to the
+    /// same directory.
+    pub fn query_cache(mut self, enabled: bool) -> Self {
+        self.query_cache = enabled;
+        self
+    }
+
+    /// Open the database read-only; see `DATABASE::open_read_only`. Off by
+    /// default. The directory must already exist.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Opens the database, finishing any interrupted compaction and replaying
+    /// the write-ahead log the same way `DATABASE::init` does (unless it is
+    /// read-only).
+    pub fn build(self) -> Result<DATABASE> {
+        let path = self
+            .path
+            .ok_or_else(|| Error::InvalidArgument("DatabaseBuilder needs a path".to_string()))?;
+        if self.shard_digits == 0 {
+            return Err(Error::InvalidArgument("shard_digits must be at least 1".to_string()));
+        }
+
+        if !Path::new(&path).exists() {
+            if !self.create_if_missing || self.read_only {
+                return Err(Error::NotFound(format!("database directory '{}'", path)));
+            }
+            fs::create_dir_all(&path)?;
+        }
+        // also repairs a directory left behind by an interrupted first open
+        if !self.read_only {
+            fs::create_dir_all(format!("{}/migrations", path))?;
+            let applied = format!("{}/migrations/.migrations_applied", path);
+            if !Path::new(&applied).exists() {
+                fs::write(applied, b"[]")?;
+            }
+        }
+
+        let db = DATABASE {
+            path,
+            schema_cache: Arc::default(),
+            shard_digits: self.shard_digits,
+            pretty: self.pretty,
+            query_cache: self.query_cache.then(|| Arc::new(Mutex::new(QueryCache::default()))),
+            table_locks: Arc::default(),
+            read_only: self.read_only,
+            counters: Arc::default(),
+        };
+        if !db.read_only {
+            db.finish_compactions()?;
+            db.replay_wal()?;
+        }
+        Ok(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::crud::make::{Data, Type};
+
+    use super::*;
+
+    #[test]
+    fn test_builder_custom_shard_width() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("db");
+        let db = DatabaseBuilder::new()
+            .path(path.to_str().unwrap())
+            .shard_digits(5)
+            .pretty(true)
+            .build()
+            .unwrap();
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..30 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        for i in 0..30 {
+            let id = format!("u{}", i);
+            let hash = DATABASE::string_to_numerical_uuid(&id);
+            let base = &hash[..hash.len() - 5];
+            let expected = format!("{base}00000-{base}99999.txt");
+            let shard = path.join("users").join(expected);
+            assert!(fs::read_to_string(shard).unwrap().contains('\n'));
+            assert!(db.get_by_id("users".to_string(), id.clone()).unwrap().is_some());
+            assert!(db.delete_row_by_id("users".to_string(), id).unwrap().is_some());
+        }
+        assert_eq!(db.count_rows("users".to_string()), 0);
+    }
+
+    #[test]
+    fn test_builder_create_if_missing() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("db");
+
+        let missing = DatabaseBuilder::new()
+            .path(path.to_str().unwrap())
+            .create_if_missing(false)
+            .build();
+        assert!(missing.is_err());
+        assert!(!path.exists());
+
+        assert!(DatabaseBuilder::new().build().is_err());
+
+        DatabaseBuilder::new().path(path.to_str().unwrap()).build().unwrap();
+        assert!(path.join("migrations").join(".migrations_applied").exists());
+    }
+
+    #[test]
+    fn test_init_creates_nested_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("data").join("app").join("db");
+
+        let db = DATABASE::init(path.to_str().unwrap().to_string());
+        assert!(path.join("migrations").join(".migrations_applied").exists());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        assert!(path.join("users").is_dir());
+    }
+
+    #[test]
+    fn test_init_repairs_missing_migrations_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("db");
+        fs::create_dir(&path).unwrap();
+
+        let db = DATABASE::init(path.to_str().unwrap().to_string());
+        assert_eq!(fs::read_to_string(path.join("migrations").join(".migrations_applied")).unwrap(), "[]");
+
+        db.generate_create_table_migration("create_users", "users", "id", vec![("id", "STRING")])
+            .unwrap();
+        db.apply_migrations().unwrap();
+        assert!(db.get_table("users").is_ok());
+
+        // reopening leaves an existing record of applied migrations alone
+        let applied = fs::read_to_string(path.join("migrations").join(".migrations_applied")).unwrap();
+        DATABASE::init(path.to_str().unwrap().to_string());
+        assert_eq!(fs::read_to_string(path.join("migrations").join(".migrations_applied")).unwrap(), applied);
+    }
+}
@@ -1,15 +1,17 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use eyre::{eyre, Result};
 use num_bigint::BigUint;
 use regex::Regex;
+use serde_json::value::RawValue;
 use sha2::{Digest, Sha256};
 
-use crate::crud::make::{Data, data_eq_type, DATABASE, TABLE, Type};
+use crate::crud::make::{CREATED_AT, Data, data_eq_type, DATABASE, FieldError, IdStrategy, Row, TABLE};
+use crate::crud::stats::bump;
+use crate::error::{Error, Result};
 
 impl DATABASE {
 
@@ -19,32 +21,32 @@ impl DATABASE {
         rows: Vec<HashMap<String, (Data, String)>>,
         overwrite: bool,
     ) -> Result<()> {
-        // Load schema
-        let mut type_path = PathBuf::from(&self.path);
-        type_path.push(format!("{}-type.txt", table_name));
-        let type_data = fs::read_to_string(&type_path)?;
-        let table_schema: TABLE = serde_json::from_str(&type_data)?;
+        self.check_writable()?;
+        let table_schema = self.get_schema(&table_name)?;
 
         // Map shard_filename -> Vec<(id, row)>
-        let mut shard_batches: BTreeMap<String, Vec<(u128, HashMap<String, (Data, String)>)>> = BTreeMap::new();
+        let mut shard_batches: BTreeMap<String, Vec<(String, Row)>> = BTreeMap::new();
+
+        for mut row in rows {
+            table_schema.apply_defaults(&mut row);
+            table_schema.stamp_timestamps(&mut row);
 
-        for row in rows {
             // Validate type
-            if !Self::check_type_regex(&row, &table_schema)? {
-                return Err(eyre!("Row data types or regex patterns do not match schema"));
-            }
+            Self::validate_row(&row, &table_schema)?;
 
             // Extract ID
-            let id_field = row.get(&table_schema.id_column)
-                .ok_or_else(|| eyre!("Missing ID field '{}'", &table_schema.id_column))?;
-            let id = Self::string_to_numerical_uuid(&id_field.0.clone().get_string());
-            let (start, end) = Self::get_shard_range_(&id);
-            let shard_file = format!("{}-{}.txt", start, end);
+            let id = Self::string_to_numerical_uuid(&table_schema.row_id(&row)?);
+            let shard_file = self.shard_file(&table_schema, &id);
 
             // Queue into the shard file group
-            shard_batches.entry(shard_file).or_default().push((id.parse()?, row));
+            shard_batches.entry(shard_file).or_default().push((id, row));
         }
 
+        let lock = self.table_lock(&table_name);
+        let _guard = lock.write().unwrap();
+        let batch: Vec<(&String, &Row)> = shard_batches.values().flatten().map(|(id, row)| (id, row)).collect();
+        self.check_constraints(&table_name, &table_schema, &batch)?;
+
         // Now write each shard once
         let mut shard_path = PathBuf::from(&self.path);
         shard_path.push(&table_name);
@@ -53,79 +55,323 @@ impl DATABASE {
         for (shard_file, entries) in shard_batches {
             let mut path = shard_path.clone();
             path.push(shard_file);
-            Self::add_many_to_file(path, entries, overwrite)?;
+            self.add_many_to_file(path, entries, overwrite)?;
         }
 
         Ok(())
     }
 
+    /// Merges `entries` into one shard file. Rows already in the shard stay raw
+    /// JSON text and are copied through as-is rather than parsed into `Data`
+    /// and serialized again. The shard is written compact unless the database
+    /// was built with `pretty(true)`: pretty-printing roughly doubles the file
+    /// and dominated large bulk inserts.
     fn add_many_to_file(
+        &self,
         path: PathBuf,
-        entries: Vec<(u128, HashMap<String, (Data, String)>)>,
+        entries: Vec<(String, Row)>,
         overwrite: bool,
     ) -> Result<()> {
-        let mut map = if path.exists() {
+        let mut map: HashMap<String, Box<RawValue>> = if path.exists() {
             let content = fs::read_to_string(&path)?;
+            bump(&self.counters.shard_reads, 1);
             serde_json::from_str(&content)?
         } else {
             HashMap::new()
         };
 
+        let (mut inserted, mut updated) = (0, 0);
         for (id, row) in entries {
-            if !overwrite && map.contains_key(&id.to_string()) {
-                return Err(eyre!("Row with ID {} already exists", id));
+            if map.contains_key(&id) {
+                if !overwrite {
+                    return Err(Error::UniqueViolation { id });
+                }
+                updated += 1;
+            } else {
+                inserted += 1;
             }
-            map.insert(id.to_string(), row);
+            map.insert(id, serde_json::value::to_raw_value(&row)?);
         }
 
-        let json = serde_json::to_string_pretty(&map)?;
-        fs::write(&path, json)?;
+        self.save_shard(&path, &map)?;
+        bump(&self.counters.rows_inserted, inserted);
+        bump(&self.counters.rows_updated, updated);
         Ok(())
     }
 
 
 
-    pub fn add_row(&self, table_name: String, row: HashMap<String, (Data, String)>, overwrite: bool) -> Result<()> {
-        let mut type_path = PathBuf::from(&self.path);
-        type_path.push(format!("{}-type.txt", table_name));
-        let type_data = fs::read_to_string(&type_path)?;
-        let table_schema: TABLE = serde_json::from_str(&type_data)?;
-        // println!("{:?}", row);
-        if !Self::check_type_regex(&row, &table_schema)? {
-            return Err(eyre!("Row data types or regex patterns do not match schema"));
+    pub fn add_row(&self, table_name: String, mut row: HashMap<String, (Data, String)>, overwrite: bool) -> Result<()> {
+        self.check_writable()?;
+        let table_schema = self.get_schema(&table_name)?;
+        Self::prepare_row(&mut row, &table_schema)?;
+
+        let lock = self.table_lock(&table_name);
+        let _guard = lock.write().unwrap();
+        self.insert_row(&table_name, &table_schema, row, overwrite)
+    }
+
+    /// Fills in defaults and timestamps and validates `row`, as every insert
+    /// does before it is written.
+    pub(crate) fn prepare_row(row: &mut Row, schema: &TABLE) -> Result<()> {
+        schema.apply_defaults(row);
+        schema.stamp_timestamps(row);
+        Self::validate_row(row, schema)
+    }
+
+    /// Writes a prepared `row` to its shard if it passes the table's unique
+    /// constraints and foreign keys. The caller holds the table's write lock.
+    pub(crate) fn insert_row(&self, table_name: &str, schema: &TABLE, row: Row, overwrite: bool) -> Result<()> {
+        let row_id = schema.row_id(&row)?;
+        let id = Self::string_to_numerical_uuid(&row_id);
+        let filepath = self.shard_path(table_name, &row_id);
+        self.check_constraints(table_name, schema, &[(&id, &row)])?;
+
+        fs::create_dir_all(filepath.parent().unwrap())?; // Ensure table folder exists
+        self.add_to_file(filepath, row, id, overwrite)
+    }
+
+    /// Inserts `row` like `add_row` (never overwriting), first generating an
+    /// id with `strategy` if the id field is missing or an empty string.
+    /// Returns the id the row was stored under. `Uuid` and `TimestampRandom`
+    /// ids are strings and need a `STRING` id column; `Sequence` ids need a
+    /// `NUMBER` one.
+    pub fn add_row_autoid(&self, table_name: &str, mut row: Row, strategy: IdStrategy) -> Result<String> {
+        self.check_writable()?;
+        let table_schema = self.get_schema(table_name)?;
+
+        let id = match row.get(&table_schema.id_column) {
+            Some((Data::STRING(id), _)) if id.is_empty() => None,
+            Some((data, _)) => Some(data.clone()),
+            None => None,
+        };
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let id = match strategy {
+                    IdStrategy::Uuid => Data::STRING(uuid::Uuid::new_v4().to_string()),
+                    IdStrategy::TimestampRandom => {
+                        let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+                        let seed = format!("{}-{}", nanos, rand::random::<u64>());
+                        Data::STRING(Self::string_to_numerical_uuid(&seed))
+                    }
+                    IdStrategy::Sequence => Data::NUMBER(self.next_id(table_name)? as f64),
+                };
+                row.insert(table_schema.id_column.clone(), (id.clone(), String::new()));
+                id
+            }
+        };
+
+        self.add_row(table_name.to_string(), row, false)?;
+        Ok(id.to_string())
+    }
+
+    /// Advances the table's auto-increment sequence and returns the new value:
+    /// 1 for the first call, then 2, 3, ... The current value lives in
+    /// `{table}-seq.txt` and is updated under an exclusive lock on that file,
+    /// so concurrent callers, in this process or another, never get the same
+    /// value. A value handed out is used up even if the insert using it fails.
+    pub fn next_id(&self, table_name: &str) -> Result<u64> {
+        self.check_writable()?;
+        self.get_schema(table_name)?;
+
+        let path = PathBuf::from(&self.path).join(format!("{}-seq.txt", table_name));
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        file.lock()?;
+
+        let mut current = String::new();
+        file.read_to_string(&mut current)?;
+        let current: u64 = match current.trim() {
+            "" => 0,
+            text => text
+                .parse()
+                .map_err(|_| Error::InvalidValue(format!("sequence file {} holds '{}'", path.display(), text)))?,
+        };
+
+        let next = current + 1;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(next.to_string().as_bytes())?;
+        file.sync_all()?;
+        Ok(next)
+    }
+
+    /// Inserts `row`, or replaces the stored row if its id already exists.
+    /// Returns `true` when the row was inserted and `false` when it replaced an
+    /// existing one. Replacement is a full-row overwrite: fields left out of
+    /// `row` are not kept from the old row (except `created_at` on timestamped
+    /// tables).
+    pub fn upsert(&self, table_name: String, mut row: Row) -> Result<bool> {
+        self.check_writable()?;
+        let table_schema = self.get_schema(&table_name)?;
+
+        let existing = self.get_by_id(table_name.clone(), table_schema.row_id(&row)?)?;
+
+        if let Some(old) = &existing {
+            if let (true, Some(created)) = (table_schema.timestamps, old.get(CREATED_AT)) {
+                row.entry(CREATED_AT.to_string()).or_insert_with(|| created.clone());
+            }
+        }
+
+        self.add_row(table_name, row, true)?;
+        Ok(existing.is_none())
+    }
+
+    /// `upsert` for a batch: rows are grouped by shard and each shard is read
+    /// and written once, instead of a lookup and a write per row. Every row is
+    /// validated before anything is written. Returns how many rows were
+    /// inserted and how many replaced an existing one; a batch repeating an id
+    /// counts the later rows as replacements.
+    pub fn upsert_many(&self, table_name: &str, rows: Vec<Row>) -> Result<(usize, usize)> {
+        self.check_writable()?;
+        let table_schema = self.get_schema(table_name)?;
+
+        // Each row remembers whether `created_at` was stamped rather than given,
+        // so a replaced row's stored value can take its place.
+        let mut shard_batches: BTreeMap<PathBuf, Vec<(String, Row, bool)>> = BTreeMap::new();
+        for mut row in rows {
+            table_schema.apply_defaults(&mut row);
+            let stamped = table_schema.timestamps && !row.contains_key(CREATED_AT);
+            table_schema.stamp_timestamps(&mut row);
+            Self::validate_row(&row, &table_schema)?;
+            let row_id = table_schema.row_id(&row)?;
+            let id = Self::string_to_numerical_uuid(&row_id);
+            shard_batches.entry(self.shard_path(table_name, &row_id)).or_default().push((id, row, stamped));
+        }
+
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        let batch: Vec<(&String, &Row)> = shard_batches.values().flatten().map(|(id, row, _)| (id, row)).collect();
+        self.check_constraints(table_name, &table_schema, &batch)?;
+
+        fs::create_dir_all(PathBuf::from(&self.path).join(table_name))?;
+        let (mut inserted, mut updated) = (0, 0);
+        for (path, entries) in shard_batches {
+            let mut shard = if path.exists() { self.load_shard::<Row>(&path)? } else { HashMap::new() };
+            for (id, mut row, stamped) in entries {
+                match shard.get(&id) {
+                    Some(old) => {
+                        if let (true, Some(created)) = (stamped, old.get(CREATED_AT)) {
+                            row.insert(CREATED_AT.to_string(), created.clone());
+                        }
+                        updated += 1;
+                    }
+                    None => inserted += 1,
+                }
+                shard.insert(id, row);
+            }
+            self.save_shard(&path, &shard)?;
+        }
+
+        bump(&self.counters.rows_inserted, inserted);
+        bump(&self.counters.rows_updated, updated);
+        Ok((inserted, updated))
+    }
+
+    /// Returns the row stored under `id`, inserting `default_row` first if
+    /// there is none. The id column of `default_row` must equal `id`. If
+    /// another caller inserts the same id between the lookup and the insert,
+    /// their row wins and is returned; `default_row` is not written.
+    pub fn get_or_insert(&self, table_name: &str, id: &str, default_row: Row) -> Result<Row> {
+        let table_schema = self.get_schema(table_name)?;
+        let row_id = table_schema.row_id(&default_row)?;
+        if row_id != id {
+            return Err(Error::InvalidArgument(format!(
+                "default row has id '{}' but '{}' was requested", row_id, id
+            )));
         }
 
-        let id_field = row.get(&table_schema.id_column)
-            .ok_or_else(|| eyre!("Missing ID field '{}'", &table_schema.id_column))?;
-        let id = Self::string_to_numerical_uuid(&id_field.0.clone().get_string());
-        let (start, end) = Self::get_shard_range_(&id);
-        let filename = format!("{}-{}.txt", start, end);
+        if let Some(row) = self.get_by_id(table_name.to_string(), id.to_string())? {
+            return Ok(row);
+        }
 
-        let mut filepath = PathBuf::from(&self.path);
-        filepath.push(&table_name);
-        fs::create_dir_all(&filepath)?; // Ensure table folder exists
-        filepath.push(&filename);
+        match self.add_row(table_name.to_string(), default_row.clone(), false) {
+            Ok(()) => Ok(default_row),
+            Err(Error::UniqueViolation { .. }) => self
+                .get_by_id(table_name.to_string(), id.to_string())?
+                .ok_or_else(|| Error::NotFound(id.to_string())),
+            Err(e) => Err(e),
+        }
+    }
 
-        Self::add_to_file(filepath, row, id, overwrite)
+    /// Fails if any of `rows` (shard key, row), about to be written over
+    /// whatever is stored under those keys, breaks a unique constraint or
+    /// holds a dangling foreign key. Run under the table's write lock, so
+    /// nothing can change between the check and the write.
+    pub(crate) fn check_constraints(&self, table_name: &str, schema: &TABLE, rows: &[(&String, &Row)]) -> Result<()> {
+        self.check_unique(table_name, schema, rows)?;
+        for (_, row) in rows {
+            for (field, foreign_key) in &schema.foreign_keys {
+                self.check_reference(row, field, foreign_key)?;
+            }
+        }
+        Ok(())
     }
 
-    fn add_to_file(filepath: PathBuf, row: HashMap<String, (Data, String)>, id: String, overwrite: bool) -> Result<()> {
-        let data: HashMap<String, HashMap<String, (Data, String)>> = if filepath.exists() {
-            let content = fs::read_to_string(&filepath)?;
-            serde_json::from_str(&content).unwrap_or_else(|_| HashMap::new())
+    /// Fails with `Error::UniqueConstraint` if any of `rows` (shard key, row)
+    /// would share the values of a composite unique constraint with a stored
+    /// row or with another row of the batch. Stored rows the batch replaces are
+    /// left out. Stored values are read through each constraint's
+    /// `unique_entries`, which are only rebuilt from the rows after a write.
+    pub(crate) fn check_unique(&self, table_name: &str, schema: &TABLE, rows: &[(&String, &Row)]) -> Result<()> {
+        let replaced: HashSet<&str> = rows.iter().map(|(id, _)| id.as_str()).collect();
+
+        for (name, columns) in &schema.unique {
+            let stored = self.unique_entries(table_name, name)?;
+            let mut batch = HashSet::new();
+            for (_, row) in rows {
+                let Some(values) = unique_values(row, columns) else {
+                    continue;
+                };
+                let key = serde_json::to_string(&values)?;
+                let taken = stored.get(&key).is_some_and(|keys| keys.iter().any(|k| !replaced.contains(k.as_str())));
+                if taken || !batch.insert(key) {
+                    return Err(Error::UniqueConstraint { constraint: name.clone(), values });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The values of `columns` in every stored row except those in `skip`,
+    /// mapped to the row's shard key. Fails if two rows already share them.
+    pub(crate) fn unique_index(
+        &self,
+        table_name: &str,
+        name: &str,
+        columns: &[String],
+        skip: &HashSet<String>,
+    ) -> Result<HashMap<Vec<String>, String>> {
+        let mut index = HashMap::new();
+        for (id, row) in self.iter_rows(table_name.to_string()) {
+            if skip.contains(&id) {
+                continue;
+            }
+            let Some(values) = unique_values(&row, columns) else {
+                continue;
+            };
+            if index.insert(values.clone(), id).is_some() {
+                return Err(Error::UniqueConstraint { constraint: name.to_string(), values });
+            }
+        }
+        Ok(index)
+    }
+
+    fn add_to_file(&self, filepath: PathBuf, row: HashMap<String, (Data, String)>, id: String, overwrite: bool) -> Result<()> {
+        let mut data: HashMap<String, Row> = if filepath.exists() {
+            self.load_shard(&filepath)?
         } else {
             HashMap::new()
         };
 
-        let mut data = data;
-
-        if data.contains_key(&id) && !overwrite {
-            return Err(eyre!("ID '{}' already exists and overwrite is false", id));
+        let existed = data.contains_key(&id);
+        if existed && !overwrite {
+            return Err(Error::UniqueViolation { id });
         }
 
         data.insert(id, row);
-        let serialized = serde_json::to_string(&data)?;
-        fs::write(&filepath, serialized)?;
+        self.save_shard(&filepath, &data)?;
+        bump(if existed { &self.counters.rows_updated } else { &self.counters.rows_inserted }, 1);
         Ok(())
     }
 
@@ -139,35 +385,653 @@ impl DATABASE {
         big_num.to_str_radix(10)
     }
 
-    fn get_shard_range_(id: &str) -> (String, String) {
-        let base = &id[..id.len().saturating_sub(7)];
-        (format!("{}0000000", base), format!("{}9999999", base))
+    pub fn check_type_regex(row: &HashMap<String, (Data, String)>, types: &TABLE) -> Result<bool> {
+        match Self::validate_row(row, types) {
+            Ok(()) => Ok(true),
+            Err(Error::SchemaMismatch(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn check_type_regex(row: &HashMap<String, (Data, String)>, types: &TABLE) -> Result<bool> {
-        if row.len() != types.field_names.len() {
-            return Ok(false);
+    /// Checks a row against the table schema. Schema violations come back as
+    /// `Error::SchemaMismatch` holding a `FieldError` that names the offending
+    /// field. Fields the schema doesn't have are reported first (the
+    /// alphabetically first one, if there are several), ahead of missing ones.
+    pub fn validate_row(row: &HashMap<String, (Data, String)>, types: &TABLE) -> Result<()> {
+        if let Some(field) = row.keys().filter(|k| !types.field_names.contains_key(*k)).min() {
+            return Err(FieldError::Unknown { field: field.clone() }.into());
+        }
+
+        for field_name in types.field_names.keys() {
+            let Some(row_val) = row.get(field_name) else {
+                if types.is_optional(field_name) {
+                    continue;
+                }
+                return Err(FieldError::Missing { field: field_name.clone() }.into());
+            };
+            Self::validate_value(field_name, &row_val.0, types)?;
         }
 
-        for (field_name, (expected_type, regex_str)) in &types.field_names {
-            let row_val = row.get(field_name).ok_or_else(|| eyre!("Missing field '{}'", field_name))?;
-            let data = &row_val.0;
-            let pattern = &row_val.1;
+        Ok(())
+    }
+
+    /// Checks only the given fields against the schema, for updates that
+    /// change part of a row. Fields left out aren't reported as missing.
+    pub fn validate_fields(fields: &HashMap<String, (Data, String)>, types: &TABLE) -> Result<()> {
+        for (field_name, (data, _)) in fields {
+            if !types.field_names.contains_key(field_name) {
+                return Err(FieldError::Unknown { field: field_name.clone() }.into());
+            }
+            Self::validate_value(field_name, data, types)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_value(field_name: &str, data: &Data, types: &TABLE) -> Result<()> {
+        let (expected_type, regex_str) = &types.field_names[field_name];
+
+        if types.required.contains(field_name) && data.is_null() {
+            return Err(FieldError::Null { field: field_name.to_string() }.into());
+        }
+
+        if !data_eq_type(data, expected_type) {
+            return Err(FieldError::Type {
+                field: field_name.to_string(),
+                expected: expected_type.clone(),
+                actual: data.variant_name(),
+            }
+            .into());
+        }
 
-            if !data_eq_type(&data.clone(), &expected_type.clone()) {
-                return Ok(false);
+        if let Data::JSON(text) | Data::JSONNULL(Some(text)) = data {
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(text) {
+                return Err(FieldError::Json {
+                    field: field_name.to_string(),
+                    message: e.to_string(),
+                }
+                .into());
             }
+        }
 
-            if !regex_str.is_empty() {
-                let re = Regex::from_str(regex_str)?;
-                if let Data::STRING(s) = data {
-                    if !re.is_match(s) {
-                        return Ok(false);
+        if !regex_str.is_empty() {
+            let re = Regex::from_str(regex_str)?;
+            if let Data::STRING(s) = data {
+                if !re.is_match(s) {
+                    return Err(FieldError::Regex {
+                        field: field_name.to_string(),
+                        pattern: regex_str.clone(),
                     }
+                    .into());
                 }
             }
         }
 
-        Ok(true)
+        Ok(())
+    }
+}
+
+/// A row's values in `columns`, as text, or `None` if any is missing or null.
+pub(crate) fn unique_values(row: &Row, columns: &[String]) -> Option<Vec<String>> {
+    columns
+        .iter()
+        .map(|column| {
+            let (value, _) = row.get(column)?;
+            (!value.is_null()).then(|| value.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crud::make::{OnDelete, Type, UPDATED_AT};
+
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, DATABASE) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        fields.insert("nickname".to_string(), (Type::STRINGNULL, String::new()));
+        fields.insert("email".to_string(), (Type::STRINGNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        db.set_required("users", "email", true).unwrap();
+
+        (temp_dir, db)
+    }
+
+    fn row(id: &str) -> HashMap<String, (Data, String)> {
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+        row.insert("age".to_string(), (Data::NUMBER(30.0), String::new()));
+        row
+    }
+
+    #[test]
+    fn test_required_field_present() {
+        let (_temp_dir, db) = setup();
+
+        let mut row = row("u1");
+        row.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+
+        // `nickname` is nullable and not required, so it may be left out
+        db.add_row("users".to_string(), row, false).unwrap();
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_required_field_absent() {
+        let (_temp_dir, db) = setup();
+
+        let err = db.add_row("users".to_string(), row("u1"), false).unwrap_err();
+        assert_eq!(err.to_string(), "missing required field 'email'");
+    }
+
+    #[test]
+    fn test_required_field_null() {
+        let (_temp_dir, db) = setup();
+
+        let mut row = row("u1");
+        row.insert("email".to_string(), (Data::STRINGNULL(None), String::new()));
+
+        let err = db.add_row("users".to_string(), row, false).unwrap_err();
+        assert_eq!(err.to_string(), "required field 'email' is null");
+    }
+
+    #[test]
+    fn test_non_nullable_field_absent() {
+        let (_temp_dir, db) = setup();
+
+        let mut row = row("u1");
+        row.remove("age");
+        row.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+
+        let err = db.add_row("users".to_string(), row, false).unwrap_err();
+        assert_eq!(err.to_string(), "missing required field 'age'");
+    }
+
+    #[test]
+    fn test_type_mismatch_names_field() {
+        let (_temp_dir, db) = setup();
+
+        let mut row = row("u1");
+        row.insert("age".to_string(), (Data::STRING("thirty".to_string()), String::new()));
+        row.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+
+        let Error::SchemaMismatch(err) = db.add_row("users".to_string(), row, false).unwrap_err() else {
+            panic!("expected a schema mismatch");
+        };
+        assert_eq!(
+            err,
+            FieldError::Type {
+                field: "age".to_string(),
+                expected: Type::NUMBER,
+                actual: "STRING",
+            }
+        );
+    }
+
+    #[test]
+    fn test_json_column_rejects_invalid_json() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("profile".to_string(), (Type::JSON, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("profile".to_string(), (Data::JSON("{bad".to_string()), String::new()));
+        let err = db.add_row("users".to_string(), row.clone(), false).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SchemaMismatch(FieldError::Json { ref field, .. }) if field == "profile"
+        ));
+
+        row.insert("profile".to_string(), (Data::JSON(r#"{"age":30}"#.to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        let stored = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        let value = stored["profile"].0.clone().get_json_value().unwrap();
+        assert_eq!(value["age"], 30);
+    }
+
+    #[test]
+    fn test_regex_mismatch_names_field() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("email".to_string(), (Type::STRING, "^[^@]+@[^@]+$".to_string()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("email".to_string(), (Data::STRING("not-an-email".to_string()), String::new()));
+
+        let Error::SchemaMismatch(err) = db.add_row("users".to_string(), row, false).unwrap_err() else {
+            panic!("expected a schema mismatch");
+        };
+        assert_eq!(
+            err,
+            FieldError::Regex {
+                field: "email".to_string(),
+                pattern: "^[^@]+@[^@]+$".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_add_row_leaves_corrupt_shard_alone() {
+        let (_temp_dir, db) = setup();
+        let mut row = row("u1");
+        row.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+        let shard = db.shard_path("users", "u1");
+        fs::create_dir_all(shard.parent().unwrap()).unwrap();
+        fs::write(&shard, "{not json").unwrap();
+
+        assert!(db.add_row("users".to_string(), row, false).is_err());
+        assert_eq!(fs::read_to_string(&shard).unwrap(), "{not json");
+    }
+
+    #[test]
+    fn test_upsert_inserts_then_overwrites() {
+        let (_temp_dir, db) = setup();
+
+        let mut first = row("u1");
+        first.insert("nickname".to_string(), (Data::STRINGNULL(Some("al".to_string())), String::new()));
+        first.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+        assert!(db.upsert("users".to_string(), first).unwrap());
+
+        // the replacement leaves `nickname` out, so it is gone afterwards
+        let mut second = row("u1");
+        second.insert("age".to_string(), (Data::NUMBER(31.0), String::new()));
+        second.insert("email".to_string(), (Data::STRINGNULL(Some("x@y.z".to_string())), String::new()));
+        assert!(!db.upsert("users".to_string(), second).unwrap());
+
+        let stored = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(stored["age"].0, Data::NUMBER(31.0));
+        assert_eq!(stored["email"].0, Data::STRINGNULL(Some("x@y.z".to_string())));
+        assert!(!stored.contains_key("nickname"));
+    }
+
+    #[test]
+    fn test_get_or_insert_inserts_then_hits() {
+        let (_temp_dir, db) = setup();
+
+        let mut default = row("u1");
+        default.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+        let got = db.get_or_insert("users", "u1", default.clone()).unwrap();
+        assert_eq!(got["age"].0, Data::NUMBER(30.0));
+        assert_eq!(db.count_rows("users".to_string()), 1);
+
+        // the stored row is returned and the new default is ignored
+        let mut other = default;
+        other.insert("age".to_string(), (Data::NUMBER(99.0), String::new()));
+        let got = db.get_or_insert("users", "u1", other).unwrap();
+        assert_eq!(got["age"].0, Data::NUMBER(30.0));
+        assert_eq!(db.count_rows("users".to_string()), 1);
+    }
+
+    #[test]
+    fn test_get_or_insert_rejects_mismatched_id() {
+        let (_temp_dir, db) = setup();
+
+        let err = db.get_or_insert("users", "u2", row("u1")).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)));
+        assert_eq!(db.count_rows("users".to_string()), 0);
+    }
+
+    #[test]
+    fn test_composite_unique_constraint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("tenant_id".to_string(), (Type::STRING, String::new()));
+        fields.insert("email".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "accounts".to_string()).unwrap();
+        db.add_unique("accounts", "tenant_email", &["tenant_id", "email"]).unwrap();
+
+        let account = |id: &str, tenant: &str, email: &str| {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("tenant_id".to_string(), (Data::STRING(tenant.to_string()), String::new()));
+            row.insert("email".to_string(), (Data::STRING(email.to_string()), String::new()));
+            row
+        };
+
+        db.add_row("accounts".to_string(), account("a1", "t1", "x@y.z"), false).unwrap();
+        // sharing only one of the two columns is fine
+        db.add_row("accounts".to_string(), account("a2", "t2", "x@y.z"), false).unwrap();
+        db.add_row("accounts".to_string(), account("a3", "t1", "other@y.z"), false).unwrap();
+
+        let err = db.add_row("accounts".to_string(), account("a4", "t1", "x@y.z"), false).unwrap_err();
+        assert_eq!(err.to_string(), "unique constraint 'tenant_email' violated by (t1, x@y.z)");
+
+        // within one batch too, and nothing of the batch is written; with no
+        // write since the last check, the stored values aren't read again
+        let reads = db.stats().shard_reads;
+        let batch = vec![account("a5", "t3", "q@y.z"), account("a6", "t3", "q@y.z")];
+        let err = db.add_rows("accounts".to_string(), batch, false).unwrap_err();
+        assert!(matches!(err, Error::UniqueConstraint { constraint, .. } if constraint == "tenant_email"));
+        assert_eq!(db.stats().shard_reads, reads);
+        assert_eq!(db.count_rows("accounts".to_string()), 3);
+
+        // a row may keep its own values when it is overwritten
+        db.upsert("accounts".to_string(), account("a1", "t1", "x@y.z")).unwrap();
+
+        // a constraint the stored rows already break can't be added
+        db.add_row("accounts".to_string(), account("a7", "t9", "other@y.z"), false).unwrap();
+        assert!(db.add_unique("accounts", "email", &["email"]).is_err());
+        assert!(db.add_unique("accounts", "bad", &["missing"]).is_err());
+    }
+
+    #[test]
+    fn test_dangling_reference_rejected() {
+        let (_temp_dir, db) = setup();
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("user_id".to_string(), (Type::STRINGNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+        db.add_foreign_key("orders", "user_id", "users", OnDelete::Restrict).unwrap();
+
+        let mut user = row("u1");
+        user.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+        db.add_row("users".to_string(), user, false).unwrap();
+
+        let order = |id: &str, user_id: Option<&str>| {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("user_id".to_string(), (Data::STRINGNULL(user_id.map(str::to_string)), String::new()));
+            row
+        };
+
+        db.add_row("orders".to_string(), order("o1", Some("u1")), false).unwrap();
+        // a null reference points nowhere and is allowed
+        db.add_row("orders".to_string(), order("o2", None), false).unwrap();
+
+        let err = db.add_row("orders".to_string(), order("o3", Some("u9")), false).unwrap_err();
+        assert_eq!(err.to_string(), "field 'user_id' references missing row 'u9' in 'users'");
+
+        let batch = vec![order("o4", Some("u1")), order("o5", Some("u9"))];
+        assert!(matches!(
+            db.add_rows("orders".to_string(), batch, false),
+            Err(Error::ForeignKeyViolation { .. })
+        ));
+        assert_eq!(db.count_rows("orders".to_string()), 2);
+    }
+
+    #[test]
+    fn test_add_row_autoid_distinct_ids() {
+        let (_temp_dir, db) = setup();
+
+        let mut ids = vec![];
+        for strategy in [IdStrategy::Uuid, IdStrategy::Uuid, IdStrategy::TimestampRandom, IdStrategy::TimestampRandom] {
+            let mut new = row("");
+            new.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+            ids.push(db.add_row_autoid("users", new, strategy).unwrap());
+        }
+        assert_eq!(ids[0].len(), 36);
+
+        for id in &ids {
+            assert_eq!(ids.iter().filter(|other| *other == id).count(), 1);
+            let stored = db.get_by_id("users".to_string(), id.clone()).unwrap().unwrap();
+            assert_eq!(stored["id"].0, Data::STRING(id.clone()));
+        }
+        assert_eq!(db.count_rows("users".to_string()), 4);
+
+        // an id that is already set is kept
+        let mut given = row("u1");
+        given.insert("email".to_string(), (Data::STRINGNULL(Some("a@b.c".to_string())), String::new()));
+        assert_eq!(db.add_row_autoid("users", given, IdStrategy::Uuid).unwrap(), "u1");
+    }
+
+    #[test]
+    fn test_sequence_ids_count_up() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let db = DATABASE::init(path.clone());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::NUMBER, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "tickets".to_string()).unwrap();
+
+        let ticket = |name: &str| {
+            HashMap::from([("name".to_string(), (Data::STRING(name.to_string()), String::new()))])
+        };
+        for (expected, name) in [(1.0, "a"), (2.0, "b"), (3.0, "c")] {
+            let id = db.add_row_autoid("tickets", ticket(name), IdStrategy::Sequence).unwrap();
+            let stored = db.get_by_id("tickets".to_string(), id).unwrap().unwrap();
+            assert_eq!(stored["id"].0, Data::NUMBER(expected));
+            assert_eq!(stored["name"].0, Data::STRING(name.to_string()));
+        }
+
+        // the sequence survives reopening the database
+        let db = DATABASE::init(path);
+        assert_eq!(db.next_id("tickets").unwrap(), 4);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || (0..25).map(|_| db.next_id("tickets").unwrap()).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut ids: Vec<u64> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, (5..105).collect::<Vec<_>>());
+
+        assert!(db.next_id("missing").is_err());
+    }
+
+    #[test]
+    fn test_add_rows_100k() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let batch = |range: std::ops::Range<usize>| {
+            range
+                .map(|i| {
+                    let mut row = HashMap::new();
+                    row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+                    row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+                    row
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // ids hash to 32 bits, so a few of 100k collide and overwrite each other
+        let distinct: std::collections::HashSet<String> =
+            (0..101_000).map(|i| DATABASE::string_to_numerical_uuid(&format!("u{}", i))).collect();
+
+        db.add_rows("users".to_string(), batch(0..100_000), true).unwrap();
+
+        // a second batch lands in the same shards, next to rows left untouched
+        db.add_rows("users".to_string(), batch(100_000..101_000), true).unwrap();
+
+        assert_eq!(db.count_rows("users".to_string()), distinct.len());
+        let row = db.get_by_id("users".to_string(), "u100500".to_string()).unwrap().unwrap();
+        assert_eq!(row["age"].0, Data::NUMBER(100_500.0));
+
+        let shard = fs::read_dir(temp_dir.path().join("users")).unwrap().flatten().next().unwrap();
+        assert!(!fs::read_to_string(shard.path()).unwrap().contains('\n'));
+    }
+
+    #[test]
+    fn test_add_row_rejects_unknown_field() {
+        let (_dir, db) = setup();
+        let string = |s: &str| (Data::STRINGNULL(Some(s.to_string())), String::new());
+
+        let mut extra = row("u1");
+        extra.insert("email".to_string(), string("a@example.com"));
+        extra.insert("bogus".to_string(), string("x"));
+        let err = db.add_row("users".to_string(), extra, false).unwrap_err();
+        assert!(err.to_string().contains("unknown field 'bogus'"), "{}", err);
+
+        // as many fields as the schema has, but `bogus` in place of `age`
+        let mut swapped = row("u1");
+        swapped.remove("age");
+        swapped.insert("nickname".to_string(), string("al"));
+        swapped.insert("email".to_string(), string("a@example.com"));
+        swapped.insert("bogus".to_string(), string("x"));
+        assert_eq!(swapped.len(), db.get_schema("users").unwrap().field_names.len());
+        let err = db.add_row("users".to_string(), swapped, false).unwrap_err();
+        assert!(matches!(err, Error::SchemaMismatch(FieldError::Unknown { field }) if field == "bogus"));
+
+        assert_eq!(db.count_rows("users".to_string()), 0);
+    }
+
+    #[test]
+    fn test_insert_fills_in_defaults() {
+        let (_dir, db) = setup();
+        db.set_default("users", "age", Some(Data::NUMBER(18.0))).unwrap();
+        db.set_default("users", "nickname", Some(Data::STRINGNULL(Some("anon".to_string())))).unwrap();
+        assert!(db.set_default("users", "age", Some(Data::STRING("old".to_string()))).is_err());
+        assert!(db.set_default("users", "missing", Some(Data::NUMBER(1.0))).is_err());
+
+        let email = (Data::STRINGNULL(Some("a@example.com".to_string())), String::new());
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("email".to_string(), email.clone());
+        db.add_row("users".to_string(), row.clone(), false).unwrap();
+
+        let stored = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(stored["age"].0, Data::NUMBER(18.0));
+        assert_eq!(stored["nickname"].0, Data::STRINGNULL(Some("anon".to_string())));
+
+        // a value given explicitly wins over the default
+        row.insert("id".to_string(), (Data::STRING("u2".to_string()), String::new()));
+        row.insert("age".to_string(), (Data::NUMBER(40.0), String::new()));
+        db.add_rows("users".to_string(), vec![row.clone()], false).unwrap();
+        let stored = db.get_by_id("users".to_string(), "u2".to_string()).unwrap().unwrap();
+        assert_eq!(stored["age"].0, Data::NUMBER(40.0));
+
+        db.set_default("users", "age", None).unwrap();
+        row.insert("id".to_string(), (Data::STRING("u3".to_string()), String::new()));
+        row.remove("age");
+        assert!(db.add_row("users".to_string(), row, false).is_err());
+    }
+
+    #[test]
+    fn test_reader_never_sees_partial_bulk_insert() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        use crate::crud::builder::DatabaseBuilder;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        // narrow shards, so every batch is spread over many files
+        let db = DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .shard_digits(2)
+            .build()
+            .unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (db, done) = (db.clone(), done.clone());
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let rows = db.get_all("users".to_string()).unwrap().len();
+                    assert_eq!(rows % 100, 0, "read {} rows mid-insert", rows);
+                    let rows = db.query("users".to_string()).execute().len();
+                    assert_eq!(rows % 100, 0, "query saw {} rows mid-insert", rows);
+                    reads += 1;
+                }
+                reads
+            })
+        };
+
+        for batch in 0..20 {
+            let rows = (0..100)
+                .map(|i| {
+                    let mut row = HashMap::new();
+                    row.insert("id".to_string(), (Data::STRING(format!("u{}-{}", batch, i)), String::new()));
+                    row
+                })
+                .collect();
+            db.add_rows("users".to_string(), rows, false).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+
+        assert!(reader.join().unwrap() > 0);
+        assert_eq!(db.count_rows("users".to_string()), 2000);
+    }
+
+    #[test]
+    fn test_upsert_many_counts_inserts_and_updates() {
+        let (_temp_dir, db) = setup();
+        let row = |id: &str| {
+            let mut row = row(id);
+            row.insert("email".to_string(), (Data::STRINGNULL(Some(format!("{}@x.y", id))), String::new()));
+            row
+        };
+        for id in ["u1", "u2", "u3"] {
+            db.add_row("users".to_string(), row(id), false).unwrap();
+        }
+
+        let batch = (1..=6)
+            .map(|i| {
+                let mut row = row(&format!("u{}", i));
+                row.insert("age".to_string(), (Data::NUMBER(40.0 + i as f64), String::new()));
+                row
+            })
+            .collect();
+        assert_eq!(db.upsert_many("users", batch).unwrap(), (3, 3));
+
+        assert_eq!(db.count_rows("users".to_string()), 6);
+        let stored = db.get_by_id("users".to_string(), "u2".to_string()).unwrap().unwrap();
+        assert_eq!(stored["age"].0, Data::NUMBER(42.0));
+
+        // one bad row stops the whole batch before anything is written
+        let mut bad = row("u9");
+        bad.insert("age".to_string(), (Data::STRING("old".to_string()), String::new()));
+        assert!(db.upsert_many("users", vec![row("u7"), bad]).is_err());
+        assert!(db.get_by_id("users".to_string(), "u7".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_upsert_many_stamps_timestamps_and_keeps_created_at() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table_with_timestamps(fields, "id".to_string(), "users".to_string()).unwrap();
+        let row = |id: &str, age: f64| {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(age), String::new()));
+            row
+        };
+
+        assert_eq!(db.upsert_many("users", vec![row("u1", 30.0)]).unwrap(), (1, 0));
+        let first = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert!(first.contains_key(CREATED_AT) && first.contains_key(UPDATED_AT));
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(db.upsert_many("users", vec![row("u1", 31.0), row("u2", 40.0)]).unwrap(), (1, 1));
+        let second = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(second["age"].0, Data::NUMBER(31.0));
+        assert_eq!(second[CREATED_AT], first[CREATED_AT]);
+        assert_ne!(second[UPDATED_AT], first[UPDATED_AT]);
     }
 }
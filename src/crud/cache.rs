@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use crate::crud::make::{DATABASE, Row};
+
+/// Results of `QueryBuilder::execute`, keyed by table and the query's
+/// conditions, sort and limit. Each table has a version counter that every
+/// write through this handle (or a clone of it) bumps; an entry is only
+/// served while the table is still at the version it was read at. Writes made
+/// by another process or another `DATABASE` handle are not seen.
+#[derive(Debug, Default)]
+pub(crate) struct QueryCache {
+    versions: HashMap<String, u64>,
+    entries: HashMap<(String, String), (u64, Vec<Row>)>,
+}
+
+impl QueryCache {
+    pub(crate) fn version(&self, table: &str) -> u64 {
+        self.versions.get(table).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn get(&self, table: &str, key: &str) -> Option<Vec<Row>> {
+        let (version, rows) = self.entries.get(&(table.to_string(), key.to_string()))?;
+        (*version == self.version(table)).then(|| rows.clone())
+    }
+
+    /// Stores `rows` as read at `version`. If the table was written to since,
+    /// the entry is already stale and is dropped instead.
+    pub(crate) fn put(&mut self, table: &str, key: String, version: u64, rows: Vec<Row>) {
+        if version == self.version(table) {
+            self.entries.insert((table.to_string(), key), (version, rows));
+        }
+    }
+
+    pub(crate) fn invalidate(&mut self, table: &str) {
+        *self.versions.entry(table.to_string()).or_default() += 1;
+        self.entries.retain(|(t, _), _| t != table);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for version in self.versions.values_mut() {
+            *version += 1;
+        }
+        self.entries.clear();
+    }
+}
+
+impl DATABASE {
+    /// Drops cached query results and index files for `table_name`; call
+    /// after any write to its shards or schema.
+    pub(crate) fn touch_table(&self, table_name: &str) {
+        self.drop_index_files(table_name);
+        if let Some(cache) = &self.query_cache {
+            cache.lock().unwrap().invalidate(table_name);
+        }
+    }
+
+    /// Drops every cached query result.
+    pub(crate) fn clear_query_cache(&self) {
+        if let Some(cache) = &self.query_cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use crate::crud::builder::DatabaseBuilder;
+    use crate::crud::make::{Data, Type};
+    use crate::Operator;
+
+    use super::*;
+
+    fn row(id: &str, age: f64) -> Row {
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+        row.insert("age".to_string(), (Data::NUMBER(age), String::new()));
+        row
+    }
+
+    #[test]
+    fn test_cached_query_sees_later_insert() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .query_cache(true)
+            .build()
+            .unwrap();
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        db.add_row("users".to_string(), row("u1", 30.0), false).unwrap();
+        db.add_row("users".to_string(), row("u2", 20.0), false).unwrap();
+
+        let adults = || db.query("users".to_string()).where_("age", Operator::Gte, Data::NUMBER(25.0));
+        assert_eq!(adults().execute().len(), 1);
+
+        // served from the cache: a shard edited behind the database's back isn't read
+        let table_dir = temp_dir.path().join("users");
+        for shard in fs::read_dir(&table_dir).unwrap().flatten() {
+            fs::write(shard.path(), "{}").unwrap();
+        }
+        assert_eq!(adults().execute().len(), 1);
+        // a different query isn't
+        assert_eq!(adults().limit(5).execute().len(), 0);
+
+        db.add_row("users".to_string(), row("u3", 40.0), false).unwrap();
+        let rows = adults().execute();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["id"].0, Data::STRING("u3".to_string()));
+    }
+
+    #[test]
+    fn test_stale_put_is_dropped() {
+        let mut cache = QueryCache::default();
+        let version = cache.version("users");
+        cache.invalidate("users");
+        cache.put("users", "q".to_string(), version, vec![]);
+        assert!(cache.get("users", "q").is_none());
+
+        cache.put("users", "q".to_string(), cache.version("users"), vec![]);
+        assert!(cache.get("users", "q").is_some());
+        cache.clear();
+        assert!(cache.get("users", "q").is_none());
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::crud::make::{Data, DATABASE, FieldError, Row};
+use crate::crud::wal::shard_files;
+use crate::error::{Error, Result};
+
+/// A problem `DATABASE::check` found in a table's shard files. `shard` is the
+/// shard's file name and `id` the key a row is stored under.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// The whole shard file can't be read or isn't a JSON map of rows.
+    UnparseableShard { shard: String, message: String },
+    /// The entry parses as JSON but isn't a row.
+    UnparseableRow { shard: String, id: String, message: String },
+    MissingId { shard: String, id: String },
+    /// The row is stored under a key other than the hash of its id column.
+    KeyMismatch { shard: String, id: String, expected: String },
+    /// The row's key belongs to another shard, so `get_by_id` can't find it.
+    Misplaced { shard: String, id: String, expected: String },
+    Schema { shard: String, id: String, error: FieldError },
+    /// The same key is stored in more than one shard.
+    DuplicateId { id: String, shards: Vec<String> },
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+impl DATABASE {
+    /// Scans every shard of a table and reports rows that can't be read, lack
+    /// an id, don't match the schema, sit in the wrong shard or are stored
+    /// twice. Nothing is modified; see `repair`.
+    pub fn check(&self, table_name: &str) -> Result<Vec<Issue>> {
+        let schema = self.get_schema(table_name)?;
+        let table_dir = PathBuf::from(&self.path).join(table_name);
+
+        let mut issues = vec![];
+        let mut seen: HashMap<String, Vec<String>> = HashMap::new();
+
+        for path in shard_files(&table_dir)? {
+            let shard = file_name(&path);
+            let rows = match self.load_shard::<Value>(&path) {
+                Ok(rows) => rows,
+                Err(e) => {
+                    issues.push(Issue::UnparseableShard { shard, message: e.to_string() });
+                    continue;
+                }
+            };
+
+            for (id, value) in rows {
+                seen.entry(id.clone()).or_default().push(shard.clone());
+
+                let row: Row = match serde_json::from_value(value) {
+                    Ok(row) => row,
+                    Err(e) => {
+                        issues.push(Issue::UnparseableRow { shard: shard.clone(), id, message: e.to_string() });
+                        continue;
+                    }
+                };
+
+                let expected = self.shard_file(&schema, &id);
+                if expected != shard {
+                    issues.push(Issue::Misplaced { shard: shard.clone(), id: id.clone(), expected });
+                }
+
+                match row.get(&schema.id_column) {
+                    None => {
+                        issues.push(Issue::MissingId { shard: shard.clone(), id });
+                        continue;
+                    }
+                    Some((value @ (Data::STRING(_) | Data::NUMBER(_)), _)) => {
+                        let expected = Self::string_to_numerical_uuid(&value.to_string());
+                        if expected != id {
+                            issues.push(Issue::KeyMismatch { shard: shard.clone(), id: id.clone(), expected });
+                        }
+                    }
+                    Some(_) => {}
+                }
+
+                if let Err(Error::SchemaMismatch(error)) = Self::validate_row(&row, &schema) {
+                    issues.push(Issue::Schema { shard: shard.clone(), id, error });
+                }
+            }
+        }
+
+        let mut duplicates: Vec<_> = seen.into_iter().filter(|(_, shards)| shards.len() > 1).collect();
+        duplicates.sort();
+        issues.extend(duplicates.into_iter().map(|(id, shards)| Issue::DuplicateId { id, shards }));
+
+        Ok(issues)
+    }
+
+    /// Fixes the problems `check` reports that need no judgement call: shard
+    /// files that don't parse at all are renamed to `{shard}.corrupt`, so scans
+    /// stop tripping over them and the data is kept for inspection, and
+    /// entries that aren't rows are dropped from their shard. Returns how many
+    /// files and rows were set aside.
+    pub fn repair(&self, table_name: &str) -> Result<usize> {
+        self.check_writable()?;
+        self.get_schema(table_name)?;
+        let table_dir = PathBuf::from(&self.path).join(table_name);
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        let mut fixed = 0;
+
+        for path in shard_files(&table_dir)? {
+            let Ok(rows) = self.load_shard::<Value>(&path) else {
+                let mut corrupt = path.clone().into_os_string();
+                corrupt.push(".corrupt");
+                fs::rename(&path, corrupt)?;
+                fixed += 1;
+                continue;
+            };
+
+            let total = rows.len();
+            let good: HashMap<String, Row> = rows
+                .into_iter()
+                .filter_map(|(id, value)| Some((id, serde_json::from_value(value).ok()?)))
+                .collect();
+            if good.len() < total {
+                fixed += total - good.len();
+                self.save_shard(&path, &good)?;
+            }
+        }
+
+        Ok(fixed)
+    }
+
+    /// Moves every row stored in a shard other than the one its id hashes to
+    /// into the right shard, re-keying it from its id column if needed, so
+    /// `get_by_id` finds it again. Returns how many rows were moved. A row
+    /// whose correct shard already holds that id is left where it is; `check`
+    /// reports it as a duplicate. Fails without moving anything if a shard
+    /// can't be read, since rows moving into it would overwrite it; `repair`
+    /// sets such shards aside.
+    pub fn reshard_verify(&self, table_name: &str) -> Result<usize> {
+        self.check_writable()?;
+        let schema = self.get_schema(table_name)?;
+        let table_dir = PathBuf::from(&self.path).join(table_name);
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+
+        let mut shards: HashMap<String, HashMap<String, Row>> = HashMap::new();
+        for path in shard_files(&table_dir)? {
+            shards.insert(file_name(&path), self.load_shard::<Row>(&path)?);
+        }
+
+        let mut moves = vec![];
+        for (shard, rows) in &shards {
+            for (id, row) in rows {
+                let key = match row.get(&schema.id_column) {
+                    Some((value @ (Data::STRING(_) | Data::NUMBER(_)), _)) => {
+                        Self::string_to_numerical_uuid(&value.to_string())
+                    }
+                    _ => id.clone(),
+                };
+                let target = self.shard_file(&schema, &key);
+                if target != *shard || key != *id {
+                    moves.push((shard.clone(), id.clone(), target, key));
+                }
+            }
+        }
+
+        let mut gained = HashSet::new();
+        let mut lost = HashSet::new();
+        let mut moved = 0;
+        for (shard, id, target, key) in &moves {
+            if shards.get(target).is_some_and(|rows| rows.contains_key(key)) {
+                continue;
+            }
+            let row = shards.get_mut(shard).and_then(|rows| rows.remove(id)).unwrap();
+            shards.entry(target.clone()).or_default().insert(key.clone(), row);
+            gained.insert(target.clone());
+            lost.insert(shard.clone());
+            moved += 1;
+        }
+
+        // write the shards rows moved into before the ones they left, so a
+        // crash in between leaves a duplicate rather than a lost row
+        for shard in gained.iter().chain(lost.difference(&gained)) {
+            self.save_shard(&table_dir.join(shard), &shards[shard])?;
+        }
+
+        Ok(moved)
+    }
+
+    /// Brings every row's fields in line with the schema after shards were
+    /// edited by hand: fields the schema doesn't have are dropped and missing
+    /// ones are added with their type's empty value (`Data::zero_of`, null
+    /// for nullable types). Values already there aren't checked or converted;
+    /// `check` reports those. Returns how many rows were changed.
+    pub fn normalize_rows(&self, table_name: &str) -> Result<usize> {
+        self.check_writable()?;
+        let schema = self.get_schema(table_name)?;
+        let table_dir = PathBuf::from(&self.path).join(table_name);
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        let mut changed = 0;
+
+        for path in shard_files(&table_dir)? {
+            let mut rows = self.load_shard::<Row>(&path)?;
+            let mut shard_changed = false;
+
+            for row in rows.values_mut() {
+                let before = row.len();
+                row.retain(|field, _| schema.field_names.contains_key(field));
+                let mut row_changed = row.len() != before;
+                for (field, (ty, _)) in &schema.field_names {
+                    if !row.contains_key(field) {
+                        row.insert(field.clone(), (Data::zero_of(ty), String::new()));
+                        row_changed = true;
+                    }
+                }
+                if row_changed {
+                    changed += 1;
+                    shard_changed = true;
+                }
+            }
+
+            if shard_changed {
+                self.save_shard(&path, &rows)?;
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crud::make::Type;
+    use crate::crud::wal::load_shard;
+
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, DATABASE) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..10 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        (temp_dir, db)
+    }
+
+    fn shard_of(db: &DATABASE, id: &str) -> String {
+        db.shard_file(&db.get_schema("users").unwrap(), &DATABASE::string_to_numerical_uuid(id))
+    }
+
+    #[test]
+    fn test_check_clean_table() {
+        let (_temp_dir, db) = setup();
+        assert_eq!(db.check("users").unwrap(), vec![]);
+        assert!(db.check("missing").is_err());
+    }
+
+    #[test]
+    fn test_check_finds_misplaced_row() {
+        let (temp_dir, db) = setup();
+        let table_dir = temp_dir.path().join("users");
+
+        // move u3 out of its own shard into one its key doesn't hash to
+        let home = table_dir.join(shard_of(&db, "u3"));
+        let mut rows: HashMap<String, Value> = load_shard(&home).unwrap();
+        let key = DATABASE::string_to_numerical_uuid("u3");
+        let row = rows.remove(&key).unwrap();
+        fs::write(&home, serde_json::to_string(&rows).unwrap()).unwrap();
+        let stray = "1-2.txt";
+        fs::write(table_dir.join(stray), serde_json::to_string(&HashMap::from([(key.clone(), row)])).unwrap())
+            .unwrap();
+
+        assert!(db.get_by_id("users".to_string(), "u3".to_string()).unwrap().is_none());
+        assert_eq!(
+            db.check("users").unwrap(),
+            vec![Issue::Misplaced { shard: stray.to_string(), id: key, expected: shard_of(&db, "u3") }]
+        );
+    }
+
+    #[test]
+    fn test_reshard_verify_moves_misplaced_rows() {
+        let (temp_dir, db) = setup();
+        let table_dir = temp_dir.path().join("users");
+
+        // u3 sits in a stray shard; u5 is in the right shard but under a stale key
+        let u3 = DATABASE::string_to_numerical_uuid("u3");
+        let home = table_dir.join(shard_of(&db, "u3"));
+        let mut rows: HashMap<String, Value> = load_shard(&home).unwrap();
+        let row = rows.remove(&u3).unwrap();
+        fs::write(&home, serde_json::to_string(&rows).unwrap()).unwrap();
+        fs::write(table_dir.join("1-2.txt"), serde_json::to_string(&HashMap::from([(u3, row)])).unwrap()).unwrap();
+
+        let u5 = DATABASE::string_to_numerical_uuid("u5");
+        let home = table_dir.join(shard_of(&db, "u5"));
+        let mut rows: HashMap<String, Value> = load_shard(&home).unwrap();
+        let row = rows.remove(&u5).unwrap();
+        let last = if u5.ends_with('0') { '1' } else { '0' };
+        let stale = format!("{}{}", &u5[..u5.len() - 1], last);
+        rows.insert(stale, row);
+        fs::write(&home, serde_json::to_string(&rows).unwrap()).unwrap();
+
+        assert!(db.get_by_id("users".to_string(), "u3".to_string()).unwrap().is_none());
+        assert!(db.get_by_id("users".to_string(), "u5".to_string()).unwrap().is_none());
+
+        assert_eq!(db.reshard_verify("users").unwrap(), 2);
+        assert!(db.get_by_id("users".to_string(), "u3".to_string()).unwrap().is_some());
+        assert!(db.get_by_id("users".to_string(), "u5".to_string()).unwrap().is_some());
+        assert_eq!(db.count_rows("users".to_string()), 10);
+        assert_eq!(db.check("users").unwrap(), vec![]);
+
+        assert_eq!(db.reshard_verify("users").unwrap(), 0);
+
+        // a shard that can't be read stops it before anything is written
+        fs::write(&home, "{not json").unwrap();
+        assert!(db.reshard_verify("users").is_err());
+        assert_eq!(fs::read_to_string(&home).unwrap(), "{not json");
+    }
+
+    #[test]
+    fn test_check_reports_and_repair_sets_aside_broken_data() {
+        let (temp_dir, db) = setup();
+        let table_dir = temp_dir.path().join("users");
+
+        fs::write(table_dir.join("0-1.txt"), "{not json").unwrap();
+
+        let u1 = DATABASE::string_to_numerical_uuid("u1");
+        let u2 = DATABASE::string_to_numerical_uuid("u2");
+        let home = table_dir.join(shard_of(&db, "u1"));
+        let mut rows: HashMap<String, Value> = load_shard(&home).unwrap();
+        rows.insert(u1.clone(), Value::from("not a row"));
+        fs::write(&home, serde_json::to_string(&rows).unwrap()).unwrap();
+
+        let home = table_dir.join(shard_of(&db, "u2"));
+        let mut rows: HashMap<String, Value> = load_shard(&home).unwrap();
+        rows.get_mut(&u2).unwrap()["age"] = serde_json::json!([{ "STRING": "old" }, ""]);
+        fs::write(&home, serde_json::to_string(&rows).unwrap()).unwrap();
+
+        let issues = db.check("users").unwrap();
+        assert!(issues.iter().any(|i| matches!(i, Issue::UnparseableShard { shard, .. } if shard == "0-1.txt")));
+        assert!(issues.iter().any(|i| matches!(i, Issue::UnparseableRow { id, .. } if *id == u1)));
+        assert!(issues.iter().any(|i| matches!(
+            i,
+            Issue::Schema { id, error: FieldError::Type { field, .. }, .. } if *id == u2 && field == "age"
+        )));
+
+        assert_eq!(db.repair("users").unwrap(), 2);
+        assert!(table_dir.join("0-1.txt.corrupt").exists());
+        assert!(db.check("users").unwrap().iter().all(|i| matches!(i, Issue::Schema { .. })));
+        assert_eq!(db.count_rows("users".to_string()), 9);
+    }
+
+    #[test]
+    fn test_check_finds_duplicate_and_missing_id() {
+        let (temp_dir, db) = setup();
+        let table_dir = temp_dir.path().join("users");
+
+        let u4 = DATABASE::string_to_numerical_uuid("u4");
+        let home = table_dir.join(shard_of(&db, "u4"));
+        let rows: HashMap<String, Value> = load_shard(&home).unwrap();
+        let mut copy = HashMap::from([(u4.clone(), rows[&u4].clone())]);
+        copy.insert("123".to_string(), serde_json::json!({ "age": [{ "NUMBER": 1.0 }, ""] }));
+        fs::write(table_dir.join("9-9.txt"), serde_json::to_string(&copy).unwrap()).unwrap();
+
+        let issues = db.check("users").unwrap();
+        assert!(issues.contains(&Issue::DuplicateId { id: u4, shards: vec![shard_of(&db, "u4"), "9-9.txt".to_string()] }));
+        assert!(issues.contains(&Issue::MissingId { shard: "9-9.txt".to_string(), id: "123".to_string() }));
+    }
+
+    #[test]
+    fn test_normalize_rows_fixes_hand_edited_row() {
+        let (temp_dir, db) = setup();
+
+        let shard = temp_dir.path().join("users").join(shard_of(&db, "u3"));
+        let key = DATABASE::string_to_numerical_uuid("u3");
+        let mut rows: HashMap<String, Row> = serde_json::from_str(&fs::read_to_string(&shard).unwrap()).unwrap();
+        let row = rows.get_mut(&key).unwrap();
+        row.remove("age");
+        row.insert("nickname".to_string(), (Data::STRING("trey".to_string()), String::new()));
+        fs::write(&shard, serde_json::to_string(&rows).unwrap()).unwrap();
+        assert!(!db.check("users").unwrap().is_empty());
+
+        assert_eq!(db.normalize_rows("users").unwrap(), 1);
+        let row = db.get_by_id("users".to_string(), "u3".to_string()).unwrap().unwrap();
+        assert_eq!(row["age"].0, Data::NUMBER(0.0));
+        assert!(!row.contains_key("nickname"));
+        assert!(db.check("users").unwrap().is_empty());
+
+        // already in line: nothing to do
+        assert_eq!(db.normalize_rows("users").unwrap(), 0);
+    }
+}
@@ -1,41 +1,196 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 
-use crate::crud::make::{Data, DATABASE};
+use crate::crud::make::{reference_value, Data, DATABASE, OnDelete, Row};
 use crate::crud::u::CMP;
+use crate::crud::stats::bump;
+use crate::crud::wal::shard_files;
+use crate::error::{Error, Result};
 
 impl DATABASE {
+    /// `delete_by_id`, taking owned strings.
     pub fn delete_row_by_id(
         &self,
         tablename: String,
         id_: String,
-    ) -> Option<HashMap<String, (Data, String)>> {
-        let id = Self::string_to_numerical_uuid(&id_);
-        let filename = Self::get_file_by_id(id.clone());
+    ) -> Result<Option<HashMap<String, (Data, String)>>> {
+        self.delete_by_id(&tablename, &id_)
+    }
+
+    /// Deletes the row with id `id_` and returns it, or `Ok(None)` if there is
+    /// none. Rows in other tables referencing it through a foreign key are
+    /// handled by that key's `OnDelete`: with `Restrict` nothing is deleted and
+    /// this fails with `Error::StillReferenced`, with `Cascade` they are
+    /// deleted too, and so on down their own references.
+    pub fn delete_by_id(&self, table_name: &str, id_: &str) -> Result<Option<Row>> {
+        self.check_writable()?;
+        let path = self.shard_path(table_name, id_);
+        if !path.exists() || !self.load_shard::<Row>(&path)?.contains_key(&Self::string_to_numerical_uuid(id_)) {
+            return Ok(None);
+        }
 
-        let mut path = PathBuf::from(&self.path);
-        path.push(&tablename);
-        path.push(&filename);
+        self.remove_rows(self.doomed_rows(table_name, id_)?)
+    }
 
+    /// The rows deleting row `id_` of `table_name` takes with it, that row
+    /// first: those referencing it through a `Cascade` foreign key, and so on
+    /// down their own references. Fails with `Error::StillReferenced` if a
+    /// `Restrict` key references any of them.
+    pub(crate) fn doomed_rows(&self, table_name: &str, id_: &str) -> Result<Vec<(String, String)>> {
+        let mut doomed = vec![(table_name.to_string(), id_.to_string())];
+        let mut i = 0;
+        while i < doomed.len() {
+            let (table, id) = doomed[i].clone();
+            for (child_table, field, on_delete, child_id) in self.referencing_rows(&table, &id)? {
+                match on_delete {
+                    OnDelete::Restrict => {
+                        return Err(Error::StillReferenced { id, table: child_table, field });
+                    }
+                    OnDelete::Cascade => {
+                        if !doomed.contains(&(child_table.clone(), child_id.clone())) {
+                            doomed.push((child_table, child_id));
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+        Ok(doomed)
+    }
+
+    /// Removes each (table, id) of `rows` with `remove_row`, taking each
+    /// table's write lock in turn, and returns the first of them.
+    fn remove_rows(&self, rows: Vec<(String, String)>) -> Result<Option<Row>> {
+        let mut first = None;
+        for (table, id) in rows {
+            let lock = self.table_lock(&table);
+            let _guard = lock.write().unwrap();
+            let row = self.remove_row(&table, &id)?;
+            first.get_or_insert(row);
+        }
+        Ok(first.flatten())
+    }
+
+    /// Removes the row with id `id_` from its shard, without looking at
+    /// foreign keys, and returns it. The caller holds the table's write lock.
+    fn remove_row(&self, table_name: &str, id_: &str) -> Result<Option<Row>> {
+        let path = self.shard_path(table_name, id_);
         if !path.exists() {
-            return None;
+            return Ok(None);
+        }
+        let mut rows = self.load_shard::<Row>(&path)?;
+        let row = rows.remove(&Self::string_to_numerical_uuid(id_));
+        if row.is_some() {
+            self.save_shard(&path, &rows)?;
+            bump(&self.counters.rows_deleted, 1);
+        }
+        Ok(row)
+    }
+
+    /// Whether any table has a foreign key into `table_name`.
+    fn is_referenced(&self, table_name: &str) -> Result<bool> {
+        for name in self.table_names()? {
+            if self.get_schema(&name)?.foreign_keys.values().any(|key| key.table == table_name) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Every row referencing row `id_` of `table_name` through a foreign key,
+    /// as (table, field, policy, id of the referencing row).
+    fn referencing_rows(&self, table_name: &str, id_: &str) -> Result<Vec<(String, String, OnDelete, String)>> {
+        let mut found = vec![];
+        for name in self.table_names()? {
+            let schema = self.get_schema(&name)?;
+            for (field, foreign_key) in &schema.foreign_keys {
+                if foreign_key.table != table_name {
+                    continue;
+                }
+                for (_, row) in self.iter_rows(name.clone()) {
+                    if reference_value(&row, field).as_deref() != Some(id_) {
+                        continue;
+                    }
+                    if let Some(child_id) = reference_value(&row, &schema.id_column) {
+                        found.push((name.clone(), field.clone(), foreign_key.on_delete, child_id));
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Deletes many rows by id, rewriting each affected shard once. Returns how
+    /// many of them were actually removed; ids that don't exist aren't
+    /// counted. Foreign keys apply as in `delete_by_id`, row by row: a row a
+    /// `Restrict` key still references is kept (and not counted), and rows a
+    /// `Cascade` key takes along are deleted once the batch is written.
+    pub fn delete_many_by_id(&self, tablename: String, ids: &[String]) -> Result<usize> {
+        self.check_writable()?;
+        let mut cascaded = vec![];
+        let mut kept = vec![];
+        if self.is_referenced(&tablename)? {
+            for id_ in ids {
+                match self.doomed_rows(&tablename, id_) {
+                    Ok(doomed) => {
+                        cascaded.extend(doomed.into_iter().skip(1));
+                        kept.push(id_);
+                    }
+                    Err(Error::StillReferenced { .. }) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        } else {
+            kept.extend(ids);
         }
 
-        let data_str = fs::read_to_string(&path).ok()?;
-        let mut deser: HashMap<String, HashMap<String, (Data, String)>> =
-            serde_json::from_str(&data_str).ok()?;
+        let mut by_shard: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        for id_ in kept {
+            let id = Self::string_to_numerical_uuid(id_);
+            by_shard.entry(self.shard_path(&tablename, id_)).or_default().push(id);
+        }
 
-        let got = deser.remove(&id);
+        let lock = self.table_lock(&tablename);
+        let guard = lock.write().unwrap();
+        // every shard is read before any is written, so a shard that can't be
+        // read fails the batch without deleting anything
+        let mut shrunk = vec![];
+        for (path, shard_ids) in by_shard {
+            if !path.exists() {
+                continue;
+            }
+            let mut deser = self.load_shard::<Row>(&path)?;
 
-        if let Some(_) = got {
-            let str_new_data = serde_json::to_string(&deser).ok()?;
-            fs::write(path, str_new_data).ok()?;
+            let before = deser.len();
+            for id in shard_ids {
+                deser.remove(&id);
+            }
+            let removed = before - deser.len();
+            if removed > 0 {
+                shrunk.push((path, deser, removed));
+            }
         }
 
-        got
+        let mut deleted = 0;
+        for (path, deser, removed) in shrunk {
+            self.save_shard(&path, &deser)?;
+            bump(&self.counters.rows_deleted, removed);
+            deleted += removed;
+        }
+        drop(guard);
+
+        self.remove_rows(cascaded)?;
+        Ok(deleted)
     }
 
+    /// Deletes the rows whose `fieldname` matches `fieldvalue` under `cmp` and
+    /// returns how many were deleted. With `multi` false only one row goes:
+    /// the first match in shard and then id order. Only shards that lose a row
+    /// are rewritten. Foreign keys apply as in `delete_by_id`: if a `Restrict`
+    /// key references any match, nothing is deleted and this fails with
+    /// `Error::StillReferenced`; rows a `Cascade` key takes along are deleted
+    /// after the matches.
     pub fn delete_row_where(
         &self,
         tablename: String,
@@ -43,61 +198,114 @@ impl DATABASE {
         fieldvalue: Data,
         multi: bool,
         cmp: CMP,
-    ) {
-        let mut path = PathBuf::from(&self.path);
-        path.push(&tablename);
-
-        let ents = match fs::read_dir(&path) {
-            Ok(e) => e,
-            Err(_) => return,
-        };
-
-        for entry in ents.filter_map(Result::ok) {
-            let file_path = entry.path();
-            let data_str = match fs::read_to_string(&file_path) {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
-
-            let mut deser: HashMap<String, HashMap<String, (Data, String)>> =
-                match serde_json::from_str(&data_str) {
-                    Ok(d) => d,
-                    Err(_) => continue,
-                };
-
-            let mut modified = false;
-
-            let keys_to_remove: Vec<String> = deser
+    ) -> Result<usize> {
+        self.check_writable()?;
+        let schema = self.get_schema(&tablename)?;
+        let referenced = self.is_referenced(&tablename)?;
+        let path = PathBuf::from(&self.path).join(&tablename);
+
+        let lock = self.table_lock(&tablename);
+        let guard = lock.write().unwrap();
+        // shards losing a row, with the rows they keep; nothing is written
+        // until every match has got past the foreign keys
+        let mut shrunk = vec![];
+        let mut cascaded = vec![];
+        for file_path in shard_files(&path)? {
+            let mut deser = self.load_shard::<Row>(&file_path)?;
+
+            let mut keys_to_remove: Vec<String> = deser
                 .iter()
                 .filter(|(_, row)| {
                     if let Some((val, _)) = row.get(&fieldname) {
-                        cmp.clone().calculate(fieldvalue.clone(), val.clone())
+                        cmp.calculate(&fieldvalue, val)
                     } else {
                         false
                     }
                 })
                 .map(|(id, _)| id.clone())
                 .collect();
+            if keys_to_remove.is_empty() {
+                continue;
+            }
+            if !multi {
+                keys_to_remove.sort();
+                keys_to_remove.truncate(1);
+            }
 
-            for id in keys_to_remove.iter() {
-                deser.remove(id);
-                modified = true;
-                if !multi {
-                    break;
+            for key in &keys_to_remove {
+                let id = deser.remove(key).and_then(|row| reference_value(&row, &schema.id_column));
+                if let (true, Some(id)) = (referenced, id) {
+                    cascaded.extend(self.doomed_rows(&tablename, &id)?.into_iter().skip(1));
                 }
             }
+            shrunk.push((file_path, deser, keys_to_remove.len()));
+            if !multi {
+                break;
+            }
+        }
+
+        let mut deleted = 0;
+        for (file_path, deser, removed) in shrunk {
+            self.save_shard(&file_path, &deser)?;
+            bump(&self.counters.rows_deleted, removed);
+            deleted += removed;
+        }
+        drop(guard);
 
-            if modified {
-                let updated_str = match serde_json::to_string(&deser) {
-                    Ok(s) => s,
-                    Err(_) => continue,
-                };
-                fs::write(file_path, updated_str).ok();
+        self.remove_rows(cascaded)?;
+        Ok(deleted)
+    }
+
+    /// Deletes every row of `table_name` but keeps the table and its schema.
+    /// Rows of other tables referencing them are handled as in `delete_by_id`:
+    /// if a `Restrict` foreign key references any, nothing is deleted and this
+    /// fails with `Error::StillReferenced`; `Cascade` ones are deleted first.
+    pub fn truncate_table(&self, table_name: &str) -> Result<()> {
+        self.check_writable()?;
+        let schema = self.get_schema(table_name)?;
+
+        let mut cascaded = vec![];
+        if self.is_referenced(table_name)? {
+            let ids: HashSet<String> = self
+                .iter_rows(table_name.to_string())
+                .filter_map(|(_, row)| reference_value(&row, &schema.id_column))
+                .collect();
+            for name in self.table_names()? {
+                if name == table_name {
+                    continue;
+                }
+                let child = self.get_schema(&name)?;
+                for (field, foreign_key) in child.foreign_keys.iter().filter(|(_, key)| key.table == table_name) {
+                    for (_, row) in self.iter_rows(name.clone()) {
+                        let Some(id) = reference_value(&row, field).filter(|id| ids.contains(id)) else {
+                            continue;
+                        };
+                        if foreign_key.on_delete == OnDelete::Restrict {
+                            return Err(Error::StillReferenced { id, table: name, field: field.clone() });
+                        }
+                        if let Some(child_id) = reference_value(&row, &child.id_column) {
+                            cascaded.extend(self.doomed_rows(&name, &child_id)?);
+                        }
+                    }
+                }
             }
         }
+        self.remove_rows(cascaded)?;
+
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        let path = PathBuf::from(&self.path).join(table_name);
+        for shard in shard_files(&path)? {
+            fs::remove_file(shard)?;
+        }
+        self.touch_table(table_name);
+
+        Ok(())
     }
 
     // Helper reused from previous code
+    /// Shard file name for `id` at the default shard width. Databases opened
+    /// with a different `shard_digits` name their shards differently.
     pub fn get_file_by_id(id: String) -> String {
         let mut start = id.clone();
         let mut end = id;
@@ -108,3 +316,242 @@ impl DATABASE {
         format!("{}0000000-{}9999999.txt", start, end)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::crud::make::{OnDelete, Type};
+
+    use super::*;
+
+    #[test]
+    fn test_truncate_table_keeps_schema() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..20 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("name".to_string(), (Data::STRING("x".to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+        assert_eq!(db.count_rows("users".to_string()), 20);
+
+        db.truncate_table("users").unwrap();
+        assert_eq!(db.count_rows("users".to_string()), 0);
+
+        let schema = db.get_schema("users").unwrap();
+        assert!(schema.field_names.contains_key("id"));
+        assert!(schema.field_names.contains_key("name"));
+
+        // still usable afterwards
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("name".to_string(), (Data::STRING("y".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+        assert_eq!(db.count_rows("users".to_string()), 1);
+
+        assert!(db.truncate_table("missing").is_err());
+    }
+
+    #[test]
+    fn test_delete_many_by_id_across_shards() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..80 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let mut ids: Vec<String> = (0..50).map(|i| format!("u{}", i)).collect();
+        ids.push("missing".to_string());
+        assert_eq!(db.delete_many_by_id("users".to_string(), &ids).unwrap(), 50);
+
+        assert_eq!(db.count_rows("users".to_string()), 30);
+        assert!(db.get_by_id("users".to_string(), "u0".to_string()).unwrap().is_none());
+        for i in 50..80 {
+            assert!(db.get_by_id("users".to_string(), format!("u{}", i)).unwrap().is_some());
+        }
+    }
+
+    fn setup_orders(on_delete: OnDelete) -> (tempfile::TempDir, DATABASE) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("user_id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+        db.add_foreign_key("orders", "user_id", "users", on_delete).unwrap();
+
+        for user in ["u1", "u2"] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(user.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+        for (order, user) in [("o1", "u1"), ("o2", "u1"), ("o3", "u2")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(order.to_string()), String::new()));
+            row.insert("user_id".to_string(), (Data::STRING(user.to_string()), String::new()));
+            db.add_row("orders".to_string(), row, false).unwrap();
+        }
+
+        (temp_dir, db)
+    }
+
+    #[test]
+    fn test_delete_referenced_row_restricted() {
+        let (_temp_dir, db) = setup_orders(OnDelete::Restrict);
+
+        let err = db.delete_by_id("users", "u1").unwrap_err();
+        assert!(matches!(err, Error::StillReferenced { .. }));
+        assert!(matches!(
+            db.delete_row_by_id("users".to_string(), "u1".to_string()),
+            Err(Error::StillReferenced { .. })
+        ));
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+
+        // once nothing points at it, it goes
+        db.delete_by_id("orders", "o3").unwrap().unwrap();
+        assert!(db.delete_by_id("users", "u2").unwrap().is_some());
+        assert!(db.delete_by_id("users", "u2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_delete_referenced_row_cascades() {
+        let (_temp_dir, db) = setup_orders(OnDelete::Cascade);
+
+        let deleted = db.delete_by_id("users", "u1").unwrap().unwrap();
+        assert_eq!(deleted["id"].0, Data::STRING("u1".to_string()));
+        assert_eq!(db.count_rows("orders".to_string()), 1);
+        assert!(db.get_by_id("orders".to_string(), "o3".to_string()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_delete_many_and_truncate_follow_foreign_keys() {
+        let (_temp_dir, db) = setup_orders(OnDelete::Restrict);
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u3".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        // u1 still has orders, so only u3 goes
+        let ids = ["u1".to_string(), "u3".to_string()];
+        assert_eq!(db.delete_many_by_id("users".to_string(), &ids).unwrap(), 1);
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+        assert!(matches!(db.truncate_table("users"), Err(Error::StillReferenced { .. })));
+        assert_eq!(db.count_rows("users".to_string()), 2);
+
+        let (_temp_dir, db) = setup_orders(OnDelete::Cascade);
+        assert_eq!(db.delete_many_by_id("users".to_string(), &["u1".to_string()]).unwrap(), 1);
+        assert_eq!(db.count_rows("orders".to_string()), 1);
+        db.truncate_table("users").unwrap();
+        assert_eq!(db.count_rows("users".to_string()), 0);
+        assert_eq!(db.count_rows("orders".to_string()), 0);
+    }
+
+    #[test]
+    fn test_delete_many_by_id_failing_write_cascades_nothing() {
+        let (_temp_dir, db) = setup_orders(OnDelete::Cascade);
+        let shard = db.shard_path("users", "u1");
+        fs::write(&shard, "{not json").unwrap();
+
+        assert!(db.delete_many_by_id("users".to_string(), &["u1".to_string()]).is_err());
+        assert_eq!(fs::read_to_string(&shard).unwrap(), "{not json");
+        assert_eq!(db.count_rows("orders".to_string()), 3);
+    }
+
+    #[test]
+    fn test_delete_row_where_follows_foreign_keys() {
+        let user_is = |id: &str| Data::STRING(id.to_string());
+
+        let (_temp_dir, db) = setup_orders(OnDelete::Restrict);
+        let err = db.delete_row_where("users".to_string(), "id".to_string(), user_is("u2"), true, CMP::EQUAL);
+        assert!(matches!(err, Err(Error::StillReferenced { .. })));
+        assert_eq!(db.count_rows("users".to_string()), 2);
+
+        let (_temp_dir, db) = setup_orders(OnDelete::Cascade);
+        assert_eq!(db.delete_row_where("users".to_string(), "id".to_string(), user_is("u1"), true, CMP::EQUAL).unwrap(), 1);
+        assert_eq!(db.count_rows("orders".to_string()), 1);
+        assert!(db.get_by_id("orders".to_string(), "o3".to_string()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_delete_row_where_single_across_shards() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("team".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        for i in 0..40 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            let team = if i % 2 == 0 { "red" } else { "blue" };
+            row.insert("team".to_string(), (Data::STRING(team.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+        assert!(db.shard_stats("users").iter().filter(|(_, rows)| *rows > 0).count() > 1);
+
+        let red = Data::STRING("red".to_string());
+        let count_red = || db.query("users".to_string()).where_("team", crate::Operator::Eq, red.clone()).count();
+        assert_eq!(db.delete_row_where("users".to_string(), "team".to_string(), red.clone(), false, CMP::EQUAL).unwrap(), 1);
+        assert_eq!(count_red(), 19);
+        assert_eq!(db.delete_row_where("users".to_string(), "team".to_string(), red.clone(), true, CMP::EQUAL).unwrap(), 19);
+        assert_eq!(count_red(), 0);
+        assert_eq!(db.count_rows("users".to_string()), 20);
+        assert_eq!(db.delete_row_where("users".to_string(), "team".to_string(), red.clone(), true, CMP::EQUAL).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_delete_row_where_single_stops_at_first_shard() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("team".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        // one "red" row in each of two different shards, "blue" everywhere else
+        let mut reds: Vec<String> = vec![];
+        for i in 0..40 {
+            let id = format!("u{}", i);
+            let red = reds.len() < 2 && reds.iter().all(|other| db.shard_path("users", other) != db.shard_path("users", &id));
+            if red {
+                reds.push(id.clone());
+            }
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id), String::new()));
+            let team = if red { "red" } else { "blue" };
+            row.insert("team".to_string(), (Data::STRING(team.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+        reds.sort_by_key(|id| db.shard_path("users", id));
+
+        let shards = db.shard_stats("users").into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        let first = db.shard_path("users", &reds[0]);
+        let position = shards.iter().position(|name| first.ends_with(name)).unwrap();
+
+        let red = Data::STRING("red".to_string());
+        let before = db.stats().shard_reads;
+        assert_eq!(db.delete_row_where("users".to_string(), "team".to_string(), red, false, CMP::EQUAL).unwrap(), 1);
+        // shards after the one holding the first match aren't even read
+        assert_eq!(db.stats().shard_reads - before, position as u64 + 1);
+        assert!(!db.exists_by_id("users", &reds[0]));
+        assert!(db.exists_by_id("users", &reds[1]));
+    }
+}
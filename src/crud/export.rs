@@ -0,0 +1,193 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer as _};
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter, Serializer};
+
+use crate::crud::make::{DATABASE, Row};
+use crate::crud::wal::shard_files;
+use crate::error::{Error, Result};
+
+/// Rows `import_ndjson` inserts per `add_rows` call.
+const IMPORT_BATCH: usize = 1000;
+
+/// One line of an NDJSON export: the row's id-column value and the row.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    id: String,
+    row: Row,
+}
+
+impl DATABASE {
+    /// Writes every row of `table_name` to `writer` as one JSON object per line,
+    /// `{"id": ..., "row": {...}}`, reading a single shard at a time so only
+    /// that shard is held in memory. Always compact, since each object must
+    /// fit on one line. Returns the number of rows written.
+    pub fn export_ndjson(&self, table_name: &str, mut writer: impl Write) -> Result<usize> {
+        let schema = self.get_schema(table_name)?;
+        let mut written = 0;
+
+        for shard in shard_files(&PathBuf::from(&self.path).join(table_name))? {
+            let mut rows: Vec<(String, Row)> = self.load_shard::<Row>(&shard)?.into_iter().collect();
+            rows.sort_by(|a, b| a.0.cmp(&b.0));
+            for (_, row) in rows {
+                let line = Line { id: schema.row_id(&row)?, row };
+                serde_json::to_writer(&mut writer, &line)?;
+                writer.write_all(b"\n")?;
+                written += 1;
+            }
+        }
+
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Writes every row of `table_name` to `writer` as a single JSON object
+    /// mapping each row's id-column value to the row, reading a single shard
+    /// at a time. Compact unless `pretty`, which indents it for reading at the
+    /// cost of roughly twice the size. Returns the number of rows written.
+    pub fn export_json(&self, table_name: &str, mut writer: impl Write, pretty: bool) -> Result<usize> {
+        let written = if pretty {
+            self.write_json_object(table_name, &mut writer, PrettyFormatter::new())?
+        } else {
+            self.write_json_object(table_name, &mut writer, CompactFormatter)?
+        };
+        writer.flush()?;
+        Ok(written)
+    }
+
+    fn write_json_object<W: Write, F: Formatter>(&self, table_name: &str, writer: W, formatter: F) -> Result<usize> {
+        let schema = self.get_schema(table_name)?;
+        let mut serializer = Serializer::with_formatter(writer, formatter);
+        let mut map = serializer.serialize_map(None)?;
+        let mut written = 0;
+
+        for shard in shard_files(&PathBuf::from(&self.path).join(table_name))? {
+            let mut rows: Vec<(String, Row)> = self.load_shard::<Row>(&shard)?.into_iter().collect();
+            rows.sort_by(|a, b| a.0.cmp(&b.0));
+            for (_, row) in rows {
+                map.serialize_entry(&schema.row_id(&row)?, &row)?;
+                written += 1;
+            }
+        }
+
+        map.end()?;
+        Ok(written)
+    }
+
+    /// Inserts the rows of an `export_ndjson` stream into `table_name`, reading
+    /// it line by line and inserting in batches, so the whole stream is never
+    /// in memory. Blank lines are skipped. Like `add_rows`, a row whose id is
+    /// already stored fails the import; batches inserted before it stay.
+    /// Returns the number of rows inserted.
+    pub fn import_ndjson(&self, table_name: &str, reader: impl BufRead) -> Result<usize> {
+        self.get_schema(table_name)?;
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        let mut imported = 0;
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line: Line = serde_json::from_str(&line)
+                .map_err(|e| Error::InvalidValue(format!("NDJSON line {}: {}", number + 1, e)))?;
+            batch.push(line.row);
+
+            if batch.len() == IMPORT_BATCH {
+                imported += batch.len();
+                self.add_rows(table_name.to_string(), std::mem::take(&mut batch), false)?;
+            }
+        }
+        imported += batch.len();
+        self.add_rows(table_name.to_string(), batch, false)?;
+
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::crud::builder::DatabaseBuilder;
+    use crate::crud::make::{Data, Type};
+
+    use super::*;
+
+    fn users(db: &DATABASE) {
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        fields.insert("tags".to_string(), (Type::ARRAYNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_ndjson_round_trip() {
+        let (from_dir, to_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let open = |dir: &tempfile::TempDir| {
+            DatabaseBuilder::new()
+                .path(dir.path().to_str().unwrap())
+                .shard_digits(2)
+                .build()
+                .unwrap()
+        };
+        let (from, to) = (open(&from_dir), open(&to_dir));
+        users(&from);
+        users(&to);
+
+        let rows: Vec<Row> = (0..2500)
+            .map(|i| {
+                let mut row = HashMap::new();
+                row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+                row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+                let tags = (i % 3 == 0).then(|| vec![Data::STRING("even".to_string())]);
+                row.insert("tags".to_string(), (Data::ARRAYNULL(tags), String::new()));
+                row
+            })
+            .collect();
+        from.add_rows("users".to_string(), rows, false).unwrap();
+        assert!(from.shard_stats("users").len() > 1);
+
+        let mut buffer: Vec<u8> = vec![];
+        assert_eq!(from.export_ndjson("users", &mut buffer).unwrap(), 2500);
+        let text = String::from_utf8(buffer.clone()).unwrap();
+        assert_eq!(text.lines().count(), 2500);
+        assert!(text.lines().all(|line| line.starts_with("{\"id\":\"u")));
+
+        assert_eq!(to.import_ndjson("users", buffer.as_slice()).unwrap(), 2500);
+        assert_eq!(to.get_all("users".to_string()).unwrap(), from.get_all("users".to_string()).unwrap());
+
+        // importing the same rows again collides on their ids
+        assert!(matches!(to.import_ndjson("users", buffer.as_slice()), Err(Error::UniqueViolation { .. })));
+        assert!(matches!(to.import_ndjson("users", &b"{not json\n"[..]), Err(Error::InvalidValue(_))));
+    }
+
+    #[test]
+    fn test_export_json_pretty_or_compact() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        users(&db);
+        for i in 0..3 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let export = |pretty: bool| {
+            let mut buffer = vec![];
+            assert_eq!(db.export_json("users", &mut buffer, pretty).unwrap(), 3);
+            String::from_utf8(buffer).unwrap()
+        };
+        let (compact, pretty) = (export(false), export(true));
+        assert!(!compact.contains('\n'));
+        assert!(pretty.contains('\n'));
+
+        let parsed: HashMap<String, Row> = serde_json::from_str(&compact).unwrap();
+        assert_eq!(parsed, serde_json::from_str::<HashMap<String, Row>>(&pretty).unwrap());
+        assert_eq!(parsed["u2"]["age"].0, Data::NUMBER(2.0));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crud::c::unique_values;
+use crate::crud::make::{Data, DATABASE, Row};
+use crate::crud::stats::bump;
+use crate::crud::wal::replace_file;
+use crate::error::{Error, Result};
+
+/// A secondary index on one field of a table, recorded in its schema.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Index {
+    pub field: String,
+    /// Also enforced on insert, through a unique constraint of the same name.
+    #[serde(default)]
+    pub unique: bool,
+}
+
+/// Index file contents: each value (as `Data`'s `Display` text) mapped to the
+/// keys of the rows holding it.
+type Entries = BTreeMap<String, Vec<String>>;
+
+impl DATABASE {
+    /// Adds index `name` on `field` and builds it from the rows already in the
+    /// table. With `unique`, no two rows may share a non-null value of
+    /// `field`: this fails if they already do, and later inserts that would
+    /// are rejected. See `find_by_index`.
+    pub fn add_index(&self, table_name: &str, name: &str, field: &str, unique: bool) -> Result<()> {
+        self.check_writable()?;
+        let schema = self.get_schema(table_name)?;
+        if schema.indexes.contains_key(name) {
+            return Err(Error::InvalidArgument(format!("index '{}' already exists on '{}'", name, table_name)));
+        }
+        if !schema.field_names.contains_key(field) {
+            return Err(Error::NotFound(format!("field '{}' in table '{}'", field, table_name)));
+        }
+        if unique {
+            self.add_unique(table_name, name, &[field])?;
+        }
+
+        let mut schema = self.get_schema(table_name)?;
+        schema.indexes.insert(name.to_string(), Index { field: field.to_string(), unique });
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        fs::write(&path, serde_json::to_string(&schema)?)?;
+        self.invalidate_schema(table_name);
+
+        self.index_entries(table_name, name)?;
+        Ok(())
+    }
+
+    /// Removes index `name`, and the unique constraint that came with it.
+    pub fn drop_index(&self, table_name: &str, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut schema = self.get_schema(table_name)?;
+        let index = schema
+            .indexes
+            .remove(name)
+            .ok_or_else(|| Error::NotFound(format!("index '{}' on '{}'", name, table_name)))?;
+        if index.unique {
+            schema.unique.remove(name);
+        }
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        fs::write(&path, serde_json::to_string(&schema)?)?;
+        self.invalidate_schema(table_name);
+
+        let path = self.index_path(table_name, name);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// The rows whose indexed field equals `value`, read through index `name`
+    /// instead of a scan. A nullable value matches its non-null form, so
+    /// `STRINGNULL(Some(x))` finds rows holding `STRING(x)`.
+    pub fn find_by_index(&self, table_name: &str, name: &str, value: &Data) -> Result<Vec<Row>> {
+        let entries = self.index_entries(table_name, name)?;
+        let Some(keys) = entries.get(&value.to_string()).filter(|_| !value.is_null()) else {
+            return Ok(vec![]);
+        };
+
+        let schema = self.get_schema(table_name)?;
+        let table_dir = PathBuf::from(&self.path).join(table_name);
+        let mut rows = vec![];
+        for key in keys {
+            let mut shard = self.load_shard::<Row>(&table_dir.join(self.shard_file(&schema, key)))?;
+            rows.extend(shard.remove(key));
+        }
+        bump(&self.counters.rows_read, rows.len());
+        Ok(rows)
+    }
+
+    /// Index `name`, read from its file, or built from the table's rows and
+    /// written out if the file is gone (kept in memory only on a read-only
+    /// database). Any write to the table deletes the file (see
+    /// `drop_index_files`), so it is never stale.
+    fn index_entries(&self, table_name: &str, name: &str) -> Result<Entries> {
+        let schema = self.get_schema(table_name)?;
+        let index = schema
+            .indexes
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("index '{}' on '{}'", name, table_name)))?;
+
+        self.cached_entries(table_name, &self.index_path(table_name, name), |row| match row.get(&index.field) {
+            Some((value, _)) if !value.is_null() => Some(value.to_string()),
+            _ => None,
+        })
+    }
+
+    /// Unique constraint `name`'s values in every stored row, like an index:
+    /// each row's values in the constraint's columns (as a JSON list of their
+    /// `Display` text) mapped to the keys of the rows holding them. Rows with
+    /// any of the columns missing or null are left out. Kept in a file of its
+    /// own, which writes drop like an index's.
+    pub(crate) fn unique_entries(&self, table_name: &str, name: &str) -> Result<Entries> {
+        let schema = self.get_schema(table_name)?;
+        let columns = schema
+            .unique
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("unique constraint '{}' on '{}'", name, table_name)))?;
+
+        self.cached_entries(table_name, &self.unique_path(table_name, name), |row| {
+            serde_json::to_string(&unique_values(row, columns)?).ok()
+        })
+    }
+
+    /// The entries stored at `path`, or built from the table's rows with
+    /// `key_of` (rows it gives no key are left out) and written there if the
+    /// file is gone (kept in memory only on a read-only database).
+    fn cached_entries(&self, table_name: &str, path: &Path, key_of: impl Fn(&Row) -> Option<String>) -> Result<Entries> {
+        if let Ok(text) = fs::read_to_string(path) {
+            if let Ok(entries) = serde_json::from_str(&text) {
+                return Ok(entries);
+            }
+        }
+
+        let mut entries = Entries::new();
+        for (key, row) in self.iter_rows(table_name.to_string()) {
+            if let Some(value) = key_of(&row) {
+                entries.entry(value).or_default().push(key);
+            }
+        }
+        if !self.read_only {
+            replace_file(path, &serde_json::to_string(&entries)?)?;
+        }
+        Ok(entries)
+    }
+
+    fn index_path(&self, table_name: &str, name: &str) -> PathBuf {
+        PathBuf::from(&self.path).join(format!("{}-index-{}.txt", table_name, name))
+    }
+
+    fn unique_path(&self, table_name: &str, name: &str) -> PathBuf {
+        PathBuf::from(&self.path).join(format!("{}-unique-{}.txt", table_name, name))
+    }
+
+    /// Deletes the index and unique constraint files of `table_name` after its
+    /// rows changed; they are rebuilt on next use.
+    pub(crate) fn drop_index_files(&self, table_name: &str) {
+        let Ok(schema) = self.get_schema(table_name) else {
+            return;
+        };
+        for name in schema.indexes.keys() {
+            let _ = fs::remove_file(self.index_path(table_name, name));
+        }
+        for name in schema.unique.keys() {
+            let _ = fs::remove_file(self.unique_path(table_name, name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::crud::make::Type;
+
+    use super::*;
+
+    fn user(id: &str, city: &str) -> Row {
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+        row.insert("city".to_string(), (Data::STRING(city.to_string()), String::new()));
+        row
+    }
+
+    #[test]
+    fn test_add_index_migration_backfills_and_records() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("city".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        for (id, city) in [("u1", "Oslo"), ("u2", "Rome"), ("u3", "Oslo")] {
+            db.add_row("users".to_string(), user(id, city), false).unwrap();
+        }
+
+        db.generate_add_index_migration("by_city", "users", "city", false).unwrap();
+        db.apply_migrations().unwrap();
+
+        let applied = fs::read_to_string(temp_dir.path().join("db/migrations/.migrations_applied")).unwrap();
+        assert!(applied.contains("add_index"));
+        assert!(db.get_schema("users").unwrap().indexes.contains_key("by_city"));
+        assert!(temp_dir.path().join("db/users-index-by_city.txt").exists());
+
+        let oslo = Data::STRING("Oslo".to_string());
+        assert_eq!(db.find_by_index("users", "by_city", &oslo).unwrap().len(), 2);
+
+        // a write drops the index file and the next lookup rebuilds it
+        db.add_row("users".to_string(), user("u4", "Oslo"), false).unwrap();
+        assert!(!temp_dir.path().join("db/users-index-by_city.txt").exists());
+        assert_eq!(db.find_by_index("users", "by_city", &oslo).unwrap().len(), 3);
+        db.delete_row_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(db.find_by_index("users", "by_city", &oslo).unwrap().len(), 2);
+        assert!(db.find_by_index("users", "by_city", &Data::STRING("Bern".to_string())).unwrap().is_empty());
+
+        db.generate_drop_index_migration("by_city", "users").unwrap();
+        db.apply_migrations().unwrap();
+        assert!(db.find_by_index("users", "by_city", &oslo).is_err());
+        assert!(!temp_dir.path().join("db/users-index-by_city.txt").exists());
+    }
+
+    #[test]
+    fn test_unique_index() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("city".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        db.add_row("users".to_string(), user("u1", "Oslo"), false).unwrap();
+
+        db.add_index("users", "city", "city", true).unwrap();
+        assert!(matches!(
+            db.add_row("users".to_string(), user("u2", "Oslo"), false),
+            Err(Error::UniqueConstraint { .. })
+        ));
+        db.add_row("users".to_string(), user("u2", "Rome"), false).unwrap();
+
+        db.drop_index("users", "city").unwrap();
+        db.add_row("users".to_string(), user("u3", "Oslo"), false).unwrap();
+        // the stored rows now break uniqueness
+        assert!(db.add_index("users", "city", "city", true).is_err());
+        assert!(db.get_schema("users").unwrap().indexes.is_empty());
+    }
+}
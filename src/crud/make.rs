@@ -1,13 +1,17 @@
 use std::{fs, path::PathBuf};
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
-use std::str::FromStr;
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use eyre::Result;
+use crate::error::{Error, Result};
+use crate::crud::builder::DatabaseBuilder;
+use crate::crud::cache::QueryCache;
+use crate::crud::index::Index;
+use crate::crud::stats::{bump, Counters};
 use crate::crud::u::CMP;
+use crate::crud::wal::shard_files;
 use crate::QueryBuilder;
 
 #[derive(Clone)]
@@ -24,9 +28,59 @@ pub struct Condition {
     pub logic: Box<LogicOp>,
 }
 
+pub type Row = HashMap<String, (Data, String)>;
+
+pub const CREATED_AT: &str = "created_at";
+pub const UPDATED_AT: &str = "updated_at";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DATABASE {
     pub path: String,
+    /// Parsed `{table}-type.txt` files, filled by `get_schema` and dropped
+    /// whenever this handle rewrites a schema. Clones share it.
+    #[serde(skip)]
+    pub(crate) schema_cache: Arc<RwLock<HashMap<String, TABLE>>>,
+    /// How many trailing digits of the id hash a shard spans (default 7).
+    #[serde(default = "default_shard_digits")]
+    pub(crate) shard_digits: usize,
+    /// Pretty-print shard files instead of writing them compact.
+    #[serde(default)]
+    pub(crate) pretty: bool,
+    /// Cached `execute` results, when turned on with
+    /// `DatabaseBuilder::query_cache`. Clones share it.
+    #[serde(skip)]
+    pub(crate) query_cache: Option<Arc<Mutex<QueryCache>>>,
+    /// One lock per table. Every write that reads a shard and writes it back
+    /// holds it exclusively from the read to the write: inserts, updates and
+    /// deletes, `ShardBuffer` flushes, compaction, resharding, migrations and
+    /// the repairs in `check.rs`. Concurrent writes to the same shard thus
+    /// can't overwrite each other's rows. Whole-table reads (`get_all`,
+    /// `get_table`, `QueryBuilder::execute` and `one`) hold it shared, so
+    /// they see a write spanning several shards entirely or not at all.
+    /// Clones share it; other handles and other processes don't, and
+    /// `iter_rows` and `get_by_id` read without it. It isn't reentrant:
+    /// helpers called under it (`insert_row`, `remove_row`) don't take it.
+    #[serde(skip)]
+    pub(crate) table_locks: Arc<Mutex<HashMap<String, Arc<RwLock<()>>>>>,
+    /// Set by `open_read_only`: every write fails with `Error::ReadOnly`.
+    #[serde(skip)]
+    pub(crate) read_only: bool,
+    /// Operation counters behind `stats`. Clones share them.
+    #[serde(skip)]
+    pub(crate) counters: Arc<Counters>,
+}
+
+pub(crate) const DEFAULT_SHARD_DIGITS: usize = 7;
+
+/// Name of the shard file holding id hash `id` when the last `digits` digits
+/// of the hash pick the row within a shard.
+fn shard_name(id: &str, digits: usize) -> String {
+    let base = &id[..id.len().saturating_sub(digits)];
+    format!("{base}{}-{base}{}.txt", "0".repeat(digits), "9".repeat(digits))
+}
+
+fn default_shard_digits() -> usize {
+    DEFAULT_SHARD_DIGITS
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -35,6 +89,113 @@ pub struct TABLE {
     pub id_column: String,
     pub field_names: HashMap<String, (Type, String)>,
     // rows: HashMap<String, ROW>
+    #[serde(default)]
+    pub timestamps: bool,
+    #[serde(default)]
+    pub required: HashSet<String>,
+    /// Composite unique constraints by name: no two rows may hold the same
+    /// values in every listed column (`add_unique`).
+    #[serde(default)]
+    pub unique: BTreeMap<String, Vec<String>>,
+    /// Fields that must hold the id of an existing row in another table, by
+    /// field name (`add_foreign_key`).
+    #[serde(default)]
+    pub foreign_keys: BTreeMap<String, ForeignKey>,
+    /// Secondary indexes by name (`add_index`).
+    #[serde(default)]
+    pub indexes: BTreeMap<String, Index>,
+    /// Values filled in for fields a row being inserted leaves out
+    /// (`set_default`).
+    #[serde(default)]
+    pub defaults: BTreeMap<String, Data>,
+    /// Shard width of this table, set by `reshard`; tables without one use
+    /// the database's `shard_digits`.
+    #[serde(default)]
+    pub shard_digits: Option<usize>,
+}
+
+/// How `add_row_autoid` makes up an id for a row that has none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// A random UUID v4 in its hyphenated form.
+    Uuid,
+    /// The hash (see `string_to_numerical_uuid`) of the current time in
+    /// nanoseconds and a random number.
+    TimestampRandom,
+    /// The table's next auto-increment value (`next_id`), stored as a
+    /// `NUMBER`, so the id column has to be a `NUMBER` one.
+    Sequence,
+}
+
+/// A field holding the id of a row in `table`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForeignKey {
+    pub table: String,
+    #[serde(default)]
+    pub on_delete: OnDelete,
+}
+
+/// What deleting a referenced row does to the rows referencing it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnDelete {
+    /// The delete fails while any row still references it.
+    #[default]
+    Restrict,
+    /// The referencing rows are deleted along with it.
+    Cascade,
+}
+
+/// One difference between two versions of a table's schema, as reported by
+/// `DATABASE::schema_diff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaChange {
+    AddedColumn { field: String, datatype: Type },
+    RemovedColumn { field: String, datatype: Type },
+    RetypedColumn { field: String, from: Type, to: Type },
+    IdColumnChanged { from: String, to: String },
+}
+
+impl TABLE {
+    /// Whether a row may leave `field` out: only nullable fields that aren't
+    /// marked required can be omitted.
+    pub fn is_optional(&self, field: &str) -> bool {
+        match self.field_names.get(field) {
+            Some((ty, _)) => ty.is_nullable() && !self.required.contains(field),
+            None => false,
+        }
+    }
+
+    /// The value of `row`'s id column as text, which the by-id operations
+    /// take. Fails if it is missing or neither a `STRING` nor a `NUMBER`.
+    pub fn row_id(&self, row: &Row) -> Result<String, FieldError> {
+        let (id, _) = row
+            .get(&self.id_column)
+            .ok_or_else(|| FieldError::Missing { field: self.id_column.clone() })?;
+        id.clone().try_get_string().ok_or_else(|| FieldError::Type {
+            field: self.id_column.clone(),
+            expected: Type::STRING,
+            actual: id.variant_name(),
+        })
+    }
+
+    /// Fills in the default of every defaulted field `row` leaves out.
+    pub fn apply_defaults(&self, row: &mut Row) {
+        for (field, default) in &self.defaults {
+            row.entry(field.clone()).or_insert_with(|| (default.clone(), String::new()));
+        }
+    }
+
+    /// Sets `created_at` / `updated_at` (unix millis) on rows of a table created
+    /// with timestamps. A row that already carries `created_at` keeps it.
+    pub fn stamp_timestamps(&self, row: &mut Row) {
+        if !self.timestamps {
+            return;
+        }
+        let now = Data::NUMBER(chrono::Utc::now().timestamp_millis() as f64);
+        row.entry(CREATED_AT.to_string())
+            .or_insert((now.clone(), String::new()));
+        row.insert(UPDATED_AT.to_string(), (now, String::new()));
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -47,7 +208,6 @@ pub enum Type {
     BOOLEAN,
     JSON,
     HASHSET,
-    TABLE,
     STRINGNULL,
     NUMBERNULL,
     ARRAYNULL,
@@ -55,111 +215,518 @@ pub enum Type {
     BOOLEANNULL,
     JSONNULL,
     HASHSETNULL,
-    TABLENULL,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Data {
     NULL,
     STRING(String),
+    /// Always an `f64`: a JSON integer like `30` reads back as `30.0`, so it
+    /// compares equal to a `NUMBER(30.0)` written from Rust.
     NUMBER(f64),
     ARRAY(Vec<Data>),
     BOOLEAN(bool),
     JSON(String),
+    HASHMAP(HashMap<String, Data>),
+    /// Unordered collection of distinct values. Kept as a `Vec`, which is how
+    /// it is stored; equality and hashing ignore element order.
+    HASHSET(Vec<Data>),
     STRINGNULL(Option<String>),
     NUMBERNULL(Option<f64>),
     ARRAYNULL(Option<Vec<Data>>),
     BOOLEANNULL(Option<bool>),
     JSONNULL(Option<String>),
+    HASHMAPNULL(Option<HashMap<String, Data>>),
+    HASHSETNULL(Option<Vec<Data>>),
+}
+/// Why a row failed schema validation in `DATABASE::validate_row`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldError {
+    Missing { field: String },
+    Null { field: String },
+    Unknown { field: String },
+    Type { field: String, expected: Type, actual: &'static str },
+    Regex { field: String, pattern: String },
+    Json { field: String, message: String },
 }
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldError::Missing { field } => write!(f, "missing required field '{}'", field),
+            FieldError::Null { field } => write!(f, "required field '{}' is null", field),
+            FieldError::Unknown { field } => write!(f, "unknown field '{}'", field),
+            FieldError::Type { field, expected, actual } => write!(
+                f,
+                "type mismatch on field '{}': expected {:?}, got {}",
+                field, expected, actual
+            ),
+            FieldError::Regex { field, pattern } => write!(
+                f,
+                "regex mismatch on field '{}': value does not match '{}'",
+                field, pattern
+            ),
+            FieldError::Json { field, message } => {
+                write!(f, "invalid JSON in field '{}': {}", field, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FieldError {}
+
 impl Type {
     pub fn from_string(s:String) -> std::result::Result<Type, &'static str> {
         match s.as_str() {
-            "NULL" => {return Ok(Type::NULL)}
-            "STRING" => {return Ok(Type::STRING)}
-            "NUMBER" => {return Ok(Type::NUMBER)}
-            "ARRAY" => {return Ok(Type::ARRAY)}
-            "HASHMAP" => {return Ok(Type::HASHMAP)}
-            "BOOLEAN" => {return Ok(Type::BOOLEAN)}
-            "JSON" => {return Ok(Type::JSON)}
-            "HASHSET" => {return Ok(Type::HASHSET)}
-            "TABLE" => {return Ok(Type::TABLE)}
-            "STRINGNULL" => {return Ok(Type::STRINGNULL)}
-            "NUMBERNULL" => {return Ok(Type::NUMBERNULL)}
-            "ARRAYNULL" => {return Ok(Type::ARRAYNULL)}
-            "HASHMAPNULL" => {return Ok(Type::HASHMAPNULL)}
-            "BOOLEANNULL" => {return Ok(Type::BOOLEANNULL)}
-            "JSONNULL" => {return Ok(Type::JSONNULL)}
-            "HASHSETNULL" => {return Ok(Type::HASHSETNULL)}
-            "TABLENULL" => {return Ok(Type::TABLENULL)}
-            _ => return Err("No type name")
+            "NULL" => Ok(Type::NULL),
+            "STRING" => Ok(Type::STRING),
+            "NUMBER" => Ok(Type::NUMBER),
+            "ARRAY" => Ok(Type::ARRAY),
+            "HASHMAP" => Ok(Type::HASHMAP),
+            "BOOLEAN" => Ok(Type::BOOLEAN),
+            "JSON" => Ok(Type::JSON),
+            "HASHSET" => Ok(Type::HASHSET),
+            "STRINGNULL" => Ok(Type::STRINGNULL),
+            "NUMBERNULL" => Ok(Type::NUMBERNULL),
+            "ARRAYNULL" => Ok(Type::ARRAYNULL),
+            "HASHMAPNULL" => Ok(Type::HASHMAPNULL),
+            "BOOLEANNULL" => Ok(Type::BOOLEANNULL),
+            "JSONNULL" => Ok(Type::JSONNULL),
+            "HASHSETNULL" => Ok(Type::HASHSETNULL),
+            _ => Err("No type name"),
+        }
+    }
+
+    /// The non-null counterpart of a nullable type (`STRINGNULL` -> `STRING`).
+    pub fn base(&self) -> Option<Type> {
+        match self {
+            Type::STRINGNULL => Some(Type::STRING),
+            Type::NUMBERNULL => Some(Type::NUMBER),
+            Type::ARRAYNULL => Some(Type::ARRAY),
+            Type::HASHMAPNULL => Some(Type::HASHMAP),
+            Type::BOOLEANNULL => Some(Type::BOOLEAN),
+            Type::JSONNULL => Some(Type::JSON),
+            Type::HASHSETNULL => Some(Type::HASHSET),
+            _ => None,
         }
     }
+
+    pub fn is_nullable(&self) -> bool {
+        matches!(
+            self,
+            Type::NULL
+                | Type::STRINGNULL
+                | Type::NUMBERNULL
+                | Type::ARRAYNULL
+                | Type::HASHMAPNULL
+                | Type::BOOLEANNULL
+                | Type::JSONNULL
+                | Type::HASHSETNULL
+        )
+    }
 }
 impl Data {
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            Data::NULL => "NULL",
+            Data::STRING(_) => "STRING",
+            Data::NUMBER(_) => "NUMBER",
+            Data::ARRAY(_) => "ARRAY",
+            Data::BOOLEAN(_) => "BOOLEAN",
+            Data::JSON(_) => "JSON",
+            Data::STRINGNULL(_) => "STRINGNULL",
+            Data::NUMBERNULL(_) => "NUMBERNULL",
+            Data::ARRAYNULL(_) => "ARRAYNULL",
+            Data::BOOLEANNULL(_) => "BOOLEANNULL",
+            Data::JSONNULL(_) => "JSONNULL",
+            Data::HASHMAP(_) => "HASHMAP",
+            Data::HASHSET(_) => "HASHSET",
+            Data::HASHMAPNULL(_) => "HASHMAPNULL",
+            Data::HASHSETNULL(_) => "HASHSETNULL",
+        }
+    }
+
+    /// `NULL` or a nullable variant holding `None`.
+    pub fn is_null(&self) -> bool {
+        matches!(
+            self,
+            Data::NULL
+                | Data::STRINGNULL(None)
+                | Data::NUMBERNULL(None)
+                | Data::ARRAYNULL(None)
+                | Data::BOOLEANNULL(None)
+                | Data::JSONNULL(None)
+                | Data::HASHMAPNULL(None)
+                | Data::HASHSETNULL(None)
+        )
+    }
+
+    /// Panics unless this is a `STRING` or `NUMBER`; see `try_get_string`.
     pub fn get_string(self) -> String {
+        self.try_get_string().expect("expected STRING or NUMBER but got different variant")
+    }
+    /// A `STRING`, or a `NUMBER` as text; `None` for any other variant.
+    pub fn try_get_string(self) -> Option<String> {
         match self {
-            Data::STRING(x) => x,
-            Data::NUMBER(x) => x.to_string(),
-            _ => panic!("expected STRING or NUMBER but got different variant"),
+            Data::STRING(x) => Some(x),
+            Data::NUMBER(x) => Some(x.to_string()),
+            _ => None,
         }
     }
+    /// Panics unless this is a `NUMBER`; see `try_get_number`.
     pub fn get_number(self) -> f64 {
+        self.try_get_number().expect("expected NUMBER but got different variant")
+    }
+    pub fn try_get_number(self) -> Option<f64> {
         match self {
-            Data::NUMBER(x) => x,
-            _ => panic!("expected NUMBER but got different variant"),
+            Data::NUMBER(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Integral `NUMBER`s as `i64`; errors on other variants and on
+    /// fractional or out-of-range values.
+    pub fn get_number_as_i64(self) -> Result<i64> {
+        let name = self.variant_name();
+        let x = self
+            .try_get_number()
+            .ok_or_else(|| Error::InvalidValue(format!("expected NUMBER but got {}", name)))?;
+        if x.fract() != 0.0 || x < i64::MIN as f64 || x >= i64::MAX as f64 {
+            return Err(Error::InvalidValue(format!("NUMBER {} is not an integer", x)));
         }
+        Ok(x as i64)
     }
+    /// Panics unless this is a `ARRAY`; see `try_get_array`.
     pub fn get_array(self) -> Vec<Data> {
+        self.try_get_array().expect("expected ARRAY but got different variant")
+    }
+    pub fn try_get_array(self) -> Option<Vec<Data>> {
         match self {
-            Data::ARRAY(x) => x,
-            _ => panic!("expected ARRAY but got different variant"),
+            Data::ARRAY(x) => Some(x),
+            _ => None,
         }
     }
+    /// Number of elements of an `ARRAY` or non-null `ARRAYNULL`; `None` for
+    /// anything else.
+    pub fn array_len(&self) -> Option<usize> {
+        self.as_array().map(Vec::len)
+    }
+    /// Element `i` of an `ARRAY` or non-null `ARRAYNULL`; `None` if out of
+    /// bounds or not an array.
+    pub fn array_get(&self, i: usize) -> Option<&Data> {
+        self.as_array()?.get(i)
+    }
+    fn as_array(&self) -> Option<&Vec<Data>> {
+        match self {
+            Data::ARRAY(x) | Data::ARRAYNULL(Some(x)) => Some(x),
+            _ => None,
+        }
+    }
+    /// Panics unless this is a `BOOLEAN`; see `try_get_boolean`.
     pub fn get_boolean(self) -> bool {
+        self.try_get_boolean().expect("expected BOOLEAN but got different variant")
+    }
+    pub fn try_get_boolean(self) -> Option<bool> {
         match self {
-            Data::BOOLEAN(x) => x,
-            _ => panic!("expected BOOLEAN but got different variant"),
+            Data::BOOLEAN(x) => Some(x),
+            _ => None,
         }
     }
+    /// Panics unless this is a `JSON`; see `try_get_json`.
     pub fn get_json(self) -> String {
+        self.try_get_json().expect("expected JSON but got different variant")
+    }
+    pub fn try_get_json(self) -> Option<String> {
+        match self {
+            Data::JSON(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Converts a value to `ty`, e.g. `STRING("42")` -> `NUMBER(42.0)`,
+    /// `NUMBER` -> `STRING`, `"true"`/`"1"` -> `BOOLEAN`, and anything into its
+    /// nullable form. Returns `None` when the value has no sensible conversion.
+    pub fn coerce(&self, ty: &Type) -> Option<Data> {
+        if data_eq_type(self, ty) {
+            return Some(self.clone());
+        }
+
+        if let Some(base) = ty.base() {
+            return match self.unwrap_nullable() {
+                Some(inner) => inner.coerce(&base).map(Data::into_nullable),
+                None => Data::null_of(ty),
+            };
+        }
+
+        let value = self.unwrap_nullable()?;
+        match (&value, ty) {
+            (_, Type::NULL) => Some(Data::NULL),
+            (Data::STRING(s), Type::NUMBER) => s.trim().parse().ok().map(Data::NUMBER),
+            (Data::STRING(s), Type::BOOLEAN) => match s.trim().to_lowercase().as_str() {
+                "true" | "1" => Some(Data::BOOLEAN(true)),
+                "false" | "0" => Some(Data::BOOLEAN(false)),
+                _ => None,
+            },
+            (Data::STRING(s), Type::JSON) => serde_json::from_str::<serde_json::Value>(s)
+                .ok()
+                .map(|_| Data::JSON(s.clone())),
+            (Data::NUMBER(_), Type::STRING) => Some(Data::STRING(value.to_string())),
+            (Data::NUMBER(n), Type::BOOLEAN) if *n == 0.0 || *n == 1.0 => Some(Data::BOOLEAN(*n == 1.0)),
+            (Data::BOOLEAN(_), Type::STRING) => Some(Data::STRING(value.to_string())),
+            (Data::BOOLEAN(b), Type::NUMBER) => Some(Data::NUMBER(if *b { 1.0 } else { 0.0 })),
+            (Data::JSON(j), Type::STRING) => Some(Data::STRING(j.clone())),
+            (Data::ARRAY(a), Type::HASHSET) => Some(Data::HASHSET(a.clone())),
+            (Data::HASHSET(a), Type::ARRAY) => Some(Data::ARRAY(a.clone())),
+            (v, Type::JSON) => Some(Data::JSON(v.to_json_value().to_string())),
+            (v, _) if data_eq_type(v, ty) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// `STRINGNULL(Some(x))` -> `STRING(x)` and so on; `None` for null values.
+    /// Non-nullable values are returned as they are.
+    pub fn unwrap_nullable(&self) -> Option<Data> {
+        match self {
+            Data::NULL => None,
+            Data::STRINGNULL(x) => x.clone().map(Data::STRING),
+            Data::NUMBERNULL(x) => x.map(Data::NUMBER),
+            Data::ARRAYNULL(x) => x.clone().map(Data::ARRAY),
+            Data::BOOLEANNULL(x) => x.map(Data::BOOLEAN),
+            Data::JSONNULL(x) => x.clone().map(Data::JSON),
+            Data::HASHMAPNULL(x) => x.clone().map(Data::HASHMAP),
+            Data::HASHSETNULL(x) => x.clone().map(Data::HASHSET),
+            other => Some(other.clone()),
+        }
+    }
+
+    /// `STRING(x)` -> `STRINGNULL(Some(x))` and so on.
+    pub fn into_nullable(self) -> Data {
+        match self {
+            Data::STRING(x) => Data::STRINGNULL(Some(x)),
+            Data::NUMBER(x) => Data::NUMBERNULL(Some(x)),
+            Data::ARRAY(x) => Data::ARRAYNULL(Some(x)),
+            Data::BOOLEAN(x) => Data::BOOLEANNULL(Some(x)),
+            Data::JSON(x) => Data::JSONNULL(Some(x)),
+            Data::HASHMAP(x) => Data::HASHMAPNULL(Some(x)),
+            Data::HASHSET(x) => Data::HASHSETNULL(Some(x)),
+            other => other,
+        }
+    }
+
+    /// The null value of a nullable type.
+    pub fn null_of(ty: &Type) -> Option<Data> {
+        match ty {
+            Type::NULL => Some(Data::NULL),
+            Type::STRINGNULL => Some(Data::STRINGNULL(None)),
+            Type::NUMBERNULL => Some(Data::NUMBERNULL(None)),
+            Type::ARRAYNULL => Some(Data::ARRAYNULL(None)),
+            Type::HASHMAPNULL => Some(Data::HASHMAPNULL(None)),
+            Type::BOOLEANNULL => Some(Data::BOOLEANNULL(None)),
+            Type::JSONNULL => Some(Data::JSONNULL(None)),
+            Type::HASHSETNULL => Some(Data::HASHSETNULL(None)),
+            _ => None,
+        }
+    }
+
+    /// The empty value of a type, used to pre-fill rows: `""`, `0.0`,
+    /// `false`, empty collections, `{}` for JSON and null for nullable types.
+    pub fn zero_of(ty: &Type) -> Data {
+        match ty {
+            Type::STRING => Data::STRING(String::new()),
+            Type::NUMBER => Data::NUMBER(0.0),
+            Type::ARRAY => Data::ARRAY(vec![]),
+            Type::HASHMAP => Data::HASHMAP(HashMap::new()),
+            Type::BOOLEAN => Data::BOOLEAN(false),
+            Type::JSON => Data::JSON("{}".to_string()),
+            Type::HASHSET => Data::HASHSET(vec![]),
+            nullable => Data::null_of(nullable).unwrap_or(Data::NULL),
+        }
+    }
+
+    /// Converts a JSON value: objects become `JSON` text, arrays become `ARRAY`
+    /// (recursively) and `null` becomes `NULL`.
+    pub fn from_json_value(value: &serde_json::Value) -> Result<Data> {
+        use serde_json::Value;
+        Ok(match value {
+            Value::Null => Data::NULL,
+            Value::Bool(b) => Data::BOOLEAN(*b),
+            Value::Number(n) => Data::NUMBER(
+                n.as_f64()
+                    .ok_or_else(|| Error::InvalidValue(format!("number {} does not fit in an f64", n)))?,
+            ),
+            Value::String(s) => Data::STRING(s.clone()),
+            Value::Array(items) => Data::ARRAY(
+                items
+                    .iter()
+                    .map(Data::from_json_value)
+                    .collect::<Result<_>>()?,
+            ),
+            Value::Object(_) => Data::JSON(value.to_string()),
+        })
+    }
+
+    /// Inverse of `from_json_value`. Nullable variants map to their inner value
+    /// or `null`; a `JSON` string that doesn't parse is kept as a JSON string.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        use serde_json::Value;
         match self {
-            Data::JSON(x) => x,
-            _ => panic!("expected JSON but got different variant"),
+            Data::NULL => Value::Null,
+            Data::STRING(s) => Value::String(s.clone()),
+            // whole numbers go back out as JSON integers
+            Data::NUMBER(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => {
+                Value::from(*n as i64)
+            }
+            Data::NUMBER(n) => serde_json::Number::from_f64(*n).map_or(Value::Null, Value::Number),
+            Data::ARRAY(items) | Data::HASHSET(items) => {
+                Value::Array(items.iter().map(Data::to_json_value).collect())
+            }
+            Data::BOOLEAN(b) => Value::Bool(*b),
+            Data::JSON(text) => {
+                serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone()))
+            }
+            Data::HASHMAP(map) => Value::Object(
+                map.iter()
+                    .map(|(k, v)| (k.clone(), v.to_json_value()))
+                    .collect(),
+            ),
+            Data::STRINGNULL(x) => x.clone().map_or(Value::Null, Value::String),
+            Data::NUMBERNULL(x) => x.map_or(Value::Null, |n| Data::NUMBER(n).to_json_value()),
+            Data::ARRAYNULL(x) => x.clone().map_or(Value::Null, |a| Data::ARRAY(a).to_json_value()),
+            Data::BOOLEANNULL(x) => x.map_or(Value::Null, Value::Bool),
+            Data::JSONNULL(x) => x.clone().map_or(Value::Null, |j| Data::JSON(j).to_json_value()),
+            Data::HASHMAPNULL(x) => x.clone().map_or(Value::Null, |m| Data::HASHMAP(m).to_json_value()),
+            Data::HASHSETNULL(x) => x.clone().map_or(Value::Null, |a| Data::HASHSET(a).to_json_value()),
         }
     }
+
+    /// Parses a `JSON` / `JSONNULL(Some)` value.
+    pub fn get_json_value(self) -> Result<serde_json::Value> {
+        let text = match self {
+            Data::JSON(x) | Data::JSONNULL(Some(x)) => x,
+            _ => {
+                return Err(Error::InvalidValue(
+                    "expected JSON but got different variant".to_string(),
+                ))
+            }
+        };
+        Ok(serde_json::from_str(&text)?)
+    }
+    /// Panics unless this is a `STRINGNULL`; see `try_get_stringnull`.
     pub fn get_stringnull(self) -> Option<String> {
+        self.try_get_stringnull().expect("expected STRINGNULL but got different variant")
+    }
+    pub fn try_get_stringnull(self) -> Option<Option<String>> {
         match self {
-            Data::STRINGNULL(x) => x,
-            _ => panic!("expected STRINGNULL but got different variant"),
+            Data::STRINGNULL(x) => Some(x),
+            _ => None,
         }
     }
+    /// Panics unless this is a `NUMBERNULL`; see `try_get_numbernull`.
     pub fn get_numbernull(self) -> Option<f64> {
+        self.try_get_numbernull().expect("expected NUMBERNULL but got different variant")
+    }
+    pub fn try_get_numbernull(self) -> Option<Option<f64>> {
         match self {
-            Data::NUMBERNULL(x) => x,
-            _ => panic!("expected NUMBERNULL but got different variant"),
+            Data::NUMBERNULL(x) => Some(x),
+            _ => None,
         }
     }
+    /// Panics unless this is a `ARRAYNULL`; see `try_get_arraynull`.
     pub fn get_arraynull(self) -> Option<Vec<Data>> {
+        self.try_get_arraynull().expect("expected ARRAYNULL but got different variant")
+    }
+    pub fn try_get_arraynull(self) -> Option<Option<Vec<Data>>> {
         match self {
-            Data::ARRAYNULL(x) => x,
-            _ => panic!("expected ARRAYNULL but got different variant"),
+            Data::ARRAYNULL(x) => Some(x),
+            _ => None,
         }
     }
+    /// Panics unless this is a `BOOLEANNULL`; see `try_get_booleannull`.
     pub fn get_booleannull(self) -> Option<bool> {
+        self.try_get_booleannull().expect("expected BOOLEANNULL but got different variant")
+    }
+    pub fn try_get_booleannull(self) -> Option<Option<bool>> {
         match self {
-            Data::BOOLEANNULL(x) => x,
-            _ => panic!("expected BOOLEANNULL but got different variant"),
+            Data::BOOLEANNULL(x) => Some(x),
+            _ => None,
         }
     }
+    /// Panics unless this is a `JSONNULL`; see `try_get_jsonnull`.
     pub fn get_jsonnull(self) -> Option<String> {
+        self.try_get_jsonnull().expect("expected JSONNULL but got different variant")
+    }
+    pub fn try_get_jsonnull(self) -> Option<Option<String>> {
+        match self {
+            Data::JSONNULL(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Panics unless this is a `HASHMAP`; see `try_get_hashmap`.
+    pub fn get_hashmap(self) -> HashMap<String, Data> {
+        self.try_get_hashmap().expect("expected HASHMAP but got different variant")
+    }
+    pub fn try_get_hashmap(self) -> Option<HashMap<String, Data>> {
+        match self {
+            Data::HASHMAP(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Panics unless this is a `HASHSET`; see `try_get_hashset`.
+    pub fn get_hashset(self) -> Vec<Data> {
+        self.try_get_hashset().expect("expected HASHSET but got different variant")
+    }
+    pub fn try_get_hashset(self) -> Option<Vec<Data>> {
         match self {
-            Data::JSONNULL(x) => x,
-            _ => panic!("expected JSONNULL but got different variant"),
+            Data::HASHSET(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Panics unless this is a `HASHMAPNULL`; see `try_get_hashmapnull`.
+    pub fn get_hashmapnull(self) -> Option<HashMap<String, Data>> {
+        self.try_get_hashmapnull().expect("expected HASHMAPNULL but got different variant")
+    }
+    pub fn try_get_hashmapnull(self) -> Option<Option<HashMap<String, Data>>> {
+        match self {
+            Data::HASHMAPNULL(x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Panics unless this is a `HASHSETNULL`; see `try_get_hashsetnull`.
+    pub fn get_hashsetnull(self) -> Option<Vec<Data>> {
+        self.try_get_hashsetnull().expect("expected HASHSETNULL but got different variant")
+    }
+    pub fn try_get_hashsetnull(self) -> Option<Option<Vec<Data>>> {
+        match self {
+            Data::HASHSETNULL(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Plain text for any value, unlike `get_string`, which panics on anything
+/// but `STRING` and `NUMBER`. Strings and JSON text print as they are,
+/// numbers as plain decimals (`30`, `0.25`), booleans as `true`/`false`, and
+/// arrays, maps and sets as JSON. `NULL` and a null `*NULL` variant print
+/// nothing; any other `*NULL` variant prints its value.
+impl std::fmt::Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.unwrap_nullable() {
+            None => Ok(()),
+            Some(Data::STRING(s) | Data::JSON(s)) => f.write_str(&s),
+            Some(Data::NUMBER(n)) => write!(f, "{}", n),
+            Some(Data::BOOLEAN(b)) => write!(f, "{}", b),
+            Some(other) => write!(f, "{}", other.to_json_value()),
         }
     }
 }
 
+/// The id a foreign-key field refers to: its `STRING` or `NUMBER` value as
+/// text, or `None` when it is missing or null.
+pub(crate) fn reference_value(row: &Row, field: &str) -> Option<String> {
+    match row.get(field)?.0.unwrap_nullable()? {
+        id @ (Data::STRING(_) | Data::NUMBER(_)) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
 pub fn data_eq_type(x: &Data, y: &Type) -> bool {
     let x_type = match x {
         Data::NULL => Type::NULL,
@@ -173,6 +740,10 @@ pub fn data_eq_type(x: &Data, y: &Type) -> bool {
         Data::ARRAYNULL(_) => Type::ARRAYNULL,
         Data::BOOLEANNULL(_) => Type::BOOLEANNULL,
         Data::JSONNULL(_) => Type::JSONNULL,
+        Data::HASHMAP(_) => Type::HASHMAP,
+        Data::HASHSET(_) => Type::HASHSET,
+        Data::HASHMAPNULL(_) => Type::HASHMAPNULL,
+        Data::HASHSETNULL(_) => Type::HASHSETNULL,
     };
 
     &x_type == y
@@ -183,48 +754,92 @@ pub fn data_eq(x: &Data, y: &Data) -> bool {
 }
 
 impl DATABASE {
+    /// Opens the database at `path` with the default configuration, creating
+    /// it and any missing parent directories if needed. Panics if that fails;
+    /// `DatabaseBuilder::build` returns the error instead. See
+    /// `DatabaseBuilder` for the other options.
     pub fn init(path: String) -> Self {
-        let x = fs::exists(path.clone()).unwrap();
-        if x {
-            println!("1");
-        }else {
-            fs::create_dir(path.clone()).unwrap();
-            fs::create_dir(format!("{}/migrations",path.clone())).unwrap();
-            let mut migrations_applied = fs::File::create(format!("{}/migrations/.migrations_applied", path.clone())).unwrap();
-            migrations_applied.write(b"[]").expect("174");
-            println!("2 xr");
-        };
-        return Self { path }
+        DatabaseBuilder::new().path(path).build().expect("Failed to open database")
+    }
+
+    /// Opens the existing database at `path` for reading only. Every method
+    /// that would change it, migrations included, fails with
+    /// `Error::ReadOnly` (those that don't return a `Result` report nothing
+    /// done). Nothing is created, and a write-ahead log or compaction left by
+    /// a crash is not recovered: open it normally once to do that.
+    pub fn open_read_only(path: impl Into<String>) -> Result<Self> {
+        DatabaseBuilder::new().path(path).read_only(true).build()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails with `Error::ReadOnly` if this database was opened read-only.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
     }
 
-    pub fn query(&self, table_name: String) -> QueryBuilder {
+    /// Name of the shard file of a table with schema `schema` holding the row
+    /// whose id hash is `id`.
+    pub(crate) fn shard_file(&self, schema: &TABLE, id: &str) -> String {
+        shard_name(id, schema.shard_digits.unwrap_or(self.shard_digits))
+    }
+
+    /// Path of the shard file of `table_name` that holds (or would hold) the
+    /// row with id `id`. Every lookup of a row by id goes through here.
+    pub(crate) fn shard_path(&self, table_name: &str, id: &str) -> PathBuf {
+        let key = Self::string_to_numerical_uuid(id);
+        let digits = self
+            .get_schema(table_name)
+            .ok()
+            .and_then(|schema| schema.shard_digits)
+            .unwrap_or(self.shard_digits);
+        PathBuf::from(&self.path).join(table_name).join(shard_name(&key, digits))
+    }
+
+    /// The lock of `table_name`; see `DATABASE::table_locks`.
+    pub(crate) fn table_lock(&self, table_name: &str) -> Arc<RwLock<()>> {
+        self.table_locks.lock().unwrap().entry(table_name.to_string()).or_default().clone()
+    }
+
+    /// Serializes a shard's rows the way this database is configured to.
+    pub(crate) fn encode_shard<T: Serialize + ?Sized>(&self, rows: &T) -> serde_json::Result<String> {
+        if self.pretty {
+            serde_json::to_string_pretty(rows)
+        } else {
+            serde_json::to_string(rows)
+        }
+    }
+
+    pub fn query(&self, table_name: String) -> QueryBuilder<'_> {
         QueryBuilder::new(self, &table_name)
     }
 
     pub fn insert(&self, table: &str, row: HashMap<String, (Data, String)>) -> Option<()> {
-        self.add_row(table.to_string(), row, true);
-        Some(())
+        self.add_row(table.to_string(), row, true).ok()
     }
 
     pub fn get_table(
         &self,
         table_name: &str,
-    ) -> Option<HashMap<String, HashMap<String, (Data, String)>>> {
+    ) -> Result<HashMap<String, Row>> {
         let mut path = PathBuf::from(&self.path);
         path.push(table_name);
         let mut table = HashMap::new();
 
-        for entry in fs::read_dir(path).ok()? {
-            let entry = entry.ok()?;
-            let file_str = fs::read_to_string(entry.path()).ok()?;
-            let deser: HashMap<String, HashMap<String, (Data, String)>> =
-                serde_json::from_str(&file_str).ok()?;
-            for (id, row) in deser {
-                table.insert(id, row);
-            }
+        let lock = self.table_lock(table_name);
+        let _guard = lock.read().unwrap();
+
+        for entry in shard_files(&path)? {
+            table.extend(self.load_shard::<Row>(&entry)?);
         }
 
-        Some(table)
+        bump(&self.counters.rows_read, table.len());
+        Ok(table)
     }
 
     pub fn create_table(
@@ -233,20 +848,406 @@ impl DATABASE {
         id_field: String,
         name: String,
     ) -> Result<()> {
-        // Check if id column exists
-        if !fields.contains_key(&id_field) {
-            eyre::bail!("Id column '{}' was not provided in fields", id_field);
-        }
+        self.check_writable()?;
+        Self::check_id_column(&fields, &id_field)?;
 
         let table = TABLE {
             name: name.clone(),
             id_column: id_field.clone(),
             field_names: fields.clone(),
+            timestamps: false,
+            required: HashSet::new(),
+            unique: BTreeMap::new(),
+            foreign_keys: BTreeMap::new(),
+            indexes: BTreeMap::new(),
+            defaults: BTreeMap::new(),
+            shard_digits: None,
         };
 
+        self.write_new_table(&table)
+    }
+
+    /// Same as `create_table`, but adds `created_at` and `updated_at` NUMBER
+    /// columns that are filled in automatically on insert and update.
+    pub fn create_table_with_timestamps(
+        &self,
+        mut fields: HashMap<String, (Type, String)>,
+        id_field: String,
+        name: String,
+    ) -> Result<()> {
+        self.check_writable()?;
+        Self::check_id_column(&fields, &id_field)?;
+
+        fields.insert(CREATED_AT.to_string(), (Type::NUMBER, String::new()));
+        fields.insert(UPDATED_AT.to_string(), (Type::NUMBER, String::new()));
+
+        let table = TABLE {
+            name,
+            id_column: id_field,
+            field_names: fields,
+            timestamps: true,
+            required: HashSet::new(),
+            unique: BTreeMap::new(),
+            foreign_keys: BTreeMap::new(),
+            indexes: BTreeMap::new(),
+            defaults: BTreeMap::new(),
+            shard_digits: None,
+        };
+
+        self.write_new_table(&table)
+    }
+
+    /// Renames a table's directory and schema file and updates the schema's name.
+    /// Foreign keys into the table, its own included, follow it to the new name.
+    pub fn rename_table(&self, old: &str, new: &str) -> Result<()> {
+        self.check_writable()?;
+        let root = PathBuf::from(&self.path);
+        let old_schema = root.join(format!("{}-type.txt", old));
+        let new_schema = root.join(format!("{}-type.txt", new));
+
+        if !old_schema.exists() {
+            return Err(Error::NotFound(format!("table '{}'", old)));
+        }
+        if root.join(new).exists() || new_schema.exists() {
+            return Err(Error::TableExists(new.to_string()));
+        }
+        // index files are named after the table; the renamed one rebuilds its
+        // own, and a table later created under the old name mustn't find them
+        self.drop_index_files(old);
+
+        let mut table: TABLE = serde_json::from_str(&fs::read_to_string(&old_schema)?)?;
+        table.name = new.to_string();
+        for foreign_key in table.foreign_keys.values_mut().filter(|key| key.table == old) {
+            foreign_key.table = new.to_string();
+        }
+        fs::write(&new_schema, serde_json::to_string(&table)?)?;
+        fs::rename(root.join(old), root.join(new))?;
+        fs::remove_file(&old_schema)?;
+        let old_seq = root.join(format!("{}-seq.txt", old));
+        if old_seq.exists() {
+            fs::rename(old_seq, root.join(format!("{}-seq.txt", new)))?;
+        }
+        self.invalidate_schema(old);
+        self.invalidate_schema(new);
+
+        for name in self.table_names()? {
+            let mut child = self.get_schema(&name)?;
+            let mut changed = false;
+            for foreign_key in child.foreign_keys.values_mut().filter(|key| key.table == old) {
+                foreign_key.table = new.to_string();
+                changed = true;
+            }
+            if changed {
+                fs::write(root.join(format!("{}-type.txt", name)), serde_json::to_string(&child)?)?;
+                self.invalidate_schema(&name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies table `src` to a new table `dest`: every shard file, the
+    /// schema (renamed) and the auto-increment sequence. The two are
+    /// independent afterwards. Fails with `Error::TableExists` if `dest`
+    /// exists. The schema is written last, so an interrupted copy leaves no
+    /// table `dest`, only a directory to delete.
+    pub fn copy_table(&self, src: &str, dest: &str) -> Result<()> {
+        self.check_writable()?;
+        let root = PathBuf::from(&self.path);
+        let dest_schema = root.join(format!("{}-type.txt", dest));
+        let mut table = self.get_schema(src)?;
+        if root.join(dest).exists() || dest_schema.exists() {
+            return Err(Error::TableExists(dest.to_string()));
+        }
+
+        let lock = self.table_lock(src);
+        let _guard = lock.read().unwrap();
+        fs::create_dir(root.join(dest))?;
+        for shard in shard_files(&root.join(src))? {
+            fs::copy(&shard, root.join(dest).join(shard.file_name().unwrap()))?;
+        }
+        let src_seq = root.join(format!("{}-seq.txt", src));
+        if src_seq.exists() {
+            fs::copy(src_seq, root.join(format!("{}-seq.txt", dest)))?;
+        }
+
+        table.name = dest.to_string();
+        fs::write(&dest_schema, serde_json::to_string(&table)?)?;
+        self.invalidate_schema(dest);
+        Ok(())
+    }
+
+    /// Marks `field` as required (NOT NULL) or not. A required field has to be
+    /// present with a non-null value on insert, even if its type is nullable.
+    pub fn set_required(&self, table_name: &str, field: &str, required: bool) -> Result<()> {
+        self.check_writable()?;
+        let mut path = PathBuf::from(&self.path);
+        path.push(format!("{}-type.txt", table_name));
+        let mut table: TABLE = serde_json::from_str(&fs::read_to_string(&path)?)?;
+
+        if !table.field_names.contains_key(field) {
+            return Err(Error::NotFound(format!("field '{}' in table '{}'", field, table_name)));
+        }
+
+        if required {
+            table.required.insert(field.to_string());
+        } else {
+            table.required.remove(field);
+        }
+
+        fs::write(&path, serde_json::to_string(&table)?)?;
+        self.invalidate_schema(table_name);
+        Ok(())
+    }
+
+    /// Sets the value inserts fill in when a row leaves `field` out, or with
+    /// `None` removes it. The default has to be a valid value of the field.
+    /// Only inserts use it; updates and existing rows are unaffected.
+    pub fn set_default(&self, table_name: &str, field: &str, default: Option<Data>) -> Result<()> {
+        self.check_writable()?;
+        let mut table = self.get_schema(table_name)?;
+        if !table.field_names.contains_key(field) {
+            return Err(Error::NotFound(format!("field '{}' in table '{}'", field, table_name)));
+        }
+
+        match default {
+            Some(default) => {
+                let check = HashMap::from([(field.to_string(), (default.clone(), String::new()))]);
+                Self::validate_fields(&check, &table)?;
+                table.defaults.insert(field.to_string(), default);
+            }
+            None => {
+                table.defaults.remove(field);
+            }
+        }
+
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        fs::write(&path, serde_json::to_string(&table)?)?;
+        self.invalidate_schema(table_name);
+        Ok(())
+    }
+
+    /// Adds a composite unique constraint `name` over `columns`: inserts and
+    /// updates that would give two rows equal values in all of them are
+    /// rejected with `Error::UniqueConstraint`. Rows with any of the columns
+    /// missing or null never conflict. Fails if the rows already stored break
+    /// the constraint.
+    pub fn add_unique(&self, table_name: &str, name: &str, columns: &[&str]) -> Result<()> {
+        self.check_writable()?;
+        let mut table = self.get_schema(table_name)?;
+
+        if columns.is_empty() {
+            return Err(Error::InvalidArgument(format!("unique constraint '{}' has no columns", name)));
+        }
+        if let Some(field) = columns.iter().find(|c| !table.field_names.contains_key(**c)) {
+            return Err(Error::NotFound(format!("field '{}' in table '{}'", field, table_name)));
+        }
+
+        let columns: Vec<String> = columns.iter().map(|c| c.to_string()).collect();
+        self.unique_index(table_name, name, &columns, &HashSet::new())?;
+        table.unique.insert(name.to_string(), columns);
+
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        fs::write(&path, serde_json::to_string(&table)?)?;
+        self.invalidate_schema(table_name);
+        Ok(())
+    }
+
+    /// Makes `field` a reference to rows of `references`: inserts whose `field`
+    /// names an id missing from that table are rejected, and deleting a
+    /// referenced row through `delete_by_id` follows `on_delete`. A null or
+    /// missing `field` references nothing. Fails if a stored row already holds
+    /// a dangling reference.
+    pub fn add_foreign_key(&self, table_name: &str, field: &str, references: &str, on_delete: OnDelete) -> Result<()> {
+        self.check_writable()?;
+        let mut table = self.get_schema(table_name)?;
+        if !table.field_names.contains_key(field) {
+            return Err(Error::NotFound(format!("field '{}' in table '{}'", field, table_name)));
+        }
+        self.get_schema(references)?;
+
+        let foreign_key = ForeignKey { table: references.to_string(), on_delete };
+        for (_, row) in self.iter_rows(table_name.to_string()) {
+            self.check_reference(&row, field, &foreign_key)?;
+        }
+        table.foreign_keys.insert(field.to_string(), foreign_key);
+
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        fs::write(&path, serde_json::to_string(&table)?)?;
+        self.invalidate_schema(table_name);
+        Ok(())
+    }
+
+    /// How the schema of `table_name` in `other` differs from the one here:
+    /// columns `other` adds, drops or gives another type, and a changed id
+    /// column, sorted by field name. Empty when the two match. Regex patterns
+    /// and constraints aren't compared.
+    pub fn schema_diff(&self, other: &DATABASE, table_name: &str) -> Result<Vec<SchemaChange>> {
+        let before = self.get_schema(table_name)?;
+        let after = other.get_schema(table_name)?;
+
+        let mut changes = vec![];
+        if before.id_column != after.id_column {
+            changes.push(SchemaChange::IdColumnChanged { from: before.id_column.clone(), to: after.id_column.clone() });
+        }
+
+        let mut fields: Vec<&String> = before.field_names.keys().chain(after.field_names.keys()).collect();
+        fields.sort();
+        fields.dedup();
+        for field in fields {
+            let change = match (before.field_names.get(field), after.field_names.get(field)) {
+                (None, Some((datatype, _))) => SchemaChange::AddedColumn { field: field.clone(), datatype: datatype.clone() },
+                (Some((datatype, _)), None) => SchemaChange::RemovedColumn { field: field.clone(), datatype: datatype.clone() },
+                (Some((from, _)), Some((to, _))) if from != to => {
+                    SchemaChange::RetypedColumn { field: field.clone(), from: from.clone(), to: to.clone() }
+                }
+                _ => continue,
+            };
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    /// A description of `table_name`'s columns for documentation or code
+    /// generation, in a fixed shape:
+    ///
+    /// ```json
+    /// {
+    ///   "table": "users",
+    ///   "id_column": "id",
+    ///   "timestamps": false,
+    ///   "columns": [
+    ///     {"name": "id", "type": "STRING", "nullable": false, "required": true, "pattern": "", "is_id": true}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Columns are sorted by name. `type` is the `Type` variant's name,
+    /// `nullable` whether it holds nulls, `required` whether a row must
+    /// include it, and `pattern` the regex values must match (empty for none).
+    pub fn schema_json(&self, table_name: &str) -> Result<serde_json::Value> {
+        let table = self.get_schema(table_name)?;
+
+        let mut names: Vec<&String> = table.field_names.keys().collect();
+        names.sort();
+        let columns: Vec<serde_json::Value> = names
+            .into_iter()
+            .map(|name| {
+                let (datatype, pattern) = &table.field_names[name];
+                serde_json::json!({
+                    "name": name,
+                    "type": format!("{:?}", datatype),
+                    "nullable": datatype.is_nullable(),
+                    "required": !table.is_optional(name),
+                    "pattern": pattern,
+                    "is_id": *name == table.id_column,
+                })
+            })
+            .collect();
+
+        Ok(serde_json::json!({
+            "table": table.name,
+            "id_column": table.id_column,
+            "timestamps": table.timestamps,
+            "columns": columns,
+        }))
+    }
+
+    /// Fails with `Error::ForeignKeyViolation` if `row` has `field` set to an
+    /// id that `foreign_key.table` doesn't hold.
+    pub(crate) fn check_reference(&self, row: &Row, field: &str, foreign_key: &ForeignKey) -> Result<()> {
+        let Some(value) = reference_value(row, field) else {
+            return Ok(());
+        };
+        if self.get_by_id(foreign_key.table.clone(), value.clone())?.is_none() {
+            return Err(Error::ForeignKeyViolation {
+                field: field.to_string(),
+                table: foreign_key.table.clone(),
+                value,
+            });
+        }
+        Ok(())
+    }
+
+    /// Names of every table in the database, sorted.
+    pub fn table_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = fs::read_dir(&self.path)?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.strip_suffix("-type.txt").map(str::to_string)
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Reads the schema of `table_name`, from the cache after the first call.
+    pub fn get_schema(&self, table_name: &str) -> Result<TABLE> {
+        if let Some(table) = self.schema_cache.read().unwrap().get(table_name) {
+            return Ok(table.clone());
+        }
+
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        if !path.exists() {
+            return Err(Error::NotFound(format!("table '{}'", table_name)));
+        }
+        let table: TABLE = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        self.schema_cache
+            .write()
+            .unwrap()
+            .insert(table_name.to_string(), table.clone());
+        Ok(table)
+    }
+
+    /// Drops a cached schema; call after writing `{table}-type.txt`.
+    pub(crate) fn invalidate_schema(&self, table_name: &str) {
+        self.schema_cache.write().unwrap().remove(table_name);
+        self.touch_table(table_name);
+    }
+
+    /// Drops every cached schema.
+    pub(crate) fn clear_schema_cache(&self) {
+        self.schema_cache.write().unwrap().clear();
+        self.clear_query_cache();
+    }
+
+    /// A row with every schema field set to its type's empty value (see
+    /// `Data::zero_of`), to fill in before inserting. The id is left empty,
+    /// and `created_at` / `updated_at` are left out for the insert to stamp.
+    pub fn new_row(&self, table_name: &str) -> Result<Row> {
+        let table = self.get_schema(table_name)?;
+
+        Ok(table
+            .field_names
+            .iter()
+            .filter(|(field, _)| !(table.timestamps && (*field == CREATED_AT || *field == UPDATED_AT)))
+            .map(|(field, (ty, _))| (field.clone(), (Data::zero_of(ty), String::new())))
+            .collect())
+    }
+
+    /// The id column must be one of `fields` and a `STRING` or `NUMBER`: row
+    /// ids are the text of that value, which no other type has.
+    fn check_id_column(fields: &HashMap<String, (Type, String)>, id_field: &str) -> Result<()> {
+        match fields.get(id_field) {
+            None => Err(Error::InvalidArgument(format!(
+                "Id column '{}' was not provided in fields",
+                id_field
+            ))),
+            Some((Type::STRING | Type::NUMBER, _)) => Ok(()),
+            Some((other, _)) => Err(Error::InvalidArgument(format!(
+                "Id column '{}' must be STRING or NUMBER, not {:?}",
+                id_field, other
+            ))),
+        }
+    }
+
+    fn write_new_table(&self, table: &TABLE) -> Result<()> {
+        let name = &table.name;
+
         // Create folder in database path for table if it doesn't exist
-        let mut dir = PathBuf::from_str(&self.path)?;
-        dir.push(&name);
+        let mut dir = PathBuf::from(&self.path);
+        dir.push(name);
 
         if !dir.exists() {
             fs::create_dir(&dir)?;
@@ -266,9 +1267,10 @@ impl DATABASE {
             dir.push(format!("{}-type.txt", name));
 
             // Write serialized table schema to file
-            fs::write(&dir, serde_json::to_string(&table)?)?;
+            fs::write(&dir, serde_json::to_string(table)?)?;
+            self.invalidate_schema(name);
         } else {
-            eyre::bail!("Table '{}' already exists", name);
+            return Err(Error::TableExists(name.to_string()));
         }
 
         Ok(())
@@ -338,6 +1340,265 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_create_table_rejects_boolean_id() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("active".to_string(), (Type::BOOLEAN, String::new()));
+        let err = db.create_table(fields.clone(), "active".to_string(), "flags".to_string()).unwrap_err();
+        assert!(matches!(&err, Error::InvalidArgument(msg) if msg.contains("STRING or NUMBER")), "{}", err);
+        assert!(db.create_table_with_timestamps(fields, "active".to_string(), "flags".to_string()).is_err());
+        assert!(!temp_dir.path().join("flags-type.txt").exists());
+
+        let mut fields = HashMap::new();
+        fields.insert("n".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "n".to_string(), "numbered".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_json_integer_number_equals_float() {
+        let from_json: Data = serde_json::from_str(r#"{"NUMBER":30}"#).unwrap();
+        assert_eq!(from_json, Data::NUMBER(30.0));
+
+        let round_trip: Data =
+            serde_json::from_str(&serde_json::to_string(&Data::NUMBER(30.0)).unwrap()).unwrap();
+        assert_eq!(round_trip, from_json);
+    }
+
+    #[test]
+    fn test_data_from_json_value() {
+        let value = serde_json::json!({
+            "tags": ["a", 1, [true, null]],
+            "profile": {"city": "NYC", "zip": 10001},
+        });
+
+        let tags = Data::from_json_value(&value["tags"]).unwrap();
+        assert_eq!(
+            tags,
+            Data::ARRAY(vec![
+                Data::STRING("a".to_string()),
+                Data::NUMBER(1.0),
+                Data::ARRAY(vec![Data::BOOLEAN(true), Data::NULL]),
+            ])
+        );
+
+        let profile = Data::from_json_value(&value["profile"]).unwrap();
+        assert!(matches!(profile, Data::JSON(_)));
+        assert_eq!(profile.get_json_value().unwrap(), value["profile"]);
+    }
+
+    #[test]
+    fn test_data_to_json_value_round_trip() {
+        let value = serde_json::json!([1.5, "x", false, null, [2, {"k": [3]}]]);
+        let data = Data::from_json_value(&value).unwrap();
+        assert_eq!(data.to_json_value(), value);
+
+        assert_eq!(Data::STRINGNULL(None).to_json_value(), serde_json::Value::Null);
+        assert_eq!(Data::NUMBERNULL(Some(2.0)).to_json_value(), serde_json::json!(2));
+    }
+
+    #[test]
+    fn test_data_display() {
+        let s = |x: &str| x.to_string();
+        assert_eq!(Data::NULL.to_string(), "");
+        assert_eq!(Data::STRING(s("a, b")).to_string(), "a, b");
+        assert_eq!(Data::NUMBER(30.0).to_string(), "30");
+        assert_eq!(Data::NUMBER(-0.25).to_string(), "-0.25");
+        assert_eq!(Data::NUMBER(1e21).to_string(), "1000000000000000000000");
+        assert_eq!(Data::BOOLEAN(false).to_string(), "false");
+        assert_eq!(Data::ARRAY(vec![Data::NUMBER(1.0), Data::STRING(s("x"))]).to_string(), r#"[1,"x"]"#);
+        assert_eq!(Data::JSON(s(r#"{"k": 1}"#)).to_string(), r#"{"k": 1}"#);
+        assert_eq!(Data::HASHMAP(HashMap::from([(s("k"), Data::BOOLEAN(true))])).to_string(), r#"{"k":true}"#);
+        assert_eq!(Data::HASHSET(vec![Data::NUMBER(2.0)]).to_string(), "[2]");
+
+        assert_eq!(Data::STRINGNULL(Some(s("x"))).to_string(), "x");
+        assert_eq!(Data::STRINGNULL(None).to_string(), "");
+        assert_eq!(Data::NUMBERNULL(Some(1.5)).to_string(), "1.5");
+        assert_eq!(Data::NUMBERNULL(None).to_string(), "");
+        assert_eq!(Data::ARRAYNULL(Some(vec![])).to_string(), "[]");
+        assert_eq!(Data::ARRAYNULL(None).to_string(), "");
+        assert_eq!(Data::BOOLEANNULL(Some(true)).to_string(), "true");
+        assert_eq!(Data::BOOLEANNULL(None).to_string(), "");
+        assert_eq!(Data::JSONNULL(Some(s("null"))).to_string(), "null");
+        assert_eq!(Data::JSONNULL(None).to_string(), "");
+        assert_eq!(Data::HASHMAPNULL(Some(HashMap::new())).to_string(), "{}");
+        assert_eq!(Data::HASHMAPNULL(None).to_string(), "");
+        assert_eq!(Data::HASHSETNULL(Some(vec![Data::NULL])).to_string(), "[null]");
+        assert_eq!(Data::HASHSETNULL(None).to_string(), "");
+    }
+
+    #[test]
+    fn test_try_get_on_wrong_variant() {
+        let b = Data::BOOLEAN(true);
+        assert_eq!(b.clone().try_get_string(), None);
+        assert_eq!(b.clone().try_get_number(), None);
+        assert_eq!(b.clone().try_get_array(), None);
+        assert_eq!(b.clone().try_get_json(), None);
+        assert_eq!(b.clone().try_get_hashmap(), None);
+        assert_eq!(b.clone().try_get_hashset(), None);
+        assert_eq!(b.clone().try_get_stringnull(), None);
+        assert_eq!(b.clone().try_get_numbernull(), None);
+        assert_eq!(b.clone().try_get_arraynull(), None);
+        assert_eq!(b.clone().try_get_booleannull(), None);
+        assert_eq!(b.clone().try_get_jsonnull(), None);
+        assert_eq!(b.clone().try_get_hashmapnull(), None);
+        assert_eq!(b.clone().try_get_hashsetnull(), None);
+        assert_eq!(Data::NULL.try_get_boolean(), None);
+        assert!(b.clone().get_number_as_i64().is_err());
+
+        assert_eq!(b.try_get_boolean(), Some(true));
+        assert_eq!(Data::NUMBER(3.0).try_get_string(), Some("3".to_string()));
+        assert_eq!(Data::STRINGNULL(None).try_get_stringnull(), Some(None));
+    }
+
+    #[test]
+    fn test_data_coerce() {
+        let s = |x: &str| Data::STRING(x.to_string());
+        assert_eq!(s(" 42 ").coerce(&Type::NUMBER), Some(Data::NUMBER(42.0)));
+        assert_eq!(s("abc").coerce(&Type::NUMBER), None);
+        assert_eq!(s("TRUE").coerce(&Type::BOOLEAN), Some(Data::BOOLEAN(true)));
+        assert_eq!(Data::NUMBER(42.0).coerce(&Type::STRING), Some(s("42")));
+        assert_eq!(Data::BOOLEAN(true).coerce(&Type::NUMBER), Some(Data::NUMBER(1.0)));
+        assert_eq!(s("7").coerce(&Type::NUMBERNULL), Some(Data::NUMBERNULL(Some(7.0))));
+        assert_eq!(Data::STRINGNULL(None).coerce(&Type::NUMBERNULL), Some(Data::NUMBERNULL(None)));
+        assert_eq!(Data::STRINGNULL(None).coerce(&Type::NUMBER), None);
+        assert_eq!(Data::STRINGNULL(Some("3".to_string())).coerce(&Type::NUMBER), Some(Data::NUMBER(3.0)));
+    }
+
+    #[test]
+    fn test_get_number_as_i64() {
+        assert_eq!(Data::NUMBER(30.0).get_number_as_i64().unwrap(), 30);
+        assert_eq!(Data::NUMBER(-2.0).get_number_as_i64().unwrap(), -2);
+        assert!(Data::NUMBER(30.5).get_number_as_i64().is_err());
+        assert!(Data::NUMBER(f64::NAN).get_number_as_i64().is_err());
+    }
+
+    #[test]
+    fn test_rename_table() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields.clone(), "id".to_string(), "users".to_string()).unwrap();
+        db.create_table(fields, "id".to_string(), "admins".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        assert!(db.rename_table("users", "admins").is_err());
+        db.rename_table("users", "people").unwrap();
+
+        assert!(db.get_by_id("people".to_string(), "u1".to_string()).unwrap().is_some());
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_none());
+        assert!(!temp_dir.path().join("users").exists());
+        assert!(!temp_dir.path().join("users-type.txt").exists());
+
+        let schema: TABLE = serde_json::from_str(
+            &fs::read_to_string(temp_dir.path().join("people-type.txt")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(schema.name, "people");
+    }
+
+    #[test]
+    fn test_rename_table_leaves_no_index_files_behind() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        let user = |id: &str, name: &str| -> Row {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            row
+        };
+        db.create_table(fields.clone(), "id".to_string(), "users".to_string()).unwrap();
+        db.add_row("users".to_string(), user("u1", "Alice"), false).unwrap();
+        db.add_index("users", "by_name", "name", false).unwrap();
+
+        db.rename_table("users", "people").unwrap();
+        assert!(!temp_dir.path().join("users-index-by_name.txt").exists());
+        assert_eq!(db.find_by_index("people", "by_name", &Data::STRING("Alice".to_string())).unwrap().len(), 1);
+
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        db.add_index("users", "by_name", "name", false).unwrap();
+        assert!(db.find_by_index("users", "by_name", &Data::STRING("Alice".to_string())).unwrap().is_empty());
+        db.add_row("users".to_string(), user("u2", "Bob"), false).unwrap();
+        assert_eq!(db.find_by_index("users", "by_name", &Data::STRING("Bob".to_string())).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rename_table_carries_foreign_keys() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields.clone(), "id".to_string(), "users".to_string()).unwrap();
+        fields.insert("user_id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+        db.add_foreign_key("orders", "user_id", "users", OnDelete::Restrict).unwrap();
+        let row = |pairs: &[(&str, &str)]| -> Row {
+            pairs
+                .iter()
+                .map(|(field, value)| (field.to_string(), (Data::STRING(value.to_string()), String::new())))
+                .collect()
+        };
+        db.add_row("users".to_string(), row(&[("id", "u1")]), false).unwrap();
+
+        db.rename_table("users", "people").unwrap();
+        assert_eq!(db.get_schema("orders").unwrap().foreign_keys["user_id"].table, "people");
+        db.add_row("orders".to_string(), row(&[("id", "o1"), ("user_id", "u1")]), false).unwrap();
+        let err = db.add_row("orders".to_string(), row(&[("id", "o2"), ("user_id", "u9")]), false).unwrap_err();
+        assert!(matches!(err, Error::ForeignKeyViolation { .. }));
+        assert!(matches!(db.delete_by_id("people", "u1"), Err(Error::StillReferenced { .. })));
+    }
+
+    #[test]
+    fn test_hashmap_and_hashset_columns() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("attrs".to_string(), (Type::HASHMAP, String::new()));
+        fields.insert("tags".to_string(), (Type::HASHSET, String::new()));
+        fields.insert("extra".to_string(), (Type::HASHMAPNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "items".to_string()).unwrap();
+
+        let mut attrs = HashMap::new();
+        attrs.insert("color".to_string(), Data::STRING("red".to_string()));
+        attrs.insert("size".to_string(), Data::NUMBER(3.0));
+        let tags = vec![Data::STRING("a".to_string()), Data::STRING("b".to_string())];
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("i1".to_string()), String::new()));
+        row.insert("attrs".to_string(), (Data::HASHMAP(attrs.clone()), String::new()));
+        row.insert("tags".to_string(), (Data::HASHSET(tags), String::new()));
+        row.insert("extra".to_string(), (Data::HASHMAPNULL(None), String::new()));
+        db.add_row("items".to_string(), row, false).unwrap();
+
+        let stored = db.get_by_id("items".to_string(), "i1".to_string()).unwrap().unwrap();
+        assert_eq!(stored["attrs"].0, Data::HASHMAP(attrs));
+        // set equality ignores order
+        assert_eq!(
+            stored["tags"].0,
+            Data::HASHSET(vec![Data::STRING("b".to_string()), Data::STRING("a".to_string())])
+        );
+        assert_ne!(stored["tags"].0, Data::HASHSET(vec![Data::STRING("a".to_string())]));
+        assert!(stored["extra"].0.is_null());
+    }
+
+    #[test]
+    fn test_table_type_removed() {
+        assert!(Type::from_string("TABLE".to_string()).is_err());
+        assert_eq!(Type::from_string("HASHSET".to_string()), Ok(Type::HASHSET));
+    }
+
     #[test]
     fn test_string_to_numerical_uuid() {
         let uuid1 = string_to_numerical_uuid("example_string");
@@ -347,4 +1608,311 @@ mod tests {
         let uuid3 = string_to_numerical_uuid("different_string");
         assert_ne!(uuid1, uuid3);
     }
+
+    #[test]
+    fn test_new_row_is_valid_once_id_set() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        fields.insert("active".to_string(), (Type::BOOLEAN, String::new()));
+        fields.insert("nickname".to_string(), (Type::STRINGNULL, String::new()));
+        fields.insert("meta".to_string(), (Type::JSON, String::new()));
+        fields.insert("tags".to_string(), (Type::HASHSET, String::new()));
+        db.create_table_with_timestamps(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = db.new_row("users").unwrap();
+        assert_eq!(row["age"].0, Data::NUMBER(0.0));
+        assert_eq!(row["active"].0, Data::BOOLEAN(false));
+        assert_eq!(row["nickname"].0, Data::STRINGNULL(None));
+        assert!(!row.contains_key(CREATED_AT));
+
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        let schema = db.get_schema("users").unwrap();
+        let mut stamped = row.clone();
+        schema.stamp_timestamps(&mut stamped);
+        assert!(DATABASE::check_type_regex(&stamped, &schema).unwrap());
+
+        db.add_row("users".to_string(), row, false).unwrap();
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+        assert!(db.new_row("missing").is_err());
+    }
+
+    #[test]
+    fn test_schema_is_cached_between_inserts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("email".to_string(), (Type::STRINGNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let row = |id: &str| {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row
+        };
+        db.add_row("users".to_string(), row("u0"), false).unwrap();
+
+        // with the schema file gone, only the cached copy can validate these
+        let schema_file = temp_dir.path().join("users-type.txt");
+        let saved = fs::read_to_string(&schema_file).unwrap();
+        fs::remove_file(&schema_file).unwrap();
+        for i in 1..100 {
+            db.add_row("users".to_string(), row(&format!("u{}", i)), false).unwrap();
+        }
+        assert_eq!(db.count_rows("users".to_string()), 100);
+        fs::write(&schema_file, saved).unwrap();
+
+        // schema changes made through the handle are picked up
+        db.set_required("users", "email", true).unwrap();
+        assert!(db.get_schema("users").unwrap().required.contains("email"));
+        assert!(db.add_row("users".to_string(), row("u100"), false).is_err());
+    }
+
+    #[test]
+    fn test_init_prints_nothing() {
+        // Test output is captured in-process, so run `init` in a child copy of
+        // this test binary and look at what it wrote between two markers.
+        if let Ok(path) = std::env::var("UDB_INIT_SILENT_CHILD") {
+            println!("<<init>>");
+            DATABASE::init(path.clone());
+            DATABASE::init(path);
+            println!("<</init>>");
+            return;
+        }
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "crud::make::tests::test_init_prints_nothing", "--nocapture"])
+            .env("UDB_INIT_SILENT_CHILD", temp_dir.path().join("db"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let start = stdout.find("<<init>>").unwrap() + "<<init>>".len();
+        let end = stdout.find("<</init>>").unwrap();
+        assert_eq!(stdout[start..end].trim(), "");
+    }
+
+    #[test]
+    fn test_insert_and_get_by_id_share_shard_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .shard_digits(4)
+            .build()
+            .unwrap();
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        let path = db.shard_path("users", "u1");
+        let key = DATABASE::string_to_numerical_uuid("u1");
+        assert!(db.load_shard::<Row>(&path).unwrap().contains_key(&key));
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+
+        // get_by_id reads nothing but that file
+        fs::write(&path, "{}").unwrap();
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_none());
+        assert!(!db.exists_by_id("users", "u1"));
+    }
+
+    #[test]
+    fn test_array_len_and_get() {
+        let array = Data::ARRAY(vec![Data::STRING("a".to_string()), Data::NUMBER(2.0)]);
+        assert_eq!(array.array_len(), Some(2));
+        assert_eq!(array.array_get(1), Some(&Data::NUMBER(2.0)));
+        assert_eq!(array.array_get(2), None);
+
+        let nullable = Data::ARRAYNULL(Some(vec![Data::BOOLEAN(true)]));
+        assert_eq!(nullable.array_len(), Some(1));
+        assert_eq!(nullable.array_get(0), Some(&Data::BOOLEAN(true)));
+        assert_eq!(Data::ARRAY(vec![]).array_len(), Some(0));
+
+        for other in [Data::ARRAYNULL(None), Data::STRING("abc".to_string()), Data::NULL, Data::NUMBER(3.0)] {
+            assert_eq!(other.array_len(), None);
+            assert_eq!(other.array_get(0), None);
+        }
+    }
+
+    #[test]
+    fn test_schema_diff_after_add_column() {
+        let setup = |dir: &std::path::Path| {
+            let db = DATABASE::init(dir.to_str().unwrap().to_string());
+            let mut fields = HashMap::new();
+            fields.insert("id".to_string(), (Type::STRING, String::new()));
+            fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+            db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+            db
+        };
+        let (staging_dir, prod_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (staging, prod) = (setup(staging_dir.path()), setup(prod_dir.path()));
+        assert!(prod.schema_diff(&staging, "users").unwrap().is_empty());
+
+        staging
+            .generate_add_column_migration("add_email", "users", "email", "STRINGNULL", None)
+            .unwrap();
+        staging.apply_migrations().unwrap();
+        assert_eq!(
+            prod.schema_diff(&staging, "users").unwrap(),
+            vec![SchemaChange::AddedColumn { field: "email".to_string(), datatype: Type::STRINGNULL }]
+        );
+        assert_eq!(
+            staging.schema_diff(&prod, "users").unwrap(),
+            vec![SchemaChange::RemovedColumn { field: "email".to_string(), datatype: Type::STRINGNULL }]
+        );
+
+        staging.generate_change_column_type_migration("users", "age", "STRING", None).unwrap();
+        staging.apply_migrations().unwrap();
+        assert!(prod
+            .schema_diff(&staging, "users")
+            .unwrap()
+            .contains(&SchemaChange::RetypedColumn { field: "age".to_string(), from: Type::NUMBER, to: Type::STRING }));
+
+        assert!(prod.schema_diff(&staging, "missing").is_err());
+    }
+
+    #[test]
+    fn test_schema_json_lists_columns() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBERNULL, String::new()));
+        fields.insert("email".to_string(), (Type::STRING, "^.+@.+$".to_string()));
+        fields.insert("tags".to_string(), (Type::ARRAY, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let schema = db.schema_json("users").unwrap();
+        assert_eq!(schema["table"], "users");
+        assert_eq!(schema["id_column"], "id");
+        assert_eq!(schema["timestamps"], false);
+
+        let columns = schema["columns"].as_array().unwrap();
+        let names: Vec<&str> = columns.iter().map(|c| c["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["age", "email", "id", "tags"]);
+        let types: Vec<&str> = columns.iter().map(|c| c["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["NUMBERNULL", "STRING", "STRING", "ARRAY"]);
+
+        assert_eq!(
+            columns[0],
+            serde_json::json!({"name": "age", "type": "NUMBERNULL", "nullable": true, "required": false, "pattern": "", "is_id": false})
+        );
+        assert_eq!(columns[1]["pattern"], "^.+@.+$");
+        assert_eq!(columns[2]["is_id"], true);
+        assert_eq!(columns[2]["required"], true);
+
+        assert!(db.schema_json("missing").is_err());
+    }
+
+    #[test]
+    fn test_copy_table() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        for i in 0..50 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        db.copy_table("users", "users_backup").unwrap();
+        assert_eq!(db.get_all("users_backup".to_string()).unwrap(), db.get_all("users".to_string()).unwrap());
+        assert_eq!(db.get_schema("users_backup").unwrap().name, "users_backup");
+
+        // the schemas and rows are independent
+        db.set_required("users", "age", true).unwrap();
+        db.add_unique("users_backup", "unique_age", &["age"]).unwrap();
+        assert!(db.get_schema("users").unwrap().unique.is_empty());
+        assert!(db.get_schema("users_backup").unwrap().required.is_empty());
+        db.delete_row_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert!(db.get_by_id("users_backup".to_string(), "u1".to_string()).unwrap().is_some());
+
+        assert!(matches!(db.copy_table("users", "users_backup"), Err(Error::TableExists(_))));
+        assert!(matches!(db.copy_table("missing", "other"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn test_read_only_reads_but_refuses_writes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("db");
+        assert!(DATABASE::open_read_only(path.to_str().unwrap()).is_err());
+        assert!(!path.exists());
+
+        let db = DATABASE::init(path.to_str().unwrap().to_string());
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields.clone(), "id".to_string(), "users".to_string()).unwrap();
+        let user = |id: &str| {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(30.0), String::new()));
+            row
+        };
+        db.add_row("users".to_string(), user("u1"), false).unwrap();
+        db.add_index("users", "by_age", "age", false).unwrap();
+        db.generate_add_index_migration("by_id", "users", "id", false).unwrap();
+        drop(db);
+
+        let db = DATABASE::open_read_only(path.to_str().unwrap()).unwrap();
+        assert!(db.is_read_only());
+        let thirty = Data::NUMBER(30.0);
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+        assert_eq!(db.get_all("users".to_string()).unwrap().len(), 1);
+        assert_eq!(db.query("users".to_string()).where_("age", crate::Operator::Eq, thirty.clone()).count(), 1);
+        assert_eq!(db.find_by_index("users", "by_age", &thirty).unwrap().len(), 1);
+        assert!(db.check("users").unwrap().is_empty());
+
+        let read_only = |result: Result<()>| assert!(matches!(result, Err(Error::ReadOnly)), "{:?}", result);
+        read_only(db.add_row("users".to_string(), user("u2"), false));
+        read_only(db.add_rows("users".to_string(), vec![user("u2")], false));
+        read_only(db.upsert("users".to_string(), user("u1")).map(drop));
+        read_only(db.upsert_many("users", vec![user("u1")]).map(drop));
+        read_only(db.add_row_autoid("users", user(""), IdStrategy::Uuid).map(drop));
+        read_only(db.update_row_by_id("users".to_string(), "u1".to_string(), user("u1")).map(drop));
+        read_only(db.update_field_by_id("users".to_string(), "u1".to_string(), "age".to_string(), (Data::NUMBER(1.0), String::new())).map(drop));
+        read_only(db.update_row_where("users".to_string(), "age".to_string(), thirty.clone(), user("u1"), true, CMP::EQUAL).map(drop));
+        read_only(db.update_many_by_id("users".to_string(), HashMap::from([("u1".to_string(), user("u1"))])).map(drop));
+        read_only(db.delete_by_id("users", "u1").map(drop));
+        read_only(db.truncate_table("users"));
+        read_only(db.create_table(fields, "id".to_string(), "more".to_string()));
+        read_only(db.rename_table("users", "people"));
+        read_only(db.copy_table("users", "people"));
+        read_only(db.set_required("users", "age", true));
+        read_only(db.add_index("users", "by_id", "id", false));
+        read_only(db.compact_table("users"));
+        read_only(db.apply_migrations());
+        read_only(db.generate_drop_index_migration("by_age", "users"));
+        read_only(db.shard_buffer("users", 4).map(drop));
+
+        read_only(db.delete_row_by_id("users".to_string(), "u1".to_string()).map(drop));
+        read_only(db.delete_many_by_id("users".to_string(), &["u1".to_string()]).map(drop));
+        read_only(db.delete_row_where("users".to_string(), "age".to_string(), thirty.clone(), true, CMP::EQUAL).map(drop));
+        read_only(db.query("users".to_string()).delete().map(drop));
+        read_only(db.query("users".to_string()).update_row(user("u1")).map(drop));
+        read_only(db.query("users".to_string()).update_field("age", (Data::NUMBER(1.0), String::new())).map(drop));
+
+        let db = DATABASE::init(path.to_str().unwrap().to_string());
+        assert_eq!(db.count_rows("users".to_string()), 1);
+        assert_eq!(db.get_schema("users").unwrap().indexes.len(), 1);
+        assert_eq!(db.applied_migrations().unwrap().len(), 0);
+    }
 }
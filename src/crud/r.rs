@@ -1,72 +1,243 @@
-use std::cmp::PartialEq;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::PathBuf;
 
-use crate::crud::make::{Data, data_eq, data_eq_type, DATABASE};
-use crate::crud::u::CMP;
+use serde::de::IgnoredAny;
+
+use crate::crud::make::{Data, DATABASE, Row};
+use crate::crud::u::{fold_for_search, CMP};
+use crate::crud::stats::bump;
+use crate::crud::wal::{read_shard, shard_files};
+use crate::error::Result;
 
 impl PartialEq for Data {
     fn eq(&self, other: &Self) -> bool {
-        if !data_eq(&self.clone(), &other.clone()) {
-            return false;
+        use Data::*;
+        match (self, other) {
+            (NULL, NULL) => true,
+            (STRING(a), STRING(b)) => a == b,
+            (NUMBER(a), NUMBER(b)) => number_eq(*a, *b),
+            (ARRAY(a), ARRAY(b)) => a == b,
+            (BOOLEAN(a), BOOLEAN(b)) => a == b,
+            (JSON(a), JSON(b)) => a == b,
+            (STRINGNULL(a), STRINGNULL(b)) => a == b,
+            (NUMBERNULL(Some(a)), NUMBERNULL(Some(b))) => number_eq(*a, *b),
+            (NUMBERNULL(None), NUMBERNULL(None)) => true,
+            (ARRAYNULL(a), ARRAYNULL(b)) => a == b,
+            (BOOLEANNULL(a), BOOLEANNULL(b)) => a == b,
+            (JSONNULL(a), JSONNULL(b)) => a == b,
+            (HASHMAP(a), HASHMAP(b)) => a == b,
+            (HASHSET(a), HASHSET(b)) => set_eq(a, b),
+            (HASHMAPNULL(a), HASHMAPNULL(b)) => a == b,
+            (HASHSETNULL(Some(a)), HASHSETNULL(Some(b))) => set_eq(a, b),
+            (HASHSETNULL(None), HASHSETNULL(None)) => true,
+            _ => false,
         }
+    }
+}
 
+/// `Data` is `Eq`, so unlike plain `f64` a NaN `NUMBER` equals itself (every
+/// NaN equals every other). `0.0` and `-0.0` are equal, as with `f64`.
+impl Eq for Data {}
+
+/// Consistent with `PartialEq`: all NaNs hash alike, as do `0.0` and `-0.0`;
+/// a `HASHMAP` or `HASHSET` hashes the same whatever its order.
+impl Hash for Data {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use Data::*;
+        std::mem::discriminant(self).hash(state);
         match self {
-            Data::NULL => true,
-            Data::STRING(i) => i == &other.clone().get_string(),
-            Data::NUMBER(i) => i == &other.clone().get_number(),
-            Data::ARRAY(i) => i == &other.clone().get_array(),
-            Data::BOOLEAN(i) => i == &other.clone().get_boolean(),
-            Data::JSON(i) => i == &other.clone().get_json(),
-            Data::STRINGNULL(i) => i == &other.clone().get_stringnull(),
-            Data::NUMBERNULL(i) => i == &other.clone().get_numbernull(),
-            Data::ARRAYNULL(i) => i == &other.clone().get_arraynull(),
-            Data::BOOLEANNULL(i) => i == &other.clone().get_booleannull(),
-            Data::JSONNULL(i) => i == &other.clone().get_jsonnull(),
+            NULL => {}
+            STRING(s) | JSON(s) => s.hash(state),
+            NUMBER(n) => number_bits(*n).hash(state),
+            ARRAY(items) => items.hash(state),
+            BOOLEAN(b) => b.hash(state),
+            HASHMAP(map) => map_hash(map, state),
+            HASHSET(items) => set_hash(items, state),
+            STRINGNULL(s) | JSONNULL(s) => s.hash(state),
+            NUMBERNULL(n) => n.map(number_bits).hash(state),
+            ARRAYNULL(items) => items.hash(state),
+            BOOLEANNULL(b) => b.hash(state),
+            HASHMAPNULL(map) => {
+                map.is_some().hash(state);
+                if let Some(map) = map {
+                    map_hash(map, state);
+                }
+            }
+            HASHSETNULL(items) => {
+                items.is_some().hash(state);
+                if let Some(items) = items {
+                    set_hash(items, state);
+                }
+            }
         }
     }
 }
 
+fn number_eq(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+fn number_bits(n: f64) -> u64 {
+    if n.is_nan() {
+        f64::NAN.to_bits()
+    } else if n == 0.0 {
+        0
+    } else {
+        n.to_bits()
+    }
+}
+
+fn hash_one(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Sums the entry hashes so the map's iteration order doesn't matter.
+fn map_hash<H: Hasher>(map: &HashMap<String, Data>, state: &mut H) {
+    map.len().hash(state);
+    map.iter().fold(0u64, |sum, entry| sum.wrapping_add(hash_one(&entry))).hash(state);
+}
+
+/// Like `map_hash`, over the distinct elements: `set_eq` ignores order and
+/// repeats.
+fn set_hash<H: Hasher>(items: &[Data], state: &mut H) {
+    items.len().hash(state);
+    items
+        .iter()
+        .enumerate()
+        .filter(|(i, item)| !items[..*i].contains(item))
+        .fold(0u64, |sum, (_, item)| sum.wrapping_add(hash_one(item)))
+        .hash(state);
+}
+
+fn set_eq(a: &[Data], b: &[Data]) -> bool {
+    a.len() == b.len() && a.iter().all(|x| b.contains(x)) && b.iter().all(|x| a.contains(x))
+}
+
 impl DATABASE {
-    pub fn get_all(&self, table_name: String) -> HashMap<String, HashMap<String, (Data, String)>> {
+    /// Every row of a table by id. Fails if the table is missing or any of its
+    /// shards can't be read or parsed.
+    pub fn get_all(&self, table_name: String) -> Result<HashMap<String, Row>> {
         let mut result = HashMap::new();
         let mut path = PathBuf::from(&self.path);
         path.push(&table_name);
 
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries.flatten() {
-                if let Ok(data_str) = fs::read_to_string(entry.path()) {
-                    if let Ok(data) = serde_json::from_str::<HashMap<String, HashMap<String, (Data, String)>>>(&data_str) {
-                        for (k, v) in data {
-                            result.insert(k, v);
-                        }
-                    }
-                }
-            }
+        let lock = self.table_lock(&table_name);
+        let _guard = lock.read().unwrap();
+
+        for entry in shard_files(&path)? {
+            result.extend(self.load_shard::<Row>(&entry)?);
         }
 
-        result
+        bump(&self.counters.rows_read, result.len());
+        Ok(result)
     }
 
-    pub fn get_by_id(&self, table_name: String, id_input: String) -> Option<HashMap<String, (Data, String)>> {
-        let id = Self::string_to_numerical_uuid(&id_input);
-        let (start, end) = Self::get_shard_range(&id);
-        let filename = format!("{}-{}.txt", start, end);
+    /// Yields every `(id, row)` of a table, reading one shard file at a time,
+    /// so only a single shard is held in memory.
+    pub fn iter_rows(&self, table_name: String) -> impl Iterator<Item = (String, Row)> {
+        let path = PathBuf::from(&self.path).join(table_name);
+        let counters = self.counters.clone();
 
-        let mut path = PathBuf::from(&self.path);
-        path.push(table_name);
-        path.push(filename);
+        shard_files(&path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(move |shard| {
+                bump(&counters.shard_reads, 1);
+                read_shard::<Row>(&shard)
+            })
+            .flatten()
+    }
+
+    pub fn count_rows(&self, table_name: String) -> usize {
+        self.shard_stats(&table_name).iter().map(|(_, rows)| rows).sum()
+    }
+
+    /// Row count of each shard file of a table, sorted by file name. One shard
+    /// holding most of the rows means the ids hash poorly.
+    pub fn shard_stats(&self, table_name: &str) -> Vec<(String, usize)> {
+        let path = PathBuf::from(&self.path).join(table_name);
+
+        shard_files(&path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|shard| {
+                // only the keys are counted, the rows themselves are skipped over
+                let rows = self.read_shard::<IgnoredAny>(&shard)?;
+                Some((shard.file_name()?.to_string_lossy().into_owned(), rows.len()))
+            })
+            .collect()
+    }
+
+    /// The row with id `id_input`. `Ok(None)` means the row doesn't exist; a
+    /// shard that can't be read or parsed is an error.
+    pub fn get_by_id(&self, table_name: String, id_input: String) -> Result<Option<Row>> {
+        let id = Self::string_to_numerical_uuid(&id_input);
+        let path = self.shard_path(&table_name, &id_input);
 
         if !path.exists() {
-            return None;
+            return Ok(None);
         }
 
-        let data_str = fs::read_to_string(&path).ok()?;
-        let deser: HashMap<String, HashMap<String, (Data, String)>> =
-            serde_json::from_str(&data_str).ok()?;
+        let row = self.load_shard::<Row>(&path)?.remove(&id);
+        bump(&self.counters.rows_read, usize::from(row.is_some()));
+        Ok(row)
+    }
+
+    /// Whether a row with id `id_input` exists. Only the shard's keys are
+    /// parsed; the rows themselves are skipped over, never built into `Data`.
+    /// A shard that can't be read counts as not holding the row.
+    pub fn exists_by_id(&self, table_name: &str, id_input: &str) -> bool {
+        let id = Self::string_to_numerical_uuid(id_input);
+        let path = self.shard_path(table_name, id_input);
+
+        path.exists() && self.read_shard::<IgnoredAny>(&path).is_some_and(|rows| rows.contains_key(&id))
+    }
+
+    /// Every `(table, id, field)` whose `STRING` or `JSON` value (nullable or
+    /// not) contains `needle`, sorted. `id` is the row's id-column value.
+    /// This reads every row of every table, so its cost grows with the whole
+    /// database; fine for an admin search over a small one, not for anything
+    /// hot. Unreadable shards are skipped.
+    pub fn search_all(&self, needle: &str) -> Result<Vec<(String, String, String)>> {
+        self.search(needle, false)
+    }
 
-        deser.get(&id).cloned()
+    /// `search_all` ignoring case and accents: both sides go through
+    /// [`fold_for_search`] first, so `"jose"` finds `"José"`.
+    pub fn search_all_insensitive(&self, needle: &str) -> Result<Vec<(String, String, String)>> {
+        self.search(needle, true)
+    }
+
+    fn search(&self, needle: &str, insensitive: bool) -> Result<Vec<(String, String, String)>> {
+        let needle = if insensitive { fold_for_search(needle) } else { needle.to_string() };
+        let mut found = vec![];
+        for table in self.table_names()? {
+            let schema = self.get_schema(&table)?;
+            for (key, row) in self.iter_rows(table.clone()) {
+                let id = schema.row_id(&row).unwrap_or(key);
+                for (field, (value, _)) in &row {
+                    let text = match value {
+                        Data::STRING(text) | Data::JSON(text) => text,
+                        Data::STRINGNULL(Some(text)) | Data::JSONNULL(Some(text)) => text,
+                        _ => continue,
+                    };
+                    let hit = if insensitive {
+                        fold_for_search(text).contains(&needle)
+                    } else {
+                        text.contains(&needle)
+                    };
+                    if hit {
+                        found.push((table.clone(), id.clone(), field.clone()));
+                    }
+                }
+            }
+        }
+        found.sort();
+        Ok(found)
     }
 
     pub fn get_where(
@@ -76,39 +247,521 @@ impl DATABASE {
         field_value: Data,
         multi: bool,
         cmp: CMP,
-    ) -> Vec<(String, HashMap<String, (Data, String)>)> {
+    ) -> Result<Vec<(String, Row)>> {
         let mut vec = vec![];
         let mut path = PathBuf::from(&self.path);
         path.push(table_name);
 
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries.flatten() {
-                if let Ok(data_str) = fs::read_to_string(entry.path()) {
-                    if let Ok(deser) =
-                        serde_json::from_str::<HashMap<String, HashMap<String, (Data, String)>>>(
-                            &data_str,
-                        )
-                    {
-                        for (id, row) in deser {
-                            if let Some((data, _regex)) = row.get(&field_name) {
-                                if cmp.clone().calculate(field_value.clone(), data.clone()) {
-                                    vec.push((id, row));
-                                    if !multi {
-                                        return vec;
-                                    }
-                                }
-                            }
+        for entry in shard_files(&path)? {
+            for (id, row) in self.load_shard::<Row>(&entry)? {
+                if let Some((data, _regex)) = row.get(&field_name) {
+                    if cmp.calculate(&field_value, data) {
+                        vec.push((id, row));
+                        if !multi {
+                            bump(&self.counters.rows_read, vec.len());
+                            return Ok(vec);
                         }
                     }
                 }
             }
         }
 
-        vec
+        bump(&self.counters.rows_read, vec.len());
+        Ok(vec)
+    }
+
+    /// Rows whose `field_name` lies between `low` and `high` by `Data`'s
+    /// ordering, each bound inclusive or exclusive, in one scan. Bounds that
+    /// are reversed (`low > high`) or can't be compared match nothing.
+    pub fn get_range(
+        &self,
+        table_name: String,
+        field_name: String,
+        low: Data,
+        high: Data,
+        inclusive_low: bool,
+        inclusive_high: bool,
+    ) -> Vec<(String, Row)> {
+        if !matches!(low.partial_cmp(&high), Some(Ordering::Less | Ordering::Equal)) {
+            return vec![];
+        }
+
+        let in_range = |value: &Data| {
+            let above = match value.partial_cmp(&low) {
+                Some(Ordering::Greater) => true,
+                Some(Ordering::Equal) => inclusive_low,
+                _ => false,
+            };
+            let below = match value.partial_cmp(&high) {
+                Some(Ordering::Less) => true,
+                Some(Ordering::Equal) => inclusive_high,
+                _ => false,
+            };
+            above && below
+        };
+
+        let rows: Vec<(String, Row)> = self
+            .iter_rows(table_name)
+            .filter(|(_, row)| row.get(&field_name).is_some_and(|(value, _)| in_range(value)))
+            .collect();
+        bump(&self.counters.rows_read, rows.len());
+        rows
+    }
+
+    /// Inner join: pairs every row of `left_table` with each row of
+    /// `right_table` whose `right_field` equals its `left_field`. Rows whose
+    /// key is missing or null never match.
+    pub fn join(
+        &self,
+        left_table: String,
+        left_field: String,
+        right_table: String,
+        right_field: String,
+    ) -> Vec<(Row, Row)> {
+        self.join_left(left_table, left_field, right_table, right_field)
+            .into_iter()
+            .filter_map(|(left, right)| Some((left, right?)))
+            .collect()
+    }
+
+    /// Left join: like [`DATABASE::join`], but a left row without any match is
+    /// kept once, paired with `None`.
+    pub fn join_left(
+        &self,
+        left_table: String,
+        left_field: String,
+        right_table: String,
+        right_field: String,
+    ) -> Vec<(Row, Option<Row>)> {
+        let mut right: HashMap<Data, Vec<Row>> = HashMap::new();
+        for (_, row) in self.iter_rows(right_table) {
+            if let Some(key) = Self::join_key(&row, &right_field) {
+                right.entry(key).or_default().push(row);
+            }
+        }
+
+        let mut pairs = vec![];
+        for (_, left) in self.iter_rows(left_table) {
+            match Self::join_key(&left, &left_field).and_then(|key| right.get(&key)) {
+                Some(matches) => {
+                    for r in matches {
+                        pairs.push((left.clone(), Some(r.clone())));
+                    }
+                }
+                None => pairs.push((left, None)),
+            }
+        }
+
+        pairs
+    }
+
+    fn join_key(row: &Row, field: &str) -> Option<Data> {
+        row.get(field)?.0.unwrap_nullable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::crud::make::Type;
+    use crate::error::Error;
+    use crate::Operator;
+
+    use super::*;
+
+    #[test]
+    fn test_large_array_equality_is_element_wise() {
+        let big: Vec<Data> = (0..100_000).map(|i| Data::NUMBER(i as f64)).collect();
+        let a = Data::ARRAY(big.clone());
+        let b = Data::ARRAY(big.clone());
+        assert_eq!(a, b);
+
+        let mut changed = big;
+        changed[99_999] = Data::STRING("last".to_string());
+        assert_ne!(a, Data::ARRAY(changed));
+
+        let nested = Data::ARRAY(vec![a.clone(), Data::NULL]);
+        assert_eq!(nested, Data::ARRAY(vec![b, Data::NULL]));
+        assert_ne!(nested, Data::ARRAYNULL(None));
+    }
+
+    #[test]
+    fn test_json_integer_matches_float_in_get_where() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        // A shard written by another tool, with `age` as a JSON integer
+        let shard = temp_dir.path().join("users").join("0000000-9999999.txt");
+        fs::write(
+            shard,
+            r#"{"1":{"id":[{"STRING":"u1"},""],"age":[{"NUMBER":30},""]}}"#,
+        )
+        .unwrap();
+
+        let found = db.get_where(
+            "users".to_string(),
+            "age".to_string(),
+            Data::NUMBER(30.0),
+            true,
+            CMP::EQUAL,
+        ).unwrap();
+        assert_eq!(found.len(), 1);
+
+        let count = db
+            .query("users".to_string())
+            .where_("age", Operator::Eq, Data::NUMBER(30.0))
+            .count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_join_users_and_orders() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("user_id".to_string(), (Type::STRINGNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+
+        for (id, name) in [("u1", "Alice"), ("u2", "Bob"), ("u3", "Carol")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+        for (id, user) in [("o1", Some("u1")), ("o2", Some("u1")), ("o3", Some("u2")), ("o4", None)] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("user_id".to_string(), (Data::STRINGNULL(user.map(str::to_string)), String::new()));
+            db.add_row("orders".to_string(), row, false).unwrap();
+        }
+
+        let mut pairs: Vec<(String, String)> = db
+            .join("users".to_string(), "id".to_string(), "orders".to_string(), "user_id".to_string())
+            .into_iter()
+            .map(|(user, order)| (user["name"].0.clone().get_string(), order["id"].0.clone().get_string()))
+            .collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                ("Alice".to_string(), "o1".to_string()),
+                ("Alice".to_string(), "o2".to_string()),
+                ("Bob".to_string(), "o3".to_string()),
+            ]
+        );
+
+        let left = db.join_left("users".to_string(), "id".to_string(), "orders".to_string(), "user_id".to_string());
+        assert_eq!(left.len(), 4);
+        let carol = left.iter().find(|(user, _)| user["id"].0 == Data::STRING("u3".to_string())).unwrap();
+        assert!(carol.1.is_none());
+    }
+
+    #[test]
+    fn test_shard_stats_counts_rows_per_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut expected: HashMap<String, usize> = HashMap::new();
+        for i in 0..50 {
+            let id = format!("u{}", i);
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.clone()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+            *expected.entry(DATABASE::get_file_by_id(DATABASE::string_to_numerical_uuid(&id))).or_default() += 1;
+        }
+
+        let stats = db.shard_stats("users");
+        assert!(stats.len() > 1);
+        for (file, rows) in &stats {
+            assert_eq!(*rows, expected.get(file).copied().unwrap_or(0), "{}", file);
+        }
+        assert_eq!(stats.iter().map(|(_, rows)| rows).sum::<usize>(), 50);
+    }
+
+    #[test]
+    fn test_iter_rows_matches_count_rows() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..40 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let mut seen = 0;
+        for (id, row) in db.iter_rows("users".to_string()) {
+            assert_eq!(id, DATABASE::string_to_numerical_uuid(&row["id"].0.clone().get_string()));
+            seen += 1;
+        }
+        assert_eq!(seen, db.count_rows("users".to_string()));
+        assert_eq!(seen, 40);
+    }
+
+    #[test]
+    fn test_get_range_numbers_and_strings() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for (id, age) in [("ann", 18.0), ("bob", 25.0), ("cat", 30.0), ("dan", 42.0), ("eve", 65.0)] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(age), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let ids = |rows: Vec<(String, Row)>| {
+            let mut ids: Vec<String> = rows.into_iter().map(|(_, row)| row["id"].0.clone().get_string()).collect();
+            ids.sort();
+            ids
+        };
+
+        let adults = db.get_range(
+            "users".to_string(),
+            "age".to_string(),
+            Data::NUMBER(25.0),
+            Data::NUMBER(42.0),
+            true,
+            false,
+        );
+        assert_eq!(ids(adults), vec!["bob", "cat"]);
+
+        let names = db.get_range(
+            "users".to_string(),
+            "id".to_string(),
+            Data::STRING("b".to_string()),
+            Data::STRING("dan".to_string()),
+            false,
+            true,
+        );
+        assert_eq!(ids(names), vec!["bob", "cat", "dan"]);
+
+        let reversed = db.get_range(
+            "users".to_string(),
+            "age".to_string(),
+            Data::NUMBER(60.0),
+            Data::NUMBER(20.0),
+            true,
+            true,
+        );
+        assert!(reversed.is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_shard_is_an_error_not_a_miss() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        for id in ["u1", "u2"] {
+            let row = HashMap::from([("id".to_string(), (Data::STRING(id.to_string()), String::new()))]);
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+        assert!(db.get_by_id("users".to_string(), "nobody".to_string()).unwrap().is_none());
+
+        let shard = temp_dir
+            .path()
+            .join("users")
+            .join(DATABASE::get_file_by_id(DATABASE::string_to_numerical_uuid("u1")));
+        fs::write(&shard, "{\"truncated").unwrap();
+
+        assert!(matches!(
+            db.get_by_id("users".to_string(), "u1".to_string()),
+            Err(Error::Serde(_))
+        ));
+        assert!(db.get_all("users".to_string()).is_err());
+        assert!(db
+            .update_row_by_id("users".to_string(), "u1".to_string(), HashMap::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_exists_by_id() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        let row = HashMap::from([("id".to_string(), (Data::STRING("u1".to_string()), String::new()))]);
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        assert!(db.exists_by_id("users", "u1"));
+        assert!(!db.exists_by_id("users", "nobody"));
+        assert!(!db.exists_by_id("missing", "u1"));
+
+        // a broken shard elsewhere in the table doesn't matter
+        fs::write(temp_dir.path().join("users").join("0-1.txt"), "{\"truncated").unwrap();
+        assert!(db.exists_by_id("users", "u1"));
+        assert!(!db.exists_by_id("users", "nobody"));
+    }
+
+    #[test]
+    fn test_data_as_hashset_key() {
+        use std::collections::HashSet;
+
+        let mut set = HashSet::new();
+        let distinct = [
+            Data::NULL,
+            Data::STRING("a".to_string()),
+            Data::STRING("b".to_string()),
+            Data::NUMBER(1.0),
+            Data::NUMBER(f64::NAN),
+            Data::NUMBER(0.0),
+            Data::NUMBERNULL(Some(1.0)),
+            Data::NUMBERNULL(None),
+            Data::STRINGNULL(None),
+            Data::ARRAY(vec![Data::NUMBER(1.0), Data::NUMBER(2.0)]),
+            Data::ARRAY(vec![Data::NUMBER(2.0), Data::NUMBER(1.0)]),
+            Data::HASHSET(vec![Data::NUMBER(1.0), Data::NUMBER(2.0)]),
+            Data::HASHMAP(HashMap::from([("k".to_string(), Data::BOOLEAN(true))])),
+        ];
+        for value in &distinct {
+            assert!(set.insert(value.clone()), "{:?} counted as a duplicate", value);
+        }
+
+        // equal to values already in the set
+        let repeats = [
+            Data::NUMBER(f64::NAN.copysign(-1.0)),
+            Data::NUMBER(-0.0),
+            Data::HASHSET(vec![Data::NUMBER(2.0), Data::NUMBER(1.0)]),
+            Data::HASHMAP(HashMap::from([("k".to_string(), Data::BOOLEAN(true))])),
+        ];
+        for value in repeats {
+            assert!(!set.insert(value.clone()), "{:?} counted as new", value);
+        }
+        assert_eq!(set.len(), distinct.len());
+        assert!(set.contains(&Data::NUMBER(f64::NAN)));
+    }
+
+    #[test]
+    fn test_different_variants_never_equal() {
+        let values = [
+            Data::NULL,
+            Data::STRING("1".to_string()),
+            Data::NUMBER(1.0),
+            Data::ARRAY(vec![]),
+            Data::BOOLEAN(true),
+            Data::JSON("1".to_string()),
+            Data::HASHMAP(HashMap::new()),
+            Data::HASHSET(vec![]),
+            Data::STRINGNULL(Some("1".to_string())),
+            Data::NUMBERNULL(Some(1.0)),
+            Data::ARRAYNULL(Some(vec![])),
+            Data::BOOLEANNULL(Some(true)),
+            Data::JSONNULL(Some("1".to_string())),
+            Data::HASHMAPNULL(Some(HashMap::new())),
+            Data::HASHSETNULL(Some(vec![])),
+        ];
+        let nulls = [
+            Data::STRINGNULL(None),
+            Data::NUMBERNULL(None),
+            Data::ARRAYNULL(None),
+            Data::BOOLEANNULL(None),
+            Data::JSONNULL(None),
+            Data::HASHMAPNULL(None),
+            Data::HASHSETNULL(None),
+        ];
+
+        let all: Vec<&Data> = values.iter().chain(&nulls).collect();
+        for (i, a) in all.iter().enumerate() {
+            for (j, b) in all.iter().enumerate() {
+                assert_eq!(a == b, i == j, "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_all_across_tables() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("email".to_string(), (Type::STRINGNULL, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("payload".to_string(), (Type::JSON, String::new()));
+        db.create_table(fields, "id".to_string(), "events".to_string()).unwrap();
+
+        for (id, email) in [("u1", "alice@example.com"), ("u2", "bob@example.com")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("email".to_string(), (Data::STRINGNULL(Some(email.to_string())), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(30.0), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("e1".to_string()), String::new()));
+        row.insert("payload".to_string(), (Data::JSON(r#"{"to":"alice@example.com"}"#.to_string()), String::new()));
+        db.add_row("events".to_string(), row, false).unwrap();
+
+        let hit = |table: &str, id: &str, field: &str| (table.to_string(), id.to_string(), field.to_string());
+        assert_eq!(
+            db.search_all("alice").unwrap(),
+            vec![hit("events", "e1", "payload"), hit("users", "u1", "email")]
+        );
+        assert_eq!(db.search_all("u2").unwrap(), vec![hit("users", "u2", "id")]);
+        // numbers aren't searched
+        assert!(db.search_all("30").unwrap().is_empty());
     }
 
-    fn get_shard_range(id: &str) -> (String, String) {
-        let base = &id[..id.len().saturating_sub(7)];
-        (format!("{}0000000", base), format!("{}9999999", base))
+    #[test]
+    fn test_search_all_insensitive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "places".to_string()).unwrap();
+        // precomposed, decomposed (e + U+0301) and plain
+        for (id, name) in [("p1", "Café Central"), ("p2", "Cafe\u{301} Noir"), ("p3", "Tea House")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            db.add_row("places".to_string(), row, false).unwrap();
+        }
+
+        let ids = |hits: Vec<(String, String, String)>| hits.into_iter().map(|(_, id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(db.search_all_insensitive("cafe").unwrap()), ["p1", "p2"]);
+        assert_eq!(ids(db.search_all_insensitive("CAFÉ").unwrap()), ["p1", "p2"]);
+        assert!(db.search_all("cafe").unwrap().is_empty());
+        assert_eq!(ids(db.search_all("Café").unwrap()), ["p1"]);
+
+        assert_eq!(fold_for_search("José Ñúñez"), "jose nunez");
+        assert_eq!(fold_for_search("Straße Æther"), "strasse aether");
     }
 }
@@ -0,0 +1,95 @@
+use crate::crud::make::{Data, DATABASE, FieldError, Row};
+use crate::error::Result;
+use crate::QueryBuilder;
+
+/// Maps a struct to and from a table row, so it can be inserted and queried
+/// without building the `HashMap` by hand.
+pub trait Record: Sized {
+    fn to_row(&self) -> Row;
+    fn from_row(row: Row) -> Result<Self>;
+}
+
+/// Takes `field` out of a row for `Record::from_row`, erroring if it's missing.
+pub fn take_field(row: &mut Row, field: &str) -> Result<Data> {
+    row.remove(field)
+        .map(|(data, _)| data)
+        .ok_or_else(|| FieldError::Missing { field: field.to_string() }.into())
+}
+
+impl DATABASE {
+    /// Inserts `record` as a new row; like `add_row` without `overwrite`, it
+    /// fails with `Error::UniqueViolation` if a row already has its id.
+    pub fn insert_typed<T: Record>(&self, table: &str, record: &T) -> Result<()> {
+        self.add_row(table.to_string(), record.to_row(), false)
+    }
+}
+
+impl QueryBuilder<'_> {
+    pub fn execute_as<T: Record>(&self) -> Result<Vec<T>> {
+        self.execute().into_iter().map(T::from_row).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::crud::make::Type;
+    use crate::error::Error;
+    use crate::Operator;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: f64,
+    }
+
+    impl Record for User {
+        fn to_row(&self) -> Row {
+            let mut row = HashMap::new();
+            row.insert("name".to_string(), (Data::STRING(self.name.clone()), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(self.age), String::new()));
+            row
+        }
+
+        fn from_row(mut row: Row) -> Result<Self> {
+            Ok(User {
+                name: take_field(&mut row, "name")?.get_string(),
+                age: take_field(&mut row, "age")?.get_number(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "name".to_string(), "users".to_string()).unwrap();
+
+        let alice = User { name: "Alice".to_string(), age: 30.0 };
+        db.insert_typed("users", &alice).unwrap();
+        db.insert_typed("users", &User { name: "Bob".to_string(), age: 25.0 }).unwrap();
+        let older = User { name: "Alice".to_string(), age: 99.0 };
+        assert!(matches!(db.insert_typed("users", &older), Err(Error::UniqueViolation { .. })));
+
+        let users: Vec<User> = db
+            .query("users".to_string())
+            .where_("age", Operator::Gt, Data::NUMBER(26.0))
+            .execute_as()
+            .unwrap();
+        assert_eq!(users, vec![alice]);
+    }
+
+    #[test]
+    fn test_from_row_missing_field() {
+        let mut row = HashMap::new();
+        row.insert("name".to_string(), (Data::STRING("Alice".to_string()), String::new()));
+        assert!(User::from_row(row).is_err());
+    }
+}
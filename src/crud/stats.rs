@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::crud::make::DATABASE;
+
+/// Operation counters of a `DATABASE`, shared by its clones.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) rows_inserted: AtomicU64,
+    pub(crate) rows_read: AtomicU64,
+    pub(crate) rows_updated: AtomicU64,
+    pub(crate) rows_deleted: AtomicU64,
+    pub(crate) shard_reads: AtomicU64,
+    pub(crate) shard_writes: AtomicU64,
+}
+
+/// Adds `n` to `counter`. Relaxed: the counters order nothing, and a
+/// snapshot taken during a write may be off by that write.
+pub(crate) fn bump(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// What a `DATABASE` handle and its clones have done since it was opened;
+/// see `DATABASE::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Rows written under an id no row held yet, by any insert method.
+    pub rows_inserted: u64,
+    /// Rows handed back by `get_by_id`, `get_all`, `get_where`, `get_range`,
+    /// `find_by_index` and queries. Reads done inside updates count too;
+    /// `iter_rows` and query cache hits don't.
+    pub rows_read: u64,
+    /// Rows changed by updates, and rows an insert or upsert overwrote.
+    pub rows_updated: u64,
+    /// Rows deleted by id or by query, cascades included. `truncate_table`
+    /// doesn't count the rows it drops.
+    pub rows_deleted: u64,
+    /// Shard files read, whether for a read or to merge in a write.
+    pub shard_reads: u64,
+    pub shard_writes: u64,
+}
+
+impl DATABASE {
+    /// A snapshot of this handle's operation counters. They are cheap to keep
+    /// (one relaxed atomic add per row or shard), start at zero on open, and
+    /// don't see other processes or separately opened handles.
+    pub fn stats(&self) -> Stats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Stats {
+            rows_inserted: get(&self.counters.rows_inserted),
+            rows_read: get(&self.counters.rows_read),
+            rows_updated: get(&self.counters.rows_updated),
+            rows_deleted: get(&self.counters.rows_deleted),
+            shard_reads: get(&self.counters.shard_reads),
+            shard_writes: get(&self.counters.shard_writes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::crud::make::{Data, Row, Type};
+
+    use super::*;
+
+    fn user(id: usize) -> Row {
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING(format!("u{}", id)), String::new()));
+        row
+    }
+
+    #[test]
+    fn test_stats_count_inserts_reads_and_shards() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        assert_eq!(db.stats(), Stats::default());
+
+        for i in 0..20 {
+            db.add_row("users".to_string(), user(i), false).unwrap();
+        }
+        let after_inserts = db.stats();
+        assert_eq!(after_inserts.rows_inserted, 20);
+        assert_eq!(after_inserts.shard_writes, 20);
+
+        for i in 0..7 {
+            assert!(db.get_by_id("users".to_string(), format!("u{}", i)).unwrap().is_some());
+        }
+        assert!(db.get_by_id("users".to_string(), "nobody".to_string()).unwrap().is_none());
+        let stats = db.stats();
+        assert_eq!(stats.rows_read, 7);
+        assert!(stats.shard_reads - after_inserts.shard_reads <= 8);
+        assert_eq!(stats.shard_writes, 20);
+
+        // an overwrite is an update, not an insert
+        db.add_row("users".to_string(), user(3), true).unwrap();
+        db.delete_row_by_id("users".to_string(), "u4".to_string()).unwrap().unwrap();
+        assert_eq!(db.query("users".to_string()).execute().len(), 19);
+        let stats = db.stats();
+        assert_eq!(stats.rows_inserted, 20);
+        assert_eq!(stats.rows_updated, 1);
+        assert_eq!(stats.rows_deleted, 1);
+        assert_eq!(stats.rows_read, 7 + 19);
+        assert_eq!(db.clone().stats(), stats);
+    }
+}
@@ -1,13 +1,22 @@
 use std::cmp::{Ordering, PartialOrd};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{info, warn};
 use serde_json::{json, Value};
-use std::io::Write;
+use sha2::{Digest, Sha256};
 
-use crate::crud::make::{Data, data_eq, data_eq_type, DATABASE, TABLE, Type};
+use crate::crud::make::{Data, DATABASE, Row, TABLE, Type};
+use crate::error::{Error, Result};
+use crate::crud::stats::bump;
+use crate::crud::wal::shard_files;
 
+/// Consistent with `PartialEq`: two values compare `Equal` exactly when they
+/// are `==`. A NaN `NUMBER`, equal to itself there, sorts after every other
+/// number here. Values of different variants, and maps or sets that aren't
+/// equal, have no ordering.
 impl PartialOrd for Data {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         use Data::*;
@@ -15,25 +24,46 @@ impl PartialOrd for Data {
             return None;
         }
         match (self, other) {
-            (NULL, NULL) => None,
-            (STRING(i), STRING(j)) => j.cmp(i).into(),
-            (NUMBER(i), NUMBER(j)) => j.partial_cmp(i),
-            (ARRAY(i), ARRAY(j)) => j.partial_cmp(i),
-            (BOOLEAN(i), BOOLEAN(j)) => j.cmp(i).into(),
-            (JSON(i), JSON(j)) => j.cmp(i).into(),
-            (STRINGNULL(i), STRINGNULL(j)) => j.cmp(i).into(),
-            (NUMBERNULL(i), NUMBERNULL(j)) => j.partial_cmp(i),
-            (ARRAYNULL(i), ARRAYNULL(j)) => j.partial_cmp(i),
-            (BOOLEANNULL(i), BOOLEANNULL(j)) => j.partial_cmp(i),
-            (JSONNULL(i), JSONNULL(j)) => j.partial_cmp(i),
+            (NULL, NULL) => Some(Ordering::Equal),
+            (STRING(i), STRING(j)) => i.cmp(j).into(),
+            (NUMBER(i), NUMBER(j)) => Some(number_cmp(*i, *j)),
+            (ARRAY(i), ARRAY(j)) => i.partial_cmp(j),
+            (BOOLEAN(i), BOOLEAN(j)) => i.cmp(j).into(),
+            (JSON(i), JSON(j)) => i.cmp(j).into(),
+            // `None` orders before `Some`, so nulls come first
+            (STRINGNULL(i), STRINGNULL(j)) => i.cmp(j).into(),
+            (NUMBERNULL(Some(i)), NUMBERNULL(Some(j))) => Some(number_cmp(*i, *j)),
+            (NUMBERNULL(i), NUMBERNULL(j)) => i.is_some().cmp(&j.is_some()).into(),
+            (ARRAYNULL(i), ARRAYNULL(j)) => i.partial_cmp(j),
+            (BOOLEANNULL(i), BOOLEANNULL(j)) => i.partial_cmp(j),
+            (JSONNULL(i), JSONNULL(j)) => i.partial_cmp(j),
+            // maps and sets have no ordering, only equality
+            (HASHMAP(_), HASHMAP(_))
+            | (HASHSET(_), HASHSET(_))
+            | (HASHMAPNULL(_), HASHMAPNULL(_))
+            | (HASHSETNULL(_), HASHSETNULL(_)) => (self == other).then_some(Ordering::Equal),
             _ => None,
         }
     }
 }
 
+/// `f64`'s order, with NaN after every other number rather than unordered,
+/// all NaNs equal, and `0.0` equal to `-0.0`, as `Data`'s `PartialEq` has it.
+fn number_cmp(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap(),
+    }
+}
+
 #[derive(Clone)]
 pub enum CMP {
     EQUAL,
+    /// Like `EQUAL`, but strings compare without regard to case (see
+    /// [`eq_ignore_case`]).
+    EQUALCI,
     LESS,
     LESSEQ,
     GREATER,
@@ -41,21 +71,114 @@ pub enum CMP {
 }
 
 impl CMP {
-    pub fn calculate(self, x: Data, y: Data) -> bool {
+    /// Compares a stored `row` value against the `value` being searched for,
+    /// e.g. `CMP::LESS` holds when `row < value`.
+    pub fn calculate(&self, value: &Data, row: &Data) -> bool {
         match self {
-            CMP::EQUAL => x == y,
-            CMP::LESS => x < y,
-            CMP::LESSEQ => x <= y,
-            CMP::GREATER => x > y,
-            CMP::GTEQ => x >= y,
+            CMP::EQUAL => row == value,
+            CMP::EQUALCI => eq_ignore_case(row, value),
+            CMP::LESS => row < value,
+            CMP::LESSEQ => row <= value,
+            CMP::GREATER => row > value,
+            CMP::GTEQ => row >= value,
+        }
+    }
+}
+
+/// Equality that lowercases strings (including non-null `STRINGNULL`s) on both
+/// sides first; other values compare as with `==`. Lowercasing is Unicode's
+/// simple `to_lowercase`, not full case folding: `"ÉCOLE"` matches `"école"`,
+/// but `"STRASSE"` does not match `"straße"`.
+pub fn eq_ignore_case(a: &Data, b: &Data) -> bool {
+    match (a.unwrap_nullable(), b.unwrap_nullable()) {
+        (Some(Data::STRING(a)), Some(Data::STRING(b))) => a.to_lowercase() == b.to_lowercase(),
+        _ => a == b,
+    }
+}
+
+/// Normalizes text for case- and accent-insensitive matching: Unicode
+/// `to_lowercase`, then Latin letters with diacritics (the Latin-1 Supplement
+/// and Latin Extended-A blocks) replaced by their base letter, ligatures and
+/// `ß` spelled out, and combining marks (U+0300 to U+036F) dropped, so both
+/// precomposed and decomposed `"Café"` become `"cafe"`. Other scripts are
+/// only lowercased.
+pub fn fold_for_search(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        let base = match c {
+            '\u{0300}'..='\u{036F}' => continue,
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => 'a',
+            'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => 'c',
+            'ď' | 'đ' | 'ð' => 'd',
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => 'e',
+            'ĝ' | 'ğ' | 'ġ' | 'ģ' => 'g',
+            'ĥ' | 'ħ' => 'h',
+            'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => 'i',
+            'ĵ' => 'j',
+            'ķ' => 'k',
+            'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => 'l',
+            'ñ' | 'ń' | 'ņ' | 'ň' => 'n',
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => 'o',
+            'ŕ' | 'ŗ' | 'ř' => 'r',
+            'ś' | 'ŝ' | 'ş' | 'š' => 's',
+            'ţ' | 'ť' | 'ŧ' => 't',
+            'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => 'u',
+            'ŵ' => 'w',
+            'ý' | 'ÿ' | 'ŷ' => 'y',
+            'ź' | 'ż' | 'ž' => 'z',
+            'æ' => {
+                folded.push_str("ae");
+                continue;
+            }
+            'œ' => {
+                folded.push_str("oe");
+                continue;
+            }
+            'ß' => {
+                folded.push_str("ss");
+                continue;
+            }
+            'þ' => {
+                folded.push_str("th");
+                continue;
+            }
+            other => other,
+        };
+        folded.push(base);
+    }
+    folded
+}
+
+/// Copies a file, or a table directory with its (flat) shard files.
+fn copy_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)?.flatten() {
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
         }
+        Ok(())
+    } else {
+        fs::copy(from, to).map(|_| ())
     }
 }
 
+/// Hex SHA-256 of a migration file's contents.
+fn migration_checksum(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Error for a migration file missing something it needs.
+fn malformed(message: &str) -> Error {
+    Error::Migration(message.to_string())
+}
+
 impl DATABASE {
-    fn next_migration_filename(&self, name: &str) -> Result<PathBuf, String> {
+    /// Path for a new migration file, creating `migrations/` if it's missing.
+    fn next_migration_filename(&self, name: &str) -> Result<PathBuf> {
+        self.check_writable()?;
         let mut dir = PathBuf::from(&self.path);
         dir.push("migrations");
+        fs::create_dir_all(&dir)?;
 
         let timestamp = chrono::Utc::now().format("%Y%m%d%H%M%S%.6f").to_string();
         let filename = format!("{}_{}.json", timestamp, name);
@@ -69,7 +192,7 @@ impl DATABASE {
         table: &str,
         old_field: &str,
         new_field: &str,
-    ) -> Result<(), String> {
+    ) -> Result<()> {
         let json = serde_json::json!({
         "operation": "rename_column",
         "table": table,
@@ -78,10 +201,25 @@ impl DATABASE {
     });
 
         let path = self.next_migration_filename("rename_column")?;
-        fs::write(&path, serde_json::to_string_pretty(&json).unwrap())
-            .map_err(|e| format!("Failed to write migration: {}", e))?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
+
+        Ok(())
+    }
+
+    pub fn generate_rename_table_migration(
+        &self,
+        table: &str,
+        new_table: &str,
+    ) -> Result<()> {
+        let json = serde_json::json!({
+        "operation": "rename_table",
+        "table": table,
+        "new_table": new_table
+    });
+
+        let path = self.next_migration_filename("rename_table")?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
 
-        // println!("📝 Generated rename_column migration: {}", path.display());
         Ok(())
     }
 
@@ -89,7 +227,7 @@ impl DATABASE {
         &self,
         table: &str,
         field: &str,
-    ) -> Result<(), String> {
+    ) -> Result<()> {
         let json = serde_json::json!({
         "operation": "drop_column",
         "table": table,
@@ -97,56 +235,178 @@ impl DATABASE {
     });
 
         let path = self.next_migration_filename("drop_column")?;
-        fs::write(&path, serde_json::to_string_pretty(&json).unwrap())
-            .map_err(|e| format!("Failed to write migration: {}", e))?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
 
-        // println!("📝 Generated drop_column migration: {}", path.display());
         Ok(())
     }
     pub fn generate_delete_table_migration(
         &self,
         table: &str,
-    ) -> Result<(), String> {
+    ) -> Result<()> {
         let json = serde_json::json!({
         "operation": "delete_table",
         "table": table
     });
 
         let path = self.next_migration_filename("delete_table")?;
-        fs::write(&path, serde_json::to_string_pretty(&json).unwrap())
-            .map_err(|e| format!("Failed to write migration: {}", e))?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
 
-        // println!("📝 Generated delete_table migration: {}", path.display());
         Ok(())
     }
 
-    pub fn apply_migrations(&self) -> Result<(), String> {
+    pub fn generate_add_index_migration(
+        &self,
+        name: &str,
+        table: &str,
+        field: &str,
+        unique: bool,
+    ) -> Result<()> {
+        let json = serde_json::json!({
+        "operation": "add_index",
+        "table": table,
+        "name": name,
+        "field": field,
+        "unique": unique
+    });
+
+        let path = self.next_migration_filename("add_index")?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
+
+        Ok(())
+    }
+
+    pub fn generate_drop_index_migration(
+        &self,
+        name: &str,
+        table: &str,
+    ) -> Result<()> {
+        let json = serde_json::json!({
+        "operation": "drop_index",
+        "table": table,
+        "name": name
+    });
+
+        let path = self.next_migration_filename("drop_index")?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
+
+        Ok(())
+    }
+
+    pub fn generate_reshard_migration(
+        &self,
+        table: &str,
+        digits: usize,
+    ) -> Result<()> {
+        let json = serde_json::json!({
+        "operation": "reshard",
+        "table": table,
+        "digits": digits
+    });
+
+        let path = self.next_migration_filename("reshard")?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
+
+        Ok(())
+    }
+
+    /// Applies every migration in `migrations/` that hasn't been applied yet,
+    /// in file name order. The SHA-256 of each applied file is kept in
+    /// `migrations/.migrations_checksums`; if an applied file has since been
+    /// edited, this fails with `Error::Migration` before applying anything.
+    /// Each migration is recorded as soon as it is applied, so when one fails,
+    /// those before it stay applied and a rerun resumes at the failed one.
+    ///
+    /// A pending migration whose name sorts before the latest applied one
+    /// (say, one merged from a branch after later migrations were applied) is
+    /// still applied, with a warning logged. See `apply_migrations_strict`.
+    pub fn apply_migrations(&self) -> Result<()> {
+        self.run_migrations(false)
+    }
+
+    /// `apply_migrations`, but failing with `Error::Migration` before applying
+    /// anything if a pending migration sorts before the latest applied one.
+    pub fn apply_migrations_strict(&self) -> Result<()> {
+        self.run_migrations(true)
+    }
+
+    fn run_migrations(&self, strict: bool) -> Result<()> {
+        self.check_writable()?;
         let mut applied = HashSet::new();
         let mut applied_path = PathBuf::from(&self.path);
         applied_path.push("migrations/.migrations_applied");
 
         // Load applied migrations
         if applied_path.exists() {
-            let content = fs::read_to_string(&applied_path)
-                .map_err(|e| format!("Failed to read applied migrations: {}", e))?;
-            let parsed: Vec<String> = serde_json::from_str(&content)
-                .map_err(|e| format!("Failed to parse .migrations_applied: {}", e))?;
+            let content = fs::read_to_string(&applied_path)?;
+            let parsed: Vec<String> = serde_json::from_str(&content)?;
             applied.extend(parsed);
         }
 
+        let checksums_path = PathBuf::from(&self.path).join("migrations/.migrations_checksums");
+        let mut checksums: BTreeMap<String, String> = if checksums_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&checksums_path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        let mut checksums_changed = false;
+
         // List migration files
         let mut migrations_path = PathBuf::from(&self.path);
         migrations_path.push("migrations");
 
-        let mut migrations: Vec<_> = fs::read_dir(&migrations_path)
-            .map_err(|e| format!("Failed to read migrations dir: {}", e))?
+        let mut migrations: Vec<_> = fs::read_dir(&migrations_path)?
             .filter_map(Result::ok)
             .filter(|e| e.path().extension().map(|s| s == "json").unwrap_or(false))
             .collect();
 
         migrations.sort_by_key(|e| e.path());
 
-        let mut newly_applied = vec![];
+        // Check every applied file before touching anything. Files applied
+        // before checksums were kept are trusted as they are now.
+        for entry in &migrations {
+            let file_name = entry.file_name().into_string().unwrap();
+            if !applied.contains(&file_name) {
+                continue;
+            }
+            let checksum = migration_checksum(&fs::read(entry.path())?);
+            match checksums.get(&file_name) {
+                Some(recorded) if *recorded != checksum => {
+                    return Err(Error::Migration(format!(
+                        "applied migration {} has been modified since it was applied",
+                        file_name
+                    )));
+                }
+                Some(_) => {}
+                None => {
+                    checksums.insert(file_name, checksum);
+                    checksums_changed = true;
+                }
+            }
+        }
+
+        let latest_applied = migrations
+            .iter()
+            .map(|e| e.file_name().into_string().unwrap())
+            .filter(|name| applied.contains(name))
+            .max();
+        if let Some(latest) = latest_applied {
+            let out_of_order: Vec<String> = migrations
+                .iter()
+                .map(|e| e.file_name().into_string().unwrap())
+                .filter(|name| !applied.contains(name) && *name < latest)
+                .collect();
+            if !out_of_order.is_empty() {
+                let message = format!(
+                    "migrations {} sort before the already applied {}",
+                    out_of_order.join(", "),
+                    latest
+                );
+                if strict {
+                    return Err(Error::Migration(message));
+                }
+                warn!("applying out of order: {}", message);
+            }
+        }
 
         for entry in migrations {
             let file_name = entry.file_name().into_string().unwrap();
@@ -155,43 +415,131 @@ impl DATABASE {
             }
 
             let path = entry.path();
-            let content = fs::read_to_string(&path)
-                .map_err(|e| format!("Failed to read migration {}: {}", file_name, e))?;
+            let content = fs::read_to_string(&path)?;
+            let json: Value = serde_json::from_str(&content)?;
+
+            #[cfg(feature = "tracing")]
+            let _span = tracing::info_span!("migration", file = %file_name).entered();
+
+            self.apply_migration_atomically(&json)?;
+            info!("applied migration {}", file_name);
+
+            // Record each migration as soon as it is in, so one failing later
+            // in the run doesn't leave it to be applied a second time.
+            checksums.insert(file_name.clone(), migration_checksum(content.as_bytes()));
+            applied.insert(file_name);
+            let updated: Vec<_> = applied.iter().collect();
+            fs::write(&applied_path, serde_json::to_string_pretty(&updated)?)?;
+            fs::write(&checksums_path, serde_json::to_string_pretty(&checksums)?)?;
+            checksums_changed = false;
+        }
 
-            let json: Value = serde_json::from_str(&content)
-                .map_err(|e| format!("Invalid JSON in {}: {}", file_name, e))?;
+        if checksums_changed {
+            fs::write(checksums_path, serde_json::to_string_pretty(&checksums)?)?;
+        }
 
-            self.apply_migration(&json)?; // corrected to pass by reference
+        Ok(())
+    }
 
-            newly_applied.push(file_name);
+    /// The applied migrations, sorted by file name (the order
+    /// `apply_migrations` runs them in), each with the time encoded in its
+    /// name by `next_migration_filename`. The time is `None` for files named
+    /// some other way.
+    pub fn applied_migrations(&self) -> Result<Vec<(String, Option<DateTime<Utc>>)>> {
+        let path = PathBuf::from(&self.path).join("migrations/.migrations_applied");
+        if !path.exists() {
+            return Ok(vec![]);
         }
+        let mut applied: Vec<String> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        applied.sort();
+
+        Ok(applied
+            .into_iter()
+            .map(|name| {
+                let timestamp = name
+                    .split('_')
+                    .next()
+                    .and_then(|prefix| NaiveDateTime::parse_from_str(prefix, "%Y%m%d%H%M%S%.f").ok())
+                    .map(|time| time.and_utc());
+                (name, timestamp)
+            })
+            .collect())
+    }
 
-        // Save updated .migrations_applied
-        if !newly_applied.is_empty() {
-            applied.extend(newly_applied.clone());
-            let updated: Vec<_> = applied.into_iter().collect();
-            let content = serde_json::to_string_pretty(&updated).unwrap();
-            fs::write(applied_path, content)
-                .map_err(|e| format!("Failed to write applied list: {}", e))?;
+    /// Runs `apply_migration` against a copy of the files it can touch (the
+    /// table directories and schema files it names) and puts that copy back if
+    /// the migration fails, so a migration is either fully applied or not at all.
+    fn apply_migration_atomically(&self, migration: &Value) -> Result<()> {
+        let mut tables = vec![];
+        for key in ["table", "new_table"] {
+            if let Some(table) = migration[key].as_str() {
+                tables.push(table.to_string());
+            }
         }
 
-        Ok(())
+        // every table the migration names stays locked until it is applied or
+        // rolled back, taken in name order so two migrations can't deadlock
+        let mut names = tables.clone();
+        names.sort();
+        names.dedup();
+        let locks: Vec<_> = names.iter().map(|table| self.table_lock(table)).collect();
+        let _guards: Vec<_> = locks.iter().map(|lock| lock.write().unwrap()).collect();
+
+        let mut migrations_path = PathBuf::from(&self.path);
+        migrations_path.push("migrations");
+        let snapshot = tempfile::Builder::new()
+            .prefix(".snapshot")
+            .tempdir_in(&migrations_path)?;
+
+        let root = PathBuf::from(&self.path);
+        let mut saved = vec![];
+        for (i, table) in tables.iter().enumerate() {
+            for (j, original) in [root.join(table), root.join(format!("{}-type.txt", table))]
+                .into_iter()
+                .enumerate()
+            {
+                let backup = snapshot.path().join(format!("{}-{}", i, j));
+                let existed = original.exists();
+                if existed {
+                    copy_path(&original, &backup)?;
+                }
+                saved.push((original, backup, existed));
+            }
+        }
+
+        let result = self.apply_migration(migration);
+        // the migration may have rewritten any schema it names, or failed halfway
+        self.clear_schema_cache();
+
+        if result.is_err() {
+            for (original, backup, existed) in saved {
+                if original.is_dir() {
+                    fs::remove_dir_all(&original)?;
+                } else if original.exists() {
+                    fs::remove_file(&original)?;
+                }
+                if existed {
+                    copy_path(&backup, &original)?;
+                }
+            }
+        }
+
+        result
     }
 
-    fn apply_migration(&self, migration: &Value) -> Result<(), String> {
-        let op = migration["operation"].as_str().ok_or("Missing 'operation' field")?;
-        let table = migration["table"].as_str().ok_or("Missing 'table' field")?;
-        // println!("{}", migration);
+    fn apply_migration(&self, migration: &Value) -> Result<()> {
+        let op = migration["operation"].as_str().ok_or_else(|| malformed("Missing 'operation' field"))?;
+        let table = migration["table"].as_str().ok_or_else(|| malformed("Missing 'table' field"))?;
         match op {
             "create_table" => {
                 let id_column = migration["id_column"]
                     .as_str()
-                    .ok_or("Missing 'id_column'")?
+                    .ok_or_else(|| malformed("Missing 'id_column'"))?
                     .to_string();
 
                 let fields_obj = migration["fields"]
                     .as_object()
-                    .ok_or("Missing or invalid 'fields' object")?;
+                    .ok_or_else(|| malformed("Missing or invalid 'fields' object"))?;
 
                 let mut fields = HashMap::new();
 
@@ -207,8 +555,8 @@ impl DATABASE {
                     //     ));
                     // }
 
-                    // let type_str = arr[0].as_str().ok_or("Invalid type string")?;
-                    // let metadata_str = arr[1].as_str().ok_or("Invalid metadata string")?;
+                    // let type_str = arr[0].as_str().ok_or_else(|| malformed("Invalid type string"))?;
+                    // let metadata_str = arr[1].as_str().ok_or_else(|| malformed("Invalid metadata string"))?;
                     let type_str = arr.clone().replace("\"","");
                     let metadata_str = "";
 
@@ -216,109 +564,95 @@ impl DATABASE {
                         "STRING" => Type::STRING,
                         "NUMBER" => Type::NUMBER,
                         "BOOLEAN" => Type::BOOLEAN,
-                        _ => return Err(format!("Unsupported type '{}'", type_str)),
+                        _ => return Err(Error::Migration(format!("Unsupported type '{}'", type_str))),
                     };
 
                     fields.insert(field.clone(), (parsed_type, metadata_str.to_string()));
                 }
 
-                self.create_table(fields, id_column, table.to_string()).unwrap();
+                self.create_table(fields, id_column, table.to_string())?;
 
                 let mut schema_path = PathBuf::from(&self.path);
                 schema_path.push(format!("{}-type.txt", table));
 
-                let schema_str = fs::read_to_string(&schema_path).unwrap();
-                let mut table: TABLE = serde_json::from_str(&schema_str).unwrap();
+                let schema_str = fs::read_to_string(&schema_path)?;
+                let table: TABLE = serde_json::from_str(&schema_str)?;
                 self.save_schema(&table)?;
-
-                // println!("✔️ Created table '{}'", table.name);
             }
 
             "add_column" => {
-                let field = migration["field"].as_str().ok_or("Missing 'field' field")?;
-                let datatype = migration["datatype"].as_str().ok_or("Missing 'datatype' field")?;
+                let field = migration["field"].as_str().ok_or_else(|| malformed("Missing 'field' field"))?;
+                let datatype = migration["datatype"].as_str().ok_or_else(|| malformed("Missing 'datatype' field"))?;
                 let default = migration["default"].clone();
 
                 let table_path = PathBuf::from(&self.path).join(table);
-                // println!("123 {:?}", table_path);
-                let entries = fs::read_dir(table_path).map_err(|e| e.to_string())?;
+                let entries = shard_files(&table_path)?;
 
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                for path in entries {
+                    let content = fs::read_to_string(&path)?;
                     let mut map: HashMap<String, HashMap<String, (Data, String)>> =
-                        serde_json::from_str(&content).map_err(|e| e.to_string())?;
+                        serde_json::from_str(&content)?;
 
                     for row in map.values_mut() {
                         if !row.contains_key(field) {
                             let data = match &default {
-                                Value::String(s) => Data::STRING(s.clone()),
-                                Value::Number(n) if n.is_f64() => Data::NUMBER(n.as_f64().unwrap()),
-                                Value::Number(n) if n.is_i64() => Data::NUMBER(n.as_i64().unwrap() as f64),
-                                Value::Bool(b) => Data::BOOLEAN(*b),
-                                _ => return Err("Unsupported default value type".into()),
+                                Value::Null => return Err(Error::Migration("Unsupported default value type".into())),
+                                // integer and float defaults both end up as f64
+                                value => Data::from_json_value(value)?,
                             };
 
                             row.insert(field.to_string(), (data, datatype.to_string()));
                         }
                     }
 
-                    let json = serde_json::to_string_pretty(&map).map_err(|e| e.to_string())?;
-                    fs::write(&path, json).map_err(|e| e.to_string())?;
+                    self.save_shard(&path, &map)?;
                 }
                 let mut schema_path = PathBuf::from(&self.path);
                 schema_path.push(format!("{}-type.txt", table));
 
-                let schema_str = fs::read_to_string(&schema_path)
-                    .map_err(|e| format!("Failed to read schema: {}", e))?;
-                let mut table: TABLE = serde_json::from_str(&schema_str)
-                    .map_err(|e| format!("Failed to parse schema: {}", e))?;
+                let schema_str = fs::read_to_string(&schema_path)?;
+                let mut table: TABLE = serde_json::from_str(&schema_str)?;
                 // ✅ Actually mutate the schema here!
-                table.field_names.insert(field.to_string(), (Type::from_string(datatype.to_string()).unwrap(), String::new()));
-                self.save_schema(&table).expect("TODO: panic message");
+                table.field_names.insert(field.to_string(), (Type::from_string(datatype.to_string()).map_err(malformed)?, String::new()));
+                self.save_schema(&table)?;
                 drop(schema_str);
                 // Save updated schema
                 let mut schema_path = PathBuf::from(&self.path);
                 schema_path.push(format!("{}-type.txt", table.name));
 
-                let schema_str = fs::read_to_string(&schema_path)
-                    .map_err(|e| format!("Failed to read schema: {}", e))?;
-                let mut table: TABLE = serde_json::from_str(&schema_str)
-                    .map_err(|e| format!("Failed to parse schema: {}", e))?;
+                let schema_str = fs::read_to_string(&schema_path)?;
+                let mut table: TABLE = serde_json::from_str(&schema_str)?;
 
                 if table.id_column == field {
-                    return Err("Cannot drop the ID field".to_string());
+                    return Err(Error::Migration("Cannot drop the ID field".into()));
                 }
 
                 if table.field_names.remove(field).is_none() {
-                    return Err(format!("Field '{}' not found in table '{}'", field, table.name));
+                    return Err(Error::Migration(format!("Field '{}' not found in table '{}'", field, table.name)));
                 }
-
-                // println!("🧩 Added column '{}' to table '{}'", field, table.name);
             }
 
             "rename_column" => {
-                let table = migration["table"].as_str().ok_or("Missing table name")?;
-                let old_field = migration["old_field"].as_str().ok_or("Missing old_field")?;
-                let new_field = migration["new_field"].as_str().ok_or("Missing new_field")?;
+                let table = migration["table"].as_str().ok_or_else(|| malformed("Missing table name"))?;
+                let old_field = migration["old_field"].as_str().ok_or_else(|| malformed("Missing old_field"))?;
+                let new_field = migration["new_field"].as_str().ok_or_else(|| malformed("Missing new_field"))?;
 
                 let schema_path = PathBuf::from(&self.path).join(format!("{}-type.txt", table));
-                let schema_content = fs::read_to_string(&schema_path).map_err(|e| e.to_string())?;
-                let schema: TABLE = serde_json::from_str(&schema_content).map_err(|e| e.to_string())?;
+                let schema_content = fs::read_to_string(&schema_path)?;
+                let schema: TABLE = serde_json::from_str(&schema_content)?;
 
                 if schema.id_column == old_field {
-                    return Err("Cannot rename the id field of a table".into());
+                    return Err(Error::Migration("Cannot rename the id field of a table".into()));
                 }
 
                 let table_path = PathBuf::from(&self.path).join(table);
-                let entries = fs::read_dir(&table_path).map_err(|e| e.to_string())?;
+                let entries = shard_files(&table_path)?;
 
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                for path in entries {
+                    let content = fs::read_to_string(&path)?;
 
                     let mut map: HashMap<String, HashMap<String, (Data, String)>> =
-                        serde_json::from_str(&content).map_err(|e| e.to_string())?;
+                        serde_json::from_str(&content)?;
 
                     for row in map.values_mut() {
                         if let Some(value) = row.remove(old_field) {
@@ -326,117 +660,205 @@ impl DATABASE {
                         }
                     }
 
-                    let json = serde_json::to_string_pretty(&map).map_err(|e| e.to_string())?;
-                    fs::write(&path, json).map_err(|e| e.to_string())?;
+                    self.save_shard(&path, &map)?;
                 }
-                let schema_str = fs::read_to_string(&schema_path)
-                    .map_err(|e| format!("Failed to read schema: {}", e))?;
-                let mut table: TABLE = serde_json::from_str(&schema_str)
-                    .map_err(|e| format!("Failed to parse schema: {}", e))?;
+                let schema_str = fs::read_to_string(&schema_path)?;
+                let mut table: TABLE = serde_json::from_str(&schema_str)?;
 
                 if let Some((ty, description)) = table.field_names.remove(old_field) {
                     table.field_names.insert(new_field.to_string(), (ty, description));
+                    if let Some(default) = table.defaults.remove(old_field) {
+                        table.defaults.insert(new_field.to_string(), default);
+                    }
                 } else {
-                    return Err(format!("Field '{}' does not exist in table '{}'", old_field, table.name));
+                    return Err(Error::Migration(format!("Field '{}' does not exist in table '{}'", old_field, table.name)));
                 }
 
                 if table.id_column == old_field {
                     table.id_column = new_field.to_string();
                 };
+                // indexes and unique constraints (a unique index's among them) follow the field
+                for index in table.indexes.values_mut() {
+                    if index.field == old_field {
+                        index.field = new_field.to_string();
+                    }
+                }
+                for fields in table.unique.values_mut() {
+                    for unique_field in fields.iter_mut().filter(|f| *f == old_field) {
+                        *unique_field = new_field.to_string();
+                    }
+                }
 
-                self.save_schema(&table);
+                self.save_schema(&table)?;
             }
 
             "drop_column" => {
-                let table = migration["table"].as_str().ok_or("Missing table name")?;
-                let field = migration["field"].as_str().ok_or("Missing field name")?;
+                let table = migration["table"].as_str().ok_or_else(|| malformed("Missing table name"))?;
+                let field = migration["field"].as_str().ok_or_else(|| malformed("Missing field name"))?;
 
                 let table_path = PathBuf::from(&self.path).join(table);
-                let entries = fs::read_dir(&table_path).map_err(|e| e.to_string())?;
+                let entries = shard_files(&table_path)?;
 
                 let schema_path = PathBuf::from(&self.path).join(format!("{}-type.txt", table));
-                let schema_content = fs::read_to_string(&schema_path).map_err(|e| e.to_string())?;
-                let schema: TABLE = serde_json::from_str(&schema_content).map_err(|e| e.to_string())?;
+                let schema_content = fs::read_to_string(&schema_path)?;
+                let schema: TABLE = serde_json::from_str(&schema_content)?;
 
                 if schema.id_column == field {
-                    return Err("Cannot drop the id field of a table".into());
+                    return Err(Error::Migration("Cannot drop the id field of a table".into()));
                 }
 
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                for path in entries {
+                    let content = fs::read_to_string(&path)?;
 
                     let mut map: HashMap<String, HashMap<String, (Data, String)>> =
-                        serde_json::from_str(&content).map_err(|e| e.to_string())?;
+                        serde_json::from_str(&content)?;
 
                     for row in map.values_mut() {
                         row.remove(field);
                     }
 
-                    let json = serde_json::to_string_pretty(&map).map_err(|e| e.to_string())?;
-                    fs::write(&path, json).map_err(|e| e.to_string())?;
+                    self.save_shard(&path, &map)?;
                 }
 
-                // println!("❌ Dropped column '{}' from table '{}'", field, table);
                 let mut schema_path = PathBuf::from(&self.path);
                 schema_path.push(format!("{}-type.txt", table));
 
-                let schema_str = fs::read_to_string(&schema_path).unwrap();
-                let mut table: TABLE = serde_json::from_str(&schema_str).unwrap();
+                let schema_str = fs::read_to_string(&schema_path)?;
+                let mut table: TABLE = serde_json::from_str(&schema_str)?;
                 let x = table.field_names.remove(field);
                 if x.is_none() {
-                    return Err(format!("Field '{}' not found in table '{}'", field, table.name));
+                    return Err(Error::Migration(format!("Field '{}' not found in table '{}'", field, table.name)));
                 }
+                table.defaults.remove(field);
+                // indexes on the field go with it, as do unique constraints
+                // covering it (a unique index's among them)
+                self.drop_index_files(&table.name);
+                table.indexes.retain(|_, index| index.field != field);
+                table.unique.retain(|_, fields| !fields.iter().any(|f| f == field));
                 self.save_schema(&table)?;
             }
 
+            "change_column_type" => {
+                let field = migration["field"].as_str().ok_or_else(|| malformed("Missing 'field' field"))?;
+                let datatype = migration["datatype"].as_str().ok_or_else(|| malformed("Missing 'datatype' field"))?;
+                let new_type = Type::from_string(datatype.to_string()).map_err(malformed)?;
+
+                let mut schema = Self::get_type_file(table.to_string(), self.path.clone());
+                if schema.id_column == field {
+                    return Err(Error::Migration("Cannot change the type of the id column".into()));
+                }
+                if !schema.field_names.contains_key(field) {
+                    return Err(Error::Migration(format!("Field '{}' not found in table '{}'", field, table)));
+                }
+
+                let default = match &migration["default"] {
+                    Value::Null => None,
+                    value => {
+                        let data = Data::from_json_value(value)?;
+                        Some(data.coerce(&new_type).ok_or_else(|| {
+                            Error::Migration(format!("Default {} does not fit type {}", value, datatype))
+                        })?)
+                    }
+                };
+
+                let table_path = PathBuf::from(&self.path).join(table);
+                let entries = shard_files(&table_path)?;
+
+                for path in entries {
+                    let content = fs::read_to_string(&path)?;
+                    let mut map: HashMap<String, HashMap<String, (Data, String)>> =
+                        serde_json::from_str(&content)?;
+
+                    for (key, row) in map.iter_mut() {
+                        let Some((value, _)) = row.get_mut(field) else {
+                            continue;
+                        };
+                        match value.coerce(&new_type).or_else(|| default.clone()) {
+                            Some(converted) => *value = converted,
+                            None => {
+                                let id = match row.get(&schema.id_column) {
+                                    Some((id, _)) if !id.is_null() => id.to_string(),
+                                    _ => key.clone(),
+                                };
+                                return Err(Error::Migration(format!(
+                                    "Cannot convert field '{}' of row '{}' to {}",
+                                    field, id, datatype
+                                )));
+                            }
+                        }
+                    }
+
+                    self.save_shard(&path, &map)?;
+                }
+
+                schema.field_names.get_mut(field).unwrap().0 = new_type;
+                self.save_schema(&schema)?;
+            }
+
+            "rename_table" => {
+                let new_table = migration["new_table"].as_str().ok_or_else(|| malformed("Missing new_table"))?;
+                self.rename_table(table, new_table)?;
+            }
+
             "delete_table" => {
-                let table = migration["table"].as_str().ok_or("Missing table name")?;
+                let table = migration["table"].as_str().ok_or_else(|| malformed("Missing table name"))?;
                 let table_path = PathBuf::from(&self.path).join(table);
 
                 if table_path.exists() {
-                    fs::remove_dir_all(&table_path).map_err(|e| e.to_string())?;
-                    // println!("🗑️ Deleted table '{}'", table);
-                } else {
-                    // println!("⚠️ Table '{}' does not exist", table);
+                    fs::remove_dir_all(&table_path)?;
                 }
                 let mut schema_path = PathBuf::from(&self.path);
                 schema_path.push(format!("{}-type.txt", table));
 
-                let schema_str = fs::read_to_string(&schema_path).unwrap();
-                let mut table: TABLE = serde_json::from_str(&schema_str).unwrap();
+                let schema_str = fs::read_to_string(&schema_path)?;
+                let table: TABLE = serde_json::from_str(&schema_str)?;
                 self.save_schema(&table)?;
             }
 
-            _ => return Err(format!("Unsupported operation: {}", op)),
+            "add_index" => {
+                let name = migration["name"].as_str().ok_or_else(|| malformed("Missing 'name' field"))?;
+                let field = migration["field"].as_str().ok_or_else(|| malformed("Missing 'field' field"))?;
+                let unique = migration["unique"].as_bool().unwrap_or(false);
+                self.add_index(table, name, field, unique)?;
+            }
+
+            "drop_index" => {
+                let name = migration["name"].as_str().ok_or_else(|| malformed("Missing 'name' field"))?;
+                self.drop_index(table, name)?;
+            }
+
+            "reshard" => {
+                let digits = migration["digits"].as_u64().ok_or_else(|| malformed("Missing 'digits' field"))?;
+                self.reshard_locked(table, digits as usize)?;
+            }
+
+            _ => return Err(Error::Migration(format!("Unsupported operation: {}", op))),
         }
 
         Ok(())
     }
 
-    fn save_schema(&self, table: &TABLE) -> Result<(), String> {
+    fn save_schema(&self, table: &TABLE) -> Result<()> {
         let mut path = PathBuf::from(&self.path);
         path.push(format!("{}-type.txt", table.name));
-        fs::write(&path, serde_json::to_string_pretty(&table).unwrap())
-            .map_err(|e| e.to_string())
+        fs::write(&path, serde_json::to_string(&table)?)?;
+        Ok(())
     }
 
 
-    pub fn create_migration(&self, name: &str, content: &serde_json::Value) -> Result<(), String> {
+    pub fn create_migration(&self, name: &str, content: &serde_json::Value) -> Result<()> {
+        self.check_writable()?;
         let mut migrations_path = PathBuf::from(&self.path);
         migrations_path.push("migrations");
-        fs::create_dir_all(&migrations_path)
-            .map_err(|e| format!("Failed to create migrations directory: {}", e))?;
+        fs::create_dir_all(&migrations_path)?;
 
         // Determine next migration number
         let mut max_number = 0;
-        for entry in fs::read_dir(&migrations_path).map_err(|e| e.to_string())? {
-            if let Ok(entry) = entry {
-                if let Some(filename) = entry.file_name().to_str() {
-                    if let Some(number) = filename.split('_').next() {
-                        if let Ok(num) = number.parse::<u32>() {
-                            max_number = max_number.max(num);
-                        }
+        for entry in fs::read_dir(&migrations_path)?.flatten() {
+            if let Some(filename) = entry.file_name().to_str() {
+                if let Some(number) = filename.split('_').next() {
+                    if let Ok(num) = number.parse::<u32>() {
+                        max_number = max_number.max(num);
                     }
                 }
             }
@@ -449,50 +871,21 @@ impl DATABASE {
         let mut file_path = migrations_path.clone();
         file_path.push(&filename);
 
-        // println!("{:?}", file_path);
-        // println!("{:?}", filename);
-        // println!("{:?}", name);
-        // println!("{:?}", next_number);
-        // println!("{:?}", safe_name);
-        File::create(&name).unwrap();
-        let json_string = serde_json::to_string_pretty(content)
-            .map_err(|e| format!("Failed to serialize migration JSON: {}", e))?;
+        File::create(name)?;
+        let json_string = serde_json::to_string_pretty(content)?;
 
-        fs::write(&name, json_string)
-            .map_err(|e| format!("Failed to write migration file: {}", e))?;
+        fs::write(name, json_string)?;
 
-        // println!("✅ Created migration: {}", filename);
         Ok(())
     }
 
-    fn get_applied_migrations(&self) -> Result<Vec<String>, String> {
-        let mut path = PathBuf::from(&self.path);
-        path.push("dbfiles/migrations/.migrations_applied");
-
-        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
-        Ok(contents.lines().map(|s| s.trim().to_string()).collect())
-    }
-
-    fn mark_migration_applied(&self, file_name: &str) -> Result<(), String> {
-        let mut path = PathBuf::from(&self.path);
-        path.push("migrations/.migrations_applied");
-
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path)
-            .map_err(|e| e.to_string())?;
-
-        writeln!(file, "{}", file_name).map_err(|e| e.to_string())
-    }
-
     pub fn generate_create_table_migration(
         &self,
         name: &str,
         table_name: &str,
         id_column: &str,
         fields: Vec<(&str, &str)>,
-    ) -> Result<(), String> {
+    ) -> Result<()> {
         let mut field_map = serde_json::Map::new();
         for (key, val) in fields {
             field_map.insert(key.to_string(), Value::String(val.to_string()));
@@ -506,8 +899,7 @@ impl DATABASE {
     });
 
         let filename = self.next_migration_filename(name)?;
-        // println!("qwe {:?}", filename);
-        self.create_migration(&filename.to_str().unwrap(), &content)
+        self.create_migration(filename.to_str().unwrap(), &content)
     }
 
     pub fn generate_add_column_migration(
@@ -517,7 +909,7 @@ impl DATABASE {
         field: &str,
         datatype: &str,
         default: Option<Value>,
-    ) -> Result<(), String> {
+    ) -> Result<()> {
         let mut content = json!({
         "operation": "add_column",
         "table": table,
@@ -530,9 +922,39 @@ impl DATABASE {
         }
 
         let filename = self.next_migration_filename(name)?;
-        self.create_migration(&filename.to_str().unwrap(), &content)
+        self.create_migration(filename.to_str().unwrap(), &content)
+    }
+
+    /// Converts every value of `field` to `datatype` (see `Data::coerce`).
+    /// Rows whose value can't be converted get `default` if one is given,
+    /// otherwise the migration fails naming the row's id.
+    pub fn generate_change_column_type_migration(
+        &self,
+        table: &str,
+        field: &str,
+        datatype: &str,
+        default: Option<Value>,
+    ) -> Result<()> {
+        let mut json = serde_json::json!({
+        "operation": "change_column_type",
+        "table": table,
+        "field": field,
+        "datatype": datatype
+    });
+
+        if let Some(def) = default {
+            json["default"] = def;
+        }
+
+        let path = self.next_migration_filename("change_column_type")?;
+        fs::write(&path, serde_json::to_string_pretty(&json)?)?;
+
+        Ok(())
     }
 
+    /// Merges `new_row` into the rows whose `fieldname` matches `fieldvalue`
+    /// under `cmp`, or with `multi` false into the first one. Returns the
+    /// merged row (the last one, with `multi`), or `None` if nothing matched.
     pub fn update_row_where(
         &self,
         tablename: String,
@@ -541,54 +963,52 @@ impl DATABASE {
         new_row: HashMap<String, (Data, String)>,
         multi: bool,
         cmp: CMP,
-    ) -> Option<HashMap<String, (Data, String)>> {
+    ) -> Result<Option<Row>> {
+        self.check_writable()?;
         let mut path = PathBuf::from(&self.path);
         path.push(&tablename);
-        let table_type = Self::get_type_file(tablename.clone(), self.path.clone());
+        let table_type = self.get_schema(&tablename)?;
+        Self::validate_fields(&new_row, &table_type)?;
 
-        if table_type.id_column == fieldname {
-            return self.update_row_by_id(tablename, fieldvalue.get_string(), new_row);
+        if table_type.id_column == fieldname && Self::is_id_lookup(&fieldvalue, &cmp) {
+            return self.update_row_by_id(tablename, fieldvalue.to_string(), new_row);
         }
 
-        let ents = fs::read_dir(path).ok()?;
-        for x in ents {
-            let entry = x.ok()?.path();
-            let data_str = fs::read_to_string(&entry).ok()?;
-            let mut deser: HashMap<String, HashMap<String, (Data, String)>> =
-                serde_json::from_str(&data_str).ok()?;
-
-            for (key, mut record) in deser.clone() {
-                if let Some((value, _)) = record.get(&fieldname) {
-                    if cmp.clone().calculate(fieldvalue.clone(), value.clone()) {
-                        // Merge new_row into existing record
-                        for (k, v) in new_row.iter() {
-                            record.insert(k.clone(), v.clone());
-                        }
-
-                        // Replace the row with the merged record
-                        deser.insert(key.clone(), record.clone());
-
-                        let seri = serde_json::to_string(&deser).ok()?;
-                        let filename = Self::get_file_by_id(key.clone());
-                        let mut new_path = PathBuf::from(&self.path);
-                        new_path.push(&tablename);
-                        new_path.push(filename);
-                        if !new_path.exists() {
-                            return None;
-                        }
-                        fs::write(new_path, seri).ok()?;
+        let lock = self.table_lock(&tablename);
+        let _guard = lock.write().unwrap();
+        let mut updated = None;
+        let mut changed = vec![];
+        for entry in shard_files(&path)? {
+            let mut deser = self.load_shard::<Row>(&entry)?;
+            let keys = Self::matching_keys(&deser, &fieldname, &fieldvalue, &cmp, multi);
+            if keys.is_empty() {
+                continue;
+            }
 
-                        if !multi {
-                            return Some(record);
-                        }
-                    }
+            for key in &keys {
+                // Merge new_row into existing record
+                let record = deser.get_mut(key).unwrap();
+                for (k, v) in new_row.iter() {
+                    record.insert(k.clone(), v.clone());
                 }
+                table_type.stamp_timestamps(record);
+                updated = Some(record.clone());
+            }
+            changed.push((entry, deser, keys));
+
+            if !multi {
+                break;
             }
         }
 
-        Some(new_row)
+        self.save_updated(&tablename, &table_type, changed)?;
+        Ok(updated)
     }
 
+    /// Sets `field_to_change` on the rows whose `fieldname` matches
+    /// `fieldvalue` under `cmp`, or with `multi` false on the first one.
+    /// Returns the new value, or `None` if nothing matched.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_field_where(
         &self,
         tablename: String,
@@ -598,54 +1018,88 @@ impl DATABASE {
         new_field_val: (Data, String),
         multi: bool,
         cmp: CMP,
-    ) -> Option<(Data, String)> {
+    ) -> Result<Option<(Data, String)>> {
+        self.check_writable()?;
         let mut path = PathBuf::from(&self.path);
         path.push(&tablename);
-        let table_type = Self::get_type_file(tablename.clone(), self.path.clone());
+        let table_type = self.get_schema(&tablename)?;
+        let change = HashMap::from([(field_to_change.clone(), new_field_val.clone())]);
+        Self::validate_fields(&change, &table_type)?;
 
-        if table_type.id_column == field_to_change {
+        if table_type.id_column == fieldname && Self::is_id_lookup(&fieldvalue, &cmp) {
             return self.update_field_by_id(
                 tablename,
-                fieldvalue.get_string(),
-                fieldname,
+                fieldvalue.to_string(),
+                field_to_change,
                 new_field_val,
             );
         }
 
-        let ents = fs::read_dir(path).ok()?;
-        for x in ents {
-            let t = x.ok()?.path();
-            let data_str = fs::read_to_string(&t).ok()?;
-            let mut deser: HashMap<String, HashMap<String, (Data, String)>> =
-                serde_json::from_str(&data_str).ok()?;
-
-            for (id, mut record) in deser.clone() {
-                if let Some((val, _)) = record.get(&fieldname) {
-                    if cmp.clone().calculate(fieldvalue.clone(), val.clone()) {
-                        deser
-                            .get_mut(&id)
-                            .unwrap()
-                            .insert(field_to_change.clone(), new_field_val.clone());
-                        let seri = serde_json::to_string(&deser).ok()?;
-
-                        let filename = Self::get_file_by_id(id.clone());
-                        let mut new_path = PathBuf::from(&self.path);
-                        new_path.push(&tablename);
-                        new_path.push(filename);
-                        if !new_path.exists() {
-                            return None;
-                        }
-                        fs::write(new_path, seri).ok()?;
+        let lock = self.table_lock(&tablename);
+        let _guard = lock.write().unwrap();
+        let mut changed = vec![];
+        for t in shard_files(&path)? {
+            let mut deser = self.load_shard::<Row>(&t)?;
+            let keys = Self::matching_keys(&deser, &fieldname, &fieldvalue, &cmp, multi);
+            if keys.is_empty() {
+                continue;
+            }
 
-                        if !multi {
-                            return Some(new_field_val);
-                        }
-                    }
-                }
+            for key in &keys {
+                let row = deser.get_mut(key).unwrap();
+                row.insert(field_to_change.clone(), new_field_val.clone());
+                table_type.stamp_timestamps(row);
+            }
+            changed.push((t, deser, keys));
+
+            if !multi {
+                break;
             }
         }
 
-        Some(new_field_val)
+        let matched = !changed.is_empty();
+        self.save_updated(&tablename, &table_type, changed)?;
+        Ok(matched.then_some(new_field_val))
+    }
+
+    /// Writes back the shards an update changed, given as (path, rows, keys of
+    /// the changed rows), once every changed row has passed the table's
+    /// unique constraints and foreign keys. The caller holds the table's
+    /// write lock.
+    fn save_updated(&self, tablename: &str, table_type: &TABLE, changed: Vec<(PathBuf, HashMap<String, Row>, Vec<String>)>) -> Result<()> {
+        let rows: Vec<(&String, &Row)> = changed
+            .iter()
+            .flat_map(|(_, shard, keys)| keys.iter().map(move |key| (key, &shard[key])))
+            .collect();
+        self.check_constraints(tablename, table_type, &rows)?;
+
+        for (path, shard, keys) in &changed {
+            self.save_shard(path, shard)?;
+            bump(&self.counters.rows_updated, keys.len());
+        }
+        Ok(())
+    }
+
+    /// The keys of the rows in `shard` whose `fieldname` matches `fieldvalue`
+    /// under `cmp`, in order; with `multi` false only the first of them.
+    fn matching_keys(shard: &HashMap<String, Row>, fieldname: &str, fieldvalue: &Data, cmp: &CMP, multi: bool) -> Vec<String> {
+        let mut keys: Vec<String> = shard
+            .iter()
+            .filter(|(_, row)| row.get(fieldname).is_some_and(|(val, _)| cmp.calculate(fieldvalue, val)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        if !multi {
+            keys.truncate(1);
+        }
+        keys
+    }
+
+    /// A predicate on the id column can go straight to the row's shard only
+    /// for an equality match on a value that can be hashed as an id; anything
+    /// else has to scan.
+    fn is_id_lookup(fieldvalue: &Data, cmp: &CMP) -> bool {
+        matches!(cmp, CMP::EQUAL) && matches!(fieldvalue, Data::STRING(_) | Data::NUMBER(_))
     }
 
     pub fn update_row_by_id(
@@ -653,14 +1107,26 @@ impl DATABASE {
         tablename: String,
         id_: String,
         new_row: HashMap<String, (Data, String)>,
-    ) -> Option<HashMap<String, (Data, String)>> {
-        let mut row = self.get_by_id(tablename.clone(), id_.clone())?;
+    ) -> Result<Option<Row>> {
+        self.check_writable()?;
+        let table_type = self.get_schema(&tablename)?;
+        Self::validate_fields(&new_row, &table_type)?;
+
+        let lock = self.table_lock(&tablename);
+        let guard = lock.write().unwrap();
+        let Some(mut row) = self.get_by_id(tablename.clone(), id_.clone())? else {
+            return Ok(None);
+        };
         for (k, v) in new_row.iter() {
             row.insert(k.clone(), v.clone());
         }
-        self.delete_row_by_id(tablename.clone(), id_.clone());
-        self.add_row(tablename, row.clone(), true);
-        Some(row)
+        let moved = self.replace_row_by_id(&tablename, &id_, row.clone(), &table_type)?;
+        drop(guard);
+
+        if moved {
+            self.delete_by_id(&tablename, &id_)?;
+        }
+        Ok(Some(row))
     }
 
     pub fn update_field_by_id(
@@ -669,12 +1135,151 @@ impl DATABASE {
         id_: String,
         fieldname: String,
         new_value: (Data, String),
-    ) -> Option<(Data, String)> {
-        let mut row = self.get_by_id(tablename.clone(), id_.clone())?;
+    ) -> Result<Option<(Data, String)>> {
+        self.check_writable()?;
+        let table_type = self.get_schema(&tablename)?;
+        let change = HashMap::from([(fieldname.clone(), new_value.clone())]);
+        Self::validate_fields(&change, &table_type)?;
+
+        let lock = self.table_lock(&tablename);
+        let guard = lock.write().unwrap();
+        let Some(mut row) = self.get_by_id(tablename.clone(), id_.clone())? else {
+            return Ok(None);
+        };
         row.insert(fieldname, new_value.clone());
-        self.delete_row_by_id(tablename.clone(), id_);
-        self.add_row(tablename, row, true);
-        Some(new_value)
+        let moved = self.replace_row_by_id(&tablename, &id_, row, &table_type)?;
+        drop(guard);
+
+        if moved {
+            self.delete_by_id(&tablename, &id_)?;
+        }
+        Ok(Some(new_value))
+    }
+
+    /// Finds the first row (in shard and then id order) whose `fieldname`
+    /// matches `fieldvalue` under `cmp`, merges `update` into it, writes it
+    /// back and returns the row as it was before. `Ok(None)` if nothing
+    /// matches. The whole read-modify-write runs under the table's write lock
+    /// and an exclusive lock on its `.lock` file, so two `find_and_update`
+    /// calls, in this process or another, never both see the same row before
+    /// it changes: claiming a job by flipping its status hands it to exactly
+    /// one caller. Other processes' writes don't take the file lock. The
+    /// updated row must pass the table's unique constraints and foreign keys,
+    /// and `update` may not change the id.
+    pub fn find_and_update(
+        &self,
+        tablename: &str,
+        fieldname: &str,
+        fieldvalue: &Data,
+        cmp: CMP,
+        update: Row,
+    ) -> Result<Option<Row>> {
+        self.check_writable()?;
+        let table_type = self.get_schema(tablename)?;
+        Self::validate_fields(&update, &table_type)?;
+        if update.contains_key(&table_type.id_column) {
+            return Err(Error::InvalidArgument(format!(
+                "find_and_update may not change the id column '{}'",
+                table_type.id_column
+            )));
+        }
+
+        let table_dir = PathBuf::from(&self.path).join(tablename);
+        let table_lock = self.table_lock(tablename);
+        let _guard = table_lock.write().unwrap();
+        let lock = File::create(table_dir.join(".lock"))?;
+        lock.lock()?;
+
+        for shard in shard_files(&table_dir)? {
+            let mut rows = self.load_shard::<Row>(&shard)?;
+            let mut keys: Vec<&String> = rows
+                .iter()
+                .filter(|(_, row)| row.get(fieldname).is_some_and(|(val, _)| cmp.calculate(fieldvalue, val)))
+                .map(|(key, _)| key)
+                .collect();
+            keys.sort();
+            let Some(key) = keys.first().map(|key| key.to_string()) else {
+                continue;
+            };
+
+            let record = rows.get_mut(&key).unwrap();
+            let before = record.clone();
+            record.extend(update);
+            table_type.stamp_timestamps(record);
+            self.save_updated(tablename, &table_type, vec![(shard, rows, vec![key])])?;
+            return Ok(Some(before));
+        }
+        Ok(None)
+    }
+
+    /// Writes the merged `row` over the stored one, under the table's write
+    /// lock, which the caller holds. Returns whether the update moved the row
+    /// to a new id; the old row is then the caller's to delete once the lock
+    /// is released, so a rejected write leaves it in place. A `Restrict`
+    /// foreign key into the old id fails the move before anything is written.
+    fn replace_row_by_id(&self, tablename: &str, id_: &str, mut row: Row, table_type: &TABLE) -> Result<bool> {
+        Self::prepare_row(&mut row, table_type)?;
+        let moved = table_type.row_id(&row)? != id_;
+        if moved {
+            self.doomed_rows(tablename, id_)?;
+        }
+        self.insert_row(tablename, table_type, row, true)?;
+        Ok(moved)
+    }
+
+    /// Applies many partial-row updates at once, merging each into the stored
+    /// row like `update_row_by_id`, but reading and writing every affected shard
+    /// only once. Ids that don't exist are skipped. Returns the merged rows by
+    /// id, or an error without writing anything if an update fails validation.
+    pub fn update_many_by_id(
+        &self,
+        tablename: String,
+        updates: HashMap<String, Row>,
+    ) -> Result<HashMap<String, Row>> {
+        self.check_writable()?;
+        let table_type = self.get_schema(&tablename)?;
+
+        let mut by_shard: HashMap<PathBuf, Vec<(String, String, Row)>> = HashMap::new();
+        for (id_, new_row) in updates {
+            Self::validate_fields(&new_row, &table_type)?;
+            let id = Self::string_to_numerical_uuid(&id_);
+            by_shard
+                .entry(self.shard_path(&tablename, &id_))
+                .or_default()
+                .push((id_, id, new_row));
+        }
+
+        let lock = self.table_lock(&tablename);
+        let _guard = lock.write().unwrap();
+        let mut updated = HashMap::new();
+        let mut changed = vec![];
+        for (path, rows) in by_shard {
+            if !path.exists() {
+                continue;
+            }
+
+            let mut deser = self.load_shard::<Row>(&path)?;
+
+            let mut keys = vec![];
+            for (id_, id, new_row) in rows {
+                let Some(record) = deser.get_mut(&id) else {
+                    continue;
+                };
+                for (k, v) in new_row {
+                    record.insert(k, v);
+                }
+                table_type.stamp_timestamps(record);
+                updated.insert(id_, record.clone());
+                keys.push(id);
+            }
+
+            if !keys.is_empty() {
+                changed.push((path, deser, keys));
+            }
+        }
+
+        self.save_updated(&tablename, &table_type, changed)?;
+        Ok(updated)
     }
 
     pub fn get_type_file(table_name: String, path: String) -> TABLE {
@@ -685,3 +1290,791 @@ impl DATABASE {
         serde_json::from_str(&data).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::crud::make::{FieldError, CREATED_AT, UPDATED_AT};
+
+    use super::*;
+
+    fn setup_scores() -> (tempfile::TempDir, DATABASE) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("score".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "scores".to_string()).unwrap();
+
+        for (id, score) in [("a", "42"), ("b", " 7.5"), ("c", "n/a")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("score".to_string(), (Data::STRING(score.to_string()), String::new()));
+            db.add_row("scores".to_string(), row, false).unwrap();
+        }
+
+        (temp_dir, db)
+    }
+
+    #[test]
+    fn test_data_order_agrees_with_equality() {
+        let nan = Data::NUMBER(f64::NAN);
+        assert_eq!(nan, nan);
+        assert_eq!(nan.partial_cmp(&nan), Some(Ordering::Equal));
+        assert_eq!(nan.partial_cmp(&Data::NUMBER(f64::INFINITY)), Some(Ordering::Greater));
+        assert!(CMP::GREATER.calculate(&Data::NUMBER(1.0), &nan));
+        assert_eq!(Data::NUMBER(0.0).partial_cmp(&Data::NUMBER(-0.0)), Some(Ordering::Equal));
+        assert_eq!(
+            Data::NUMBERNULL(Some(f64::NAN)).partial_cmp(&Data::NUMBERNULL(Some(f64::NAN))),
+            Some(Ordering::Equal)
+        );
+        assert!(Data::NUMBERNULL(None) < Data::NUMBERNULL(Some(f64::NAN)));
+        assert_eq!(
+            Data::ARRAY(vec![nan.clone()]).partial_cmp(&Data::ARRAY(vec![nan.clone()])),
+            Some(Ordering::Equal)
+        );
+
+        // maps and sets are only ever equal or unordered
+        let set = |items: &[f64]| Data::HASHSET(items.iter().map(|n| Data::NUMBER(*n)).collect());
+        assert_eq!(set(&[1.0, 2.0]).partial_cmp(&set(&[2.0, 1.0])), Some(Ordering::Equal));
+        assert_eq!(set(&[1.0]).partial_cmp(&set(&[2.0])), None);
+        let map = Data::HASHMAP(HashMap::from([("a".to_string(), nan.clone())]));
+        assert_eq!(map.partial_cmp(&map.clone()), Some(Ordering::Equal));
+        assert_eq!(map.partial_cmp(&Data::HASHMAP(HashMap::new())), None);
+        assert_eq!(nan.partial_cmp(&Data::STRING("NaN".to_string())), None);
+    }
+
+    #[test]
+    fn test_change_column_type_string_to_number() {
+        let (_temp_dir, db) = setup_scores();
+
+        db.generate_change_column_type_migration("scores", "score", "NUMBER", Some(json!(0)))
+            .unwrap();
+        db.apply_migrations().unwrap();
+
+        let score = |id: &str| db.get_by_id("scores".to_string(), id.to_string()).unwrap().unwrap()["score"].0.clone();
+        assert_eq!(score("a"), Data::NUMBER(42.0));
+        assert_eq!(score("b"), Data::NUMBER(7.5));
+        assert_eq!(score("c"), Data::NUMBER(0.0));
+
+        let schema = DATABASE::get_type_file("scores".to_string(), db.path.clone());
+        assert_eq!(schema.field_names["score"].0, Type::NUMBER);
+    }
+
+    #[test]
+    fn test_change_column_type_failure_names_row() {
+        let (_temp_dir, db) = setup_scores();
+
+        db.generate_change_column_type_migration("scores", "score", "NUMBER", None)
+            .unwrap();
+        let err = db.apply_migrations().unwrap_err();
+        assert!(matches!(&err, Error::Migration(msg) if msg.contains("row 'c'")), "{}", err);
+
+        let schema = DATABASE::get_type_file("scores".to_string(), db.path.clone());
+        assert_eq!(schema.field_names["score"].0, Type::STRING);
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "people".to_string()).unwrap();
+        for id in ["a", "b", "c", "d"] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            db.add_row("people".to_string(), row, false).unwrap();
+        }
+
+        // Shards are rewritten in name order, so this one fails after the rest got the column
+        let table_dir = PathBuf::from(&db.path).join("people");
+        fs::write(table_dir.join("zzz.txt"), "{not json").unwrap();
+
+        db.generate_add_column_migration("add_age", "people", "age", "NUMBER", Some(json!(1)))
+            .unwrap();
+        assert!(db.apply_migrations().is_err());
+
+        let rows: HashMap<String, Row> = db.iter_rows("people".to_string()).collect();
+        assert_eq!(rows.len(), 4);
+        assert!(rows.values().all(|row| !row.contains_key("age")));
+
+        let schema = DATABASE::get_type_file("people".to_string(), db.path.clone());
+        assert!(!schema.field_names.contains_key("age"));
+        assert_eq!(fs::read_to_string(table_dir.join("zzz.txt")).unwrap(), "{not json");
+    }
+
+    #[test]
+    fn test_migrations_before_a_failure_stay_applied() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING")])
+            .unwrap();
+        db.generate_add_column_migration("add_age", "nobody", "age", "NUMBER", Some(json!(1)))
+            .unwrap();
+        assert!(db.apply_migrations().is_err());
+
+        let applied = db.applied_migrations().unwrap();
+        assert_eq!(applied.len(), 1);
+        assert!(applied[0].0.ends_with("create_people.json"));
+        assert!(db.get_table("people").is_ok());
+
+        // the rerun retries only the failed migration
+        let err = db.apply_migrations().unwrap_err();
+        assert!(!matches!(err, Error::TableExists(_)), "{}", err);
+        assert_eq!(db.applied_migrations().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rename_and_drop_column_carry_indexes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING"), ("email", "STRING")])
+            .unwrap();
+        db.apply_migrations().unwrap();
+        db.add_index("people", "by_email", "email", true).unwrap();
+        let person = |name: &str, field: &str, email: &str| {
+            let mut row = HashMap::new();
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            row.insert(field.to_string(), (Data::STRING(email.to_string()), String::new()));
+            row
+        };
+        db.add_row("people".to_string(), person("Alice", "email", "a@x.y"), false).unwrap();
+
+        db.generate_rename_column_migration("people", "email", "mail").unwrap();
+        db.apply_migrations().unwrap();
+        let schema = db.get_schema("people").unwrap();
+        assert_eq!(schema.indexes["by_email"].field, "mail");
+        assert_eq!(schema.unique["by_email"], vec!["mail".to_string()]);
+        let found = db.find_by_index("people", "by_email", &Data::STRING("a@x.y".to_string())).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(db.add_row("people".to_string(), person("Bob", "mail", "a@x.y"), false).is_err());
+
+        db.generate_drop_column_migration("people", "mail").unwrap();
+        db.apply_migrations().unwrap();
+        let schema = db.get_schema("people").unwrap();
+        assert!(schema.indexes.is_empty() && schema.unique.is_empty());
+        let mut bob = HashMap::new();
+        bob.insert("name".to_string(), (Data::STRING("Bob".to_string()), String::new()));
+        db.add_row("people".to_string(), bob, false).unwrap();
+        assert!(db.find_by_index("people", "by_email", &Data::STRING("a@x.y".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_rename_table_migration() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING")])
+            .unwrap();
+        db.apply_migrations().unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("name".to_string(), (Data::STRING("Alice".to_string()), String::new()));
+        db.add_row("people".to_string(), row, false).unwrap();
+
+        db.generate_rename_table_migration("people", "persons").unwrap();
+        db.apply_migrations().unwrap();
+
+        assert!(db.get_by_id("persons".to_string(), "Alice".to_string()).unwrap().is_some());
+        assert!(db.get_table("people").is_err());
+    }
+
+    #[test]
+    fn test_edited_applied_migration_is_detected() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING")])
+            .unwrap();
+        db.apply_migrations().unwrap();
+        // re-running with nothing changed is fine
+        db.apply_migrations().unwrap();
+
+        let migrations_dir = temp_dir.path().join("db/migrations");
+        let file = fs::read_dir(&migrations_dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| p.extension().is_some_and(|e| e == "json"))
+            .unwrap();
+        let edited = fs::read_to_string(&file).unwrap().replace("STRING", "NUMBER");
+        fs::write(&file, edited).unwrap();
+
+        db.generate_rename_table_migration("people", "persons").unwrap();
+        let err = db.apply_migrations().unwrap_err();
+        assert!(matches!(&err, Error::Migration(m) if m.contains("modified")), "{:?}", err);
+        // nothing after the check ran
+        assert!(db.get_table("people").is_ok());
+        assert!(db.get_table("persons").is_err());
+    }
+
+    #[test]
+    fn test_out_of_order_migration_is_flagged() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING")])
+            .unwrap();
+        db.apply_migrations().unwrap();
+
+        // a teammate's migration, timestamped before the one applied above
+        let early = json!({"operation": "rename_table", "table": "people", "new_table": "persons"});
+        fs::write(
+            temp_dir.path().join("db/migrations/20000101000000.000000_rename_table.json"),
+            early.to_string(),
+        )
+        .unwrap();
+
+        let err = db.apply_migrations_strict().unwrap_err();
+        assert!(matches!(&err, Error::Migration(m) if m.contains("20000101000000")), "{:?}", err);
+        assert!(db.get_table("people").is_ok());
+
+        db.apply_migrations().unwrap();
+        assert!(db.get_table("persons").is_ok());
+        // once applied it no longer counts as pending
+        db.apply_migrations_strict().unwrap();
+    }
+
+    #[test]
+    fn test_generate_migration_without_migrations_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+        fs::remove_dir_all(temp_dir.path().join("db/migrations")).unwrap();
+
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING")])
+            .unwrap();
+        db.apply_migrations().unwrap();
+        assert!(db.get_table("people").is_ok());
+    }
+
+    #[test]
+    fn test_find_and_update_checks_constraints() {
+        let (_temp_dir, db) = setup_scores();
+        db.add_unique("scores", "unique_score", &["score"]).unwrap();
+        let update = HashMap::from([("score".to_string(), (Data::STRING("42".to_string()), String::new()))]);
+        let err = db
+            .find_and_update("scores", "score", &Data::STRING("n/a".to_string()), CMP::EQUAL, update)
+            .unwrap_err();
+        assert!(matches!(err, Error::UniqueConstraint { .. }));
+        let c = db.get_by_id("scores".to_string(), "c".to_string()).unwrap().unwrap();
+        assert_eq!(c["score"].0, Data::STRING("n/a".to_string()));
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("score_id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "prizes".to_string()).unwrap();
+        db.add_foreign_key("prizes", "score_id", "scores", crate::crud::make::OnDelete::Restrict).unwrap();
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("p1".to_string()), String::new()));
+        row.insert("score_id".to_string(), (Data::STRING("a".to_string()), String::new()));
+        db.add_row("prizes".to_string(), row, false).unwrap();
+
+        let update = HashMap::from([("score_id".to_string(), (Data::STRING("zzz".to_string()), String::new()))]);
+        let err = db
+            .find_and_update("prizes", "id", &Data::STRING("p1".to_string()), CMP::EQUAL, update)
+            .unwrap_err();
+        assert!(matches!(err, Error::ForeignKeyViolation { .. }));
+    }
+
+    #[test]
+    fn test_concurrent_find_and_update_claims_each_row_once() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("owner".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "jobs".to_string()).unwrap();
+        for i in 0..5 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("j{}", i)), String::new()));
+            row.insert("owner".to_string(), (Data::STRING(String::new()), String::new()));
+            db.add_row("jobs".to_string(), row, false).unwrap();
+        }
+
+        let workers: Vec<_> = (0..12)
+            .map(|w| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let claim = HashMap::from([(
+                        "owner".to_string(),
+                        (Data::STRING(format!("w{}", w)), String::new()),
+                    )]);
+                    db.find_and_update("jobs", "owner", &Data::STRING(String::new()), CMP::EQUAL, claim)
+                        .unwrap()
+                        .map(|before| before["id"].0.to_string())
+                })
+            })
+            .collect();
+        let mut claimed: Vec<String> = workers.into_iter().filter_map(|w| w.join().unwrap()).collect();
+        claimed.sort();
+        assert_eq!(claimed, ["j0", "j1", "j2", "j3", "j4"]);
+
+        // every job ended up with exactly one owner
+        for i in 0..5 {
+            let row = db.get_by_id("jobs".to_string(), format!("j{}", i)).unwrap().unwrap();
+            assert_ne!(row["owner"].0, Data::STRING(String::new()));
+        }
+        let nothing_left = db
+            .find_and_update("jobs", "owner", &Data::STRING(String::new()), CMP::EQUAL, HashMap::new())
+            .unwrap();
+        assert!(nothing_left.is_none());
+    }
+
+    #[test]
+    fn test_concurrent_updates_to_one_shard_keep_each_other() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // wide shards: every row lands in the same handful of files
+        let db = crate::crud::builder::DatabaseBuilder::new()
+            .path(temp_dir.path().to_str().unwrap())
+            .shard_digits(9)
+            .build()
+            .unwrap();
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("n".to_string(), (Type::NUMBER, String::new()));
+        fields.insert("seen".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "counters".to_string()).unwrap();
+        for i in 0..8 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("c{}", i)), String::new()));
+            row.insert("n".to_string(), (Data::NUMBER(i as f64), String::new()));
+            row.insert("seen".to_string(), (Data::NUMBER(0.0), String::new()));
+            db.add_row("counters".to_string(), row, false).unwrap();
+        }
+
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    // one update goes by id, the other scans the shards
+                    let seen = (Data::NUMBER(1.0), String::new());
+                    db.update_field_by_id("counters".to_string(), format!("c{}", i), "seen".to_string(), seen)
+                        .unwrap();
+                    let n = (Data::NUMBER(i as f64 + 100.0), String::new());
+                    db.update_field_where(
+                        "counters".to_string(),
+                        "n".to_string(),
+                        Data::NUMBER(i as f64),
+                        "n".to_string(),
+                        n,
+                        false,
+                        CMP::EQUAL,
+                    )
+                    .unwrap();
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        for i in 0..8 {
+            let row = db.get_by_id("counters".to_string(), format!("c{}", i)).unwrap().unwrap();
+            assert_eq!(row["seen"].0, Data::NUMBER(1.0));
+            assert_eq!(row["n"].0, Data::NUMBER(i as f64 + 100.0));
+        }
+    }
+
+    #[test]
+    fn test_applied_migrations_timeline() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+        assert!(db.applied_migrations().unwrap().is_empty());
+
+        let start = Utc::now();
+        db.generate_create_table_migration("create_people", "people", "name", vec![("name", "STRING")])
+            .unwrap();
+        db.generate_rename_table_migration("people", "persons").unwrap();
+        db.apply_migrations().unwrap();
+        // generated after the run above, so still pending
+        db.generate_delete_table_migration("persons").unwrap();
+
+        let applied = db.applied_migrations().unwrap();
+        assert_eq!(applied.len(), 2);
+        assert!(applied[0].0.ends_with("_create_people.json"));
+        assert!(applied[1].0.ends_with("_rename_table.json"));
+
+        let times: Vec<DateTime<Utc>> = applied.iter().map(|(_, time)| time.unwrap()).collect();
+        assert!(times[0] <= times[1]);
+        assert!((times[0] - start).num_seconds().abs() < 60);
+
+        fs::write(temp_dir.path().join("db/migrations/001_manual.json"), "{}").unwrap();
+        let mut names: Vec<String> = applied.into_iter().map(|(name, _)| name).collect();
+        names.push("001_manual.json".to_string());
+        fs::write(
+            temp_dir.path().join("db/migrations/.migrations_applied"),
+            serde_json::to_string(&names).unwrap(),
+        )
+        .unwrap();
+        let applied = db.applied_migrations().unwrap();
+        assert_eq!(applied[0], ("001_manual.json".to_string(), None));
+    }
+
+    #[test]
+    fn test_timestamps_created_fixed_updated_bumped() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table_with_timestamps(fields, "id".to_string(), "users".to_string())
+            .unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("name".to_string(), (Data::STRING("Alice".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        let inserted = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        let created = inserted[CREATED_AT].0.clone().get_number();
+        let updated = inserted[UPDATED_AT].0.clone().get_number();
+        assert_eq!(created, updated);
+
+        sleep(Duration::from_millis(5));
+        db.update_field_by_id(
+            "users".to_string(),
+            "u1".to_string(),
+            "name".to_string(),
+            (Data::STRING("Bob".to_string()), String::new()),
+        ).unwrap()
+        .unwrap();
+
+        let row = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(row[CREATED_AT].0.clone().get_number(), created);
+        assert!(row[UPDATED_AT].0.clone().get_number() > updated);
+        assert_eq!(row["name"].0, Data::STRING("Bob".to_string()));
+    }
+
+    #[test]
+    fn test_update_many_by_id_across_shards() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        fields.insert("visits".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let rows: Vec<_> = (0..100)
+            .map(|i| {
+                let mut row = HashMap::new();
+                row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+                row.insert("name".to_string(), (Data::STRING(format!("user {}", i)), String::new()));
+                row.insert("visits".to_string(), (Data::NUMBER(0.0), String::new()));
+                row
+            })
+            .collect();
+        db.add_rows("users".to_string(), rows, false).unwrap();
+
+        let updates: HashMap<String, Row> = (0..100)
+            .map(|i| {
+                let mut partial = HashMap::new();
+                partial.insert("visits".to_string(), (Data::NUMBER(i as f64), String::new()));
+                (format!("u{}", i), partial)
+            })
+            .chain([("missing".to_string(), HashMap::new())])
+            .collect();
+
+        let updated = db.update_many_by_id("users".to_string(), updates).unwrap();
+        assert_eq!(updated.len(), 100);
+
+        for i in 0..100 {
+            let row = db.get_by_id("users".to_string(), format!("u{}", i)).unwrap().unwrap();
+            assert_eq!(row["visits"].0, Data::NUMBER(i as f64));
+            assert_eq!(row["name"].0, Data::STRING(format!("user {}", i)));
+        }
+    }
+
+    #[test]
+    fn test_update_many_by_id_rejects_invalid_without_writing() {
+        let (_temp_dir, db) = setup_scores();
+
+        let mut good = HashMap::new();
+        good.insert("score".to_string(), (Data::STRING("1".to_string()), String::new()));
+        let mut bad = HashMap::new();
+        bad.insert("score".to_string(), (Data::NUMBER(1.0), String::new()));
+
+        let updates = HashMap::from([("a".to_string(), good), ("b".to_string(), bad)]);
+        assert!(db.update_many_by_id("scores".to_string(), updates).is_err());
+
+        let a = db.get_by_id("scores".to_string(), "a".to_string()).unwrap().unwrap();
+        assert_eq!(a["score"].0, Data::STRING("42".to_string()));
+    }
+
+    #[test]
+    fn test_updates_enforce_unique_constraints() {
+        let (_temp_dir, db) = setup_scores();
+        db.add_unique("scores", "unique_score", &["score"]).unwrap();
+        let taken = || (Data::STRING("42".to_string()), String::new());
+        fn clash<T>(result: Result<T>) -> bool {
+            matches!(result, Err(Error::UniqueConstraint { .. }))
+        }
+
+        assert!(clash(db.update_field_by_id("scores".to_string(), "b".to_string(), "score".to_string(), taken())));
+        assert!(clash(db.update_field_where(
+            "scores".to_string(),
+            "score".to_string(),
+            Data::STRING("n/a".to_string()),
+            "score".to_string(),
+            taken(),
+            false,
+            CMP::EQUAL,
+        )));
+        let new_row = HashMap::from([("score".to_string(), taken())]);
+        assert!(clash(db.update_row_where(
+            "scores".to_string(),
+            "score".to_string(),
+            Data::STRING("n/a".to_string()),
+            new_row.clone(),
+            true,
+            CMP::EQUAL,
+        )));
+        let updates = HashMap::from([("c".to_string(), new_row)]);
+        assert!(clash(db.update_many_by_id("scores".to_string(), updates)));
+        assert!(clash(db.query("scores".to_string()).filter("id", crate::Operator::Eq, Data::STRING("c".to_string())).map_update(|mut row| {
+            row.insert("score".to_string(), taken());
+            row
+        })));
+
+        let score = |id: &str| db.get_by_id("scores".to_string(), id.to_string()).unwrap().unwrap()["score"].0.clone();
+        assert_eq!(score("b"), Data::STRING(" 7.5".to_string()));
+        assert_eq!(score("c"), Data::STRING("n/a".to_string()));
+
+        // a row may keep its own value
+        db.update_field_by_id("scores".to_string(), "a".to_string(), "score".to_string(), taken()).unwrap();
+    }
+
+    #[test]
+    fn test_update_field_where_by_id_changes_target_field() {
+        let (_temp_dir, db) = setup_scores();
+
+        db.update_field_where(
+            "scores".to_string(),
+            "id".to_string(),
+            Data::STRING("a".to_string()),
+            "score".to_string(),
+            (Data::STRING("99".to_string()), String::new()),
+            false,
+            CMP::EQUAL,
+        ).unwrap()
+        .unwrap();
+
+        let a = db.get_by_id("scores".to_string(), "a".to_string()).unwrap().unwrap();
+        assert_eq!(a["id"].0, Data::STRING("a".to_string()));
+        assert_eq!(a["score"].0, Data::STRING("99".to_string()));
+    }
+
+    #[test]
+    fn test_update_row_where_id_with_non_equal_cmp_scans() {
+        let (_temp_dir, db) = setup_scores();
+
+        let mut new_row = HashMap::new();
+        new_row.insert("score".to_string(), (Data::STRING("0".to_string()), String::new()));
+        db.update_row_where(
+            "scores".to_string(),
+            "id".to_string(),
+            Data::STRING("b".to_string()),
+            new_row,
+            true,
+            CMP::LESS,
+        ).unwrap()
+        .unwrap();
+
+        let score = |id: &str| db.get_by_id("scores".to_string(), id.to_string()).unwrap().unwrap()["score"].0.clone();
+        assert_eq!(score("a"), Data::STRING("0".to_string()));
+        assert_eq!(score("b"), Data::STRING(" 7.5".to_string()));
+        assert_eq!(score("c"), Data::STRING("n/a".to_string()));
+    }
+
+    #[test]
+    fn test_update_row_where_non_string_id_value_does_not_panic() {
+        let (_temp_dir, db) = setup_scores();
+
+        let mut new_row = HashMap::new();
+        new_row.insert("score".to_string(), (Data::STRING("0".to_string()), String::new()));
+        db.update_row_where(
+            "scores".to_string(),
+            "id".to_string(),
+            Data::BOOLEAN(true),
+            new_row,
+            true,
+            CMP::EQUAL,
+        ).unwrap();
+
+        let a = db.get_by_id("scores".to_string(), "a".to_string()).unwrap().unwrap();
+        assert_eq!(a["score"].0, Data::STRING("42".to_string()));
+    }
+
+    #[test]
+    fn test_update_where_returns_none_without_a_match() {
+        let (_temp_dir, db) = setup_scores();
+        let zero = (Data::STRING("0".to_string()), String::new());
+        let update_row = |value: &str, multi: bool| {
+            db.update_row_where(
+                "scores".to_string(),
+                "score".to_string(),
+                Data::STRING(value.to_string()),
+                HashMap::from([("score".to_string(), zero.clone())]),
+                multi,
+                CMP::EQUAL,
+            )
+            .unwrap()
+        };
+        let update_field = |value: &str, multi: bool| {
+            db.update_field_where(
+                "scores".to_string(),
+                "score".to_string(),
+                Data::STRING(value.to_string()),
+                "score".to_string(),
+                zero.clone(),
+                multi,
+                CMP::EQUAL,
+            )
+            .unwrap()
+        };
+
+        assert!(update_row("missing", false).is_none());
+        assert!(update_row("missing", true).is_none());
+        assert!(update_field("missing", false).is_none());
+        assert!(update_field("missing", true).is_none());
+
+        let updated = update_row("42", false).unwrap();
+        assert_eq!(updated["id"].0, Data::STRING("a".to_string()));
+        assert_eq!(updated["score"].0, zero.0);
+        assert_eq!(update_field("n/a", true), Some(zero.clone()));
+        // the old values are gone, so they match nothing any more
+        assert!(update_row("42", true).is_none());
+        assert!(update_field("n/a", false).is_none());
+    }
+
+    #[test]
+    fn test_updates_reject_regex_violation() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("email".to_string(), (Type::STRING, r"^[^@\s]+@[^@\s]+$".to_string()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("email".to_string(), (Data::STRING("a@b.c".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        let garbage = (Data::STRING("not an email".to_string()), String::new());
+        let schema = db.get_schema("users").unwrap();
+        let err = DATABASE::validate_fields(&HashMap::from([("email".to_string(), garbage.clone())]), &schema)
+            .unwrap_err();
+        assert!(matches!(err, Error::SchemaMismatch(FieldError::Regex { ref field, .. }) if field == "email"));
+
+        assert!(db
+            .update_field_by_id("users".to_string(), "u1".to_string(), "email".to_string(), garbage.clone())
+            .is_err());
+        assert!(db
+            .update_field_where(
+                "users".to_string(),
+                "email".to_string(),
+                Data::STRING("a@b.c".to_string()),
+                "email".to_string(),
+                garbage.clone(),
+                true,
+                CMP::EQUAL,
+            )
+            .is_err());
+        assert!(db
+            .update_row_where(
+                "users".to_string(),
+                "email".to_string(),
+                Data::STRING("a@b.c".to_string()),
+                HashMap::from([("email".to_string(), garbage)]),
+                true,
+                CMP::EQUAL,
+            )
+            .is_err());
+
+        let stored = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(stored["email"].0, Data::STRING("a@b.c".to_string()));
+    }
+
+    #[test]
+    fn test_update_by_id_rejects_type_mismatch() {
+        let (_temp_dir, db) = setup_scores();
+
+        let mut wrong = HashMap::new();
+        wrong.insert("score".to_string(), (Data::NUMBER(42.0), String::new()));
+        assert!(db.update_row_by_id("scores".to_string(), "a".to_string(), wrong).is_err());
+        assert!(db
+            .update_field_by_id(
+                "scores".to_string(),
+                "a".to_string(),
+                "score".to_string(),
+                (Data::BOOLEAN(true), String::new()),
+            )
+            .is_err());
+
+        // the row is still there, untouched
+        let a = db.get_by_id("scores".to_string(), "a".to_string()).unwrap().unwrap();
+        assert_eq!(a["score"].0, Data::STRING("42".to_string()));
+
+        let mut right = HashMap::new();
+        right.insert("score".to_string(), (Data::STRING("43".to_string()), String::new()));
+        let updated = db.update_row_by_id("scores".to_string(), "a".to_string(), right).unwrap().unwrap();
+        assert_eq!(updated["score"].0, Data::STRING("43".to_string()));
+    }
+
+    #[test]
+    fn test_update_row_by_id_moving_id() {
+        let (_temp_dir, db) = setup_scores();
+
+        let mut moved = HashMap::new();
+        moved.insert("id".to_string(), (Data::STRING("z".to_string()), String::new()));
+        db.update_row_by_id("scores".to_string(), "a".to_string(), moved).unwrap().unwrap();
+
+        assert!(db.get_by_id("scores".to_string(), "a".to_string()).unwrap().is_none());
+        let z = db.get_by_id("scores".to_string(), "z".to_string()).unwrap().unwrap();
+        assert_eq!(z["score"].0, Data::STRING("42".to_string()));
+    }
+
+    #[test]
+    fn test_migration_refreshes_cached_schema() {
+        let (_temp_dir, db) = setup_scores();
+        db.get_schema("scores").unwrap();
+
+        db.generate_add_column_migration("add_level", "scores", "level", "NUMBER", Some(json!(1))).unwrap();
+        db.apply_migrations().unwrap();
+
+        assert!(db.get_schema("scores").unwrap().field_names.contains_key("level"));
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("d".to_string()), String::new()));
+        row.insert("score".to_string(), (Data::STRING("1".to_string()), String::new()));
+        row.insert("level".to_string(), (Data::NUMBER(2.0), String::new()));
+        db.add_row("scores".to_string(), row, false).unwrap();
+    }
+
+    #[test]
+    fn test_migrated_shards_are_compact() {
+        let (temp_dir, db) = setup_scores();
+
+        db.generate_add_column_migration("add_level", "scores", "level", "NUMBER", Some(json!(1))).unwrap();
+        db.generate_rename_column_migration("scores", "score", "points").unwrap();
+        db.apply_migrations().unwrap();
+
+        let table_dir = temp_dir.path().join("db").join("scores");
+        for shard in shard_files(&table_dir).unwrap() {
+            let text = fs::read_to_string(&shard).unwrap();
+            assert!(!text.contains('\n'), "{} is pretty-printed", shard.display());
+        }
+        let schema = fs::read_to_string(temp_dir.path().join("db").join("scores-type.txt")).unwrap();
+        assert!(!schema.contains('\n'));
+    }
+}
@@ -0,0 +1,645 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+
+use log::{debug, warn};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+
+use crate::crud::make::{DATABASE, Row, TABLE};
+use crate::crud::stats::bump;
+use crate::error::{Error, Result};
+
+/// One pending shard write in a table's write-ahead log (`{table}-wal.txt`).
+#[derive(Serialize, Deserialize)]
+struct WalEntry {
+    shard: String,
+    contents: String,
+}
+
+/// `{db}/{table}/{shard}.txt` -> `{db}/{table}-wal.txt`
+fn wal_path(shard: &Path) -> io::Result<PathBuf> {
+    let table_dir = shard
+        .parent()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "shard path has no table directory"))?;
+    let table = table_dir.file_name().unwrap_or_default().to_string_lossy();
+    let root = table_dir.parent().unwrap_or_else(|| Path::new(""));
+    Ok(root.join(format!("{}-wal.txt", table)))
+}
+
+/// Records the new contents of `shard` in the table's log before anything
+/// touches the shard itself.
+pub(crate) fn wal_append(shard: &Path, contents: &str) -> io::Result<PathBuf> {
+    let wal = wal_path(shard)?;
+    let entry = WalEntry {
+        shard: shard.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        contents: contents.to_string(),
+    };
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&wal)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    file.sync_data()?;
+    Ok(wal)
+}
+
+/// Replaces `path` with `contents` by writing `{path}.tmp` next to it and
+/// renaming it over the target, so readers see either the old file or the new
+/// one, never a partial write.
+pub(crate) fn replace_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Lists the shard files of a table directory, sorted by name. Anything that
+/// isn't a `.txt` file, such as a temp file left by an interrupted write, is
+/// skipped.
+pub(crate) fn shard_files(table_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut shards: Vec<PathBuf> = fs::read_dir(table_dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    shards.sort();
+    Ok(shards)
+}
+
+/// Writes a shard file through the write-ahead log: the new contents are
+/// logged first, then the shard is atomically replaced, then the log is
+/// removed, which commits the write. A log left behind by a crash is replayed
+/// on `init`. A table has a single log, so writes to it through any handle in
+/// this process take turns; otherwise one write would remove the log while
+/// another's entry in it was still uncommitted.
+pub(crate) fn write_shard(shard: &Path, contents: &str) -> io::Result<()> {
+    let lock = wal_lock(&wal_path(shard)?);
+    let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    let wal = wal_append(shard, contents)?;
+    replace_file(shard, contents)?;
+    fs::remove_file(wal)
+}
+
+/// The lock serializing writes through the log at `wal`.
+fn wal_lock(wal: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = LazyLock::new(Mutex::default);
+    let mut locks = LOCKS.lock().unwrap_or_else(PoisonError::into_inner);
+    locks.entry(wal.to_path_buf()).or_default().clone()
+}
+
+/// Reads and parses one shard file. A shard that can't be read or parsed is
+/// logged as a warning and comes back as `None`, so scans skip it.
+pub(crate) fn read_shard<T: DeserializeOwned>(shard: &Path) -> Option<HashMap<String, T>> {
+    match parse_shard_file::<T>(shard) {
+        Ok(rows) => {
+            debug!("read {} rows from {}", rows.len(), shard.display());
+            Some(rows)
+        }
+        Err(Error::Io(e)) => {
+            warn!("skipping unreadable shard {}: {}", shard.display(), e);
+            None
+        }
+        Err(e) => {
+            warn!("skipping corrupt shard {}: {}", shard.display(), e);
+            None
+        }
+    }
+}
+
+/// Reads and parses one shard file like `read_shard`, but for callers that
+/// need to tell a missing row from a broken shard: a shard that can't be read
+/// or parsed is an error instead of being skipped.
+pub(crate) fn load_shard<T: DeserializeOwned>(shard: &Path) -> Result<HashMap<String, T>> {
+    let rows = parse_shard_file::<T>(shard)?;
+    debug!("read {} rows from {}", rows.len(), shard.display());
+    Ok(rows)
+}
+
+#[cfg(not(feature = "mmap"))]
+fn parse_shard_file<T: DeserializeOwned>(shard: &Path) -> Result<HashMap<String, T>> {
+    parse_shard_text(shard)
+}
+
+#[cfg(feature = "mmap")]
+fn parse_shard_file<T: DeserializeOwned>(shard: &Path) -> Result<HashMap<String, T>> {
+    parse_shard_mapped(shard)
+}
+
+#[cfg(any(not(feature = "mmap"), test))]
+fn parse_shard_text<T: DeserializeOwned>(shard: &Path) -> Result<HashMap<String, T>> {
+    Ok(serde_json::from_str(&fs::read_to_string(shard)?)?)
+}
+
+/// Parses a shard from a read-only memory map of the file, saving the copy
+/// into a `String`. The file must not be truncated while it is mapped (by
+/// another process, say): touching the missing pages raises SIGBUS. Writes
+/// from this crate never truncate in place, they rename a new file over the
+/// old one, so the mapped file stays intact.
+#[cfg(feature = "mmap")]
+fn parse_shard_mapped<T: DeserializeOwned>(shard: &Path) -> Result<HashMap<String, T>> {
+    let file = fs::File::open(shard)?;
+    // mapping an empty file fails on some platforms; it's not valid JSON anyway
+    if file.metadata()?.len() == 0 {
+        return Ok(serde_json::from_slice(&[])?);
+    }
+    // SAFETY: the map is read-only and dropped before returning; see above for
+    // what happens if the file shrinks underneath it.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(serde_json::from_slice(&map)?)
+}
+
+impl DATABASE {
+    /// `load_shard`, counted in `stats`.
+    pub(crate) fn load_shard<T: DeserializeOwned>(&self, shard: &Path) -> Result<HashMap<String, T>> {
+        bump(&self.counters.shard_reads, 1);
+        load_shard(shard)
+    }
+
+    /// `read_shard`, counted in `stats`.
+    pub(crate) fn read_shard<T: DeserializeOwned>(&self, shard: &Path) -> Option<HashMap<String, T>> {
+        bump(&self.counters.shard_reads, 1);
+        read_shard(shard)
+    }
+
+    /// Encodes `rows` as configured and writes them to `shard` through the
+    /// write-ahead log.
+    pub(crate) fn save_shard<T: Serialize>(&self, shard: &Path, rows: &HashMap<String, T>) -> Result<()> {
+        self.check_writable()?;
+        write_shard(shard, &self.encode_shard(rows)?)?;
+        bump(&self.counters.shard_writes, 1);
+        if let Some(table) = shard.parent().and_then(Path::file_name) {
+            self.touch_table(&table.to_string_lossy());
+        }
+        debug!("wrote {} rows to {}", rows.len(), shard.display());
+        Ok(())
+    }
+
+    /// Re-applies shard writes left uncommitted in any table's log and clears
+    /// the logs. Returns how many writes were replayed.
+    pub fn replay_wal(&self) -> Result<usize> {
+        let mut replayed = 0;
+
+        for entry in fs::read_dir(&self.path)?.flatten() {
+            let wal = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(table) = name.strip_suffix("-wal.txt") else {
+                continue;
+            };
+
+            let table_dir = PathBuf::from(&self.path).join(table);
+            for line in fs::read_to_string(&wal)?.lines() {
+                // a torn last line is a write that never got logged, so it never started
+                let Ok(entry) = serde_json::from_str::<WalEntry>(line) else {
+                    continue;
+                };
+                fs::create_dir_all(&table_dir)?;
+                replace_file(&table_dir.join(&entry.shard), &entry.contents)?;
+                replayed += 1;
+            }
+
+            fs::remove_file(&wal)?;
+        }
+
+        Ok(replayed)
+    }
+
+    /// Rewrites a table's shards from scratch: every row is reassigned to the
+    /// shard its id hashes to, and shards left empty are dropped. The new shards
+    /// are written to `.{table}.compact-new` and swapped in with two renames;
+    /// `init` finishes a swap cut short by a crash. The table's write lock is
+    /// held throughout, so no write made meanwhile is lost in the swap.
+    pub fn compact_table(&self, table_name: &str) -> Result<()> {
+        self.check_writable()?;
+        let schema = self.get_schema(table_name)?;
+
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        self.rewrite_shards(table_name, &schema)
+    }
+
+    /// `compact_table`'s rewrite, placing rows by `schema`'s shard width. The
+    /// caller holds the table's write lock.
+    fn rewrite_shards(&self, table_name: &str, schema: &TABLE) -> Result<()> {
+        let root = PathBuf::from(&self.path);
+        let table_dir = root.join(table_name);
+        let new_dir = root.join(format!(".{}.compact-new", table_name));
+        let old_dir = root.join(format!(".{}.compact-old", table_name));
+
+        let mut shards: BTreeMap<String, HashMap<String, Row>> = BTreeMap::new();
+        for shard in shard_files(&table_dir)? {
+            let rows = self.load_shard::<Row>(&shard)?;
+            for (id, row) in rows {
+                shards.entry(self.shard_file(schema, &id)).or_default().insert(id, row);
+            }
+        }
+
+        if new_dir.exists() {
+            fs::remove_dir_all(&new_dir)?;
+        }
+        fs::create_dir(&new_dir)?;
+        for (filename, rows) in shards {
+            replace_file(&new_dir.join(filename), &self.encode_shard(&rows)?)?;
+            bump(&self.counters.shard_writes, 1);
+        }
+
+        fs::rename(&table_dir, &old_dir)?;
+        fs::rename(&new_dir, &table_dir)?;
+        fs::remove_dir_all(&old_dir)?;
+        self.touch_table(table_name);
+        Ok(())
+    }
+
+    /// Changes the shard width of `table_name` to `digits`: like
+    /// `compact_table`, every row is rewritten into the shard it belongs to at
+    /// that width and the old shards are deleted, and only then is the width
+    /// recorded in its schema, all under the table's write lock. Run again
+    /// after a crash in between, this finishes the move. Without it, rows of
+    /// a table whose width changed can no longer be found by id.
+    pub fn reshard(&self, table_name: &str, digits: usize) -> Result<()> {
+        self.check_writable()?;
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        self.reshard_locked(table_name, digits)
+    }
+
+    /// `reshard`, for a caller already holding the table's write lock.
+    pub(crate) fn reshard_locked(&self, table_name: &str, digits: usize) -> Result<()> {
+        if digits == 0 {
+            return Err(Error::InvalidArgument("shard width must be at least 1".to_string()));
+        }
+        let mut schema = self.get_schema(table_name)?;
+        schema.shard_digits = Some(digits);
+        self.rewrite_shards(table_name, &schema)?;
+
+        let path = PathBuf::from(&self.path).join(format!("{}-type.txt", table_name));
+        replace_file(&path, &serde_json::to_string(&schema)?)?;
+        self.invalidate_schema(table_name);
+        Ok(())
+    }
+
+    /// Removes a table's shard files that hold no rows and any temp files left
+    /// by interrupted writes, returning how many files were deleted. Shards
+    /// that can't be parsed are left alone. A table needs no shard files at
+    /// all, so this can remove every one of them.
+    pub fn vacuum(&self, table_name: &str) -> Result<usize> {
+        self.check_writable()?;
+        self.get_schema(table_name)?;
+
+        let table_dir = PathBuf::from(&self.path).join(table_name);
+        let lock = self.table_lock(table_name);
+        let _guard = lock.write().unwrap();
+        let mut removed = 0;
+
+        for entry in fs::read_dir(&table_dir)?.flatten() {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            let stray_tmp = path.extension().is_some_and(|ext| ext == "tmp");
+            let empty_shard = path.extension().is_some_and(|ext| ext == "txt")
+                && fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|text| serde_json::from_str::<HashMap<String, IgnoredAny>>(&text).ok())
+                    .is_some_and(|rows| rows.is_empty());
+
+            if stray_tmp || empty_shard {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Completes or discards compactions interrupted by a crash. A complete set
+    /// of new shards is only moved aside once written, so if the table
+    /// directory is gone the new one replaces it; otherwise leftovers are
+    /// removed.
+    pub(crate) fn finish_compactions(&self) -> Result<()> {
+        let root = PathBuf::from(&self.path);
+
+        for entry in fs::read_dir(&root)?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(table) = name.strip_prefix('.').and_then(|n| n.strip_suffix(".compact-new")) else {
+                continue;
+            };
+            let table_dir = root.join(table);
+            if table_dir.exists() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::rename(entry.path(), &table_dir)?;
+            }
+        }
+
+        for entry in fs::read_dir(&root)?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && name.ends_with(".compact-old") {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crud::make::{Data, Type};
+
+    use super::*;
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_reads_match_string_reads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+        for i in 0..200 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("name".to_string(), (Data::STRING(format!("n\u{e9}{}", i)), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let table_dir = temp_dir.path().join("users");
+        fs::write(table_dir.join("0-1.txt"), "").unwrap();
+        fs::write(table_dir.join("1-2.txt"), "{\"half").unwrap();
+
+        for shard in shard_files(&table_dir).unwrap() {
+            let text = parse_shard_text::<Row>(&shard);
+            let mapped = parse_shard_mapped::<Row>(&shard);
+            match (text, mapped) {
+                (Ok(text), Ok(mapped)) => assert_eq!(text, mapped),
+                (Err(_), Err(_)) => {}
+                (text, mapped) => panic!("{}: {:?} vs {:?}", shard.display(), text, mapped),
+            }
+        }
+        assert!(db.get_all("users".to_string()).is_err());
+        assert_eq!(db.query("users".to_string()).execute().len(), 200);
+    }
+
+    #[test]
+    fn test_replay_restores_truncated_shard() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let db = DATABASE::init(path.clone());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+
+        // committed writes leave no log behind
+        let wal = temp_dir.path().join("users-wal.txt");
+        assert!(!wal.exists());
+
+        let id = DATABASE::string_to_numerical_uuid("u1");
+        let shard = temp_dir.path().join("users").join(DATABASE::get_file_by_id(id));
+        let contents = fs::read_to_string(&shard).unwrap();
+
+        // crash between logging a write and finishing it: the shard is torn
+        wal_append(&shard, &contents).unwrap();
+        fs::write(&shard, "").unwrap();
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).is_err());
+
+        let db = DATABASE::init(path);
+        assert!(!wal.exists());
+        assert!(db.get_by_id("users".to_string(), "u1".to_string()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_interrupted_write_leaves_prior_contents() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u1".to_string()), String::new()));
+        row.insert("name".to_string(), (Data::STRING("old".to_string()), String::new()));
+        db.add_row("users".to_string(), row.clone(), false).unwrap();
+
+        // a write that died before its rename: only the temp file is torn
+        let id = DATABASE::string_to_numerical_uuid("u1");
+        let shard = temp_dir.path().join("users").join(DATABASE::get_file_by_id(id));
+        let tmp = PathBuf::from(format!("{}.tmp", shard.display()));
+        fs::write(&tmp, "{\"half").unwrap();
+
+        let found = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(found["name"].0, Data::STRING("old".to_string()));
+        assert_eq!(db.get_all("users".to_string()).unwrap().len(), 1);
+
+        // the next write lands in full over the shard
+        row.insert("name".to_string(), (Data::STRING("new".to_string()), String::new()));
+        db.add_row("users".to_string(), row, true).unwrap();
+        let found = db.get_by_id("users".to_string(), "u1".to_string()).unwrap().unwrap();
+        assert_eq!(found["name"].0, Data::STRING("new".to_string()));
+        assert!(!tmp.exists());
+    }
+
+    fn setup_users(db: &DATABASE, count: usize) {
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..count {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_inserts_on_one_table() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        setup_users(&db, 0);
+
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        let mut row = HashMap::new();
+                        row.insert("id".to_string(), (Data::STRING(format!("t{}-{}", t, i)), String::new()));
+                        db.add_row("users".to_string(), row, false).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(db.count_rows("users".to_string()), 1600);
+        assert!(!temp_dir.path().join("users-wal.txt").exists());
+    }
+
+    #[test]
+    fn test_compact_table_keeps_rows_and_drops_empty_shards() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        setup_users(&db, 60);
+
+        for i in (0..60).filter(|i| i % 3 != 0) {
+            db.delete_row_by_id("users".to_string(), format!("u{}", i)).unwrap();
+        }
+        assert!(db.shard_stats("users").iter().any(|(_, rows)| *rows == 0));
+
+        db.compact_table("users").unwrap();
+
+        let stats = db.shard_stats("users");
+        assert!(stats.iter().all(|(_, rows)| *rows > 0));
+        assert_eq!(db.count_rows("users".to_string()), 20);
+        for i in (0..60).step_by(3) {
+            assert!(db.get_by_id("users".to_string(), format!("u{}", i)).unwrap().is_some());
+        }
+        assert!(!temp_dir.path().join(".users.compact-new").exists());
+        assert!(!temp_dir.path().join(".users.compact-old").exists());
+    }
+
+    #[test]
+    fn test_reshard_keeps_rows_reachable_by_id() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        setup_users(&db, 40);
+        let before = db.shard_stats("users").len();
+
+        db.generate_reshard_migration("users", 3).unwrap();
+        db.apply_migrations().unwrap();
+
+        assert_eq!(db.get_schema("users").unwrap().shard_digits, Some(3));
+        assert!(db.shard_stats("users").len() > before);
+        assert_eq!(db.count_rows("users".to_string()), 40);
+        for i in 0..40 {
+            assert!(db.get_by_id("users".to_string(), format!("u{}", i)).unwrap().is_some());
+        }
+        assert!(db.check("users").unwrap().is_empty());
+
+        // new rows land in shards of the new width too
+        let mut row = HashMap::new();
+        row.insert("id".to_string(), (Data::STRING("u40".to_string()), String::new()));
+        db.add_row("users".to_string(), row, false).unwrap();
+        assert!(db.check("users").unwrap().is_empty());
+        db.reshard("users", 9).unwrap();
+        assert_eq!(db.count_rows("users".to_string()), 41);
+        assert!(db.get_by_id("users".to_string(), "u40".to_string()).unwrap().is_some());
+        assert!(db.reshard("users", 0).is_err());
+
+        // the width is only recorded once the rows have moved
+        fs::write(db.shard_path("users", "u0"), "{not json").unwrap();
+        assert!(db.reshard("users", 2).is_err());
+        assert_eq!(db.get_schema("users").unwrap().shard_digits, Some(9));
+        let schema: TABLE = serde_json::from_str(&fs::read_to_string(temp_dir.path().join("users-type.txt")).unwrap()).unwrap();
+        assert_eq!(schema.shard_digits, Some(9));
+    }
+
+    #[test]
+    fn test_init_finishes_interrupted_compaction() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let db = DATABASE::init(path.clone());
+        setup_users(&db, 10);
+
+        // crash after the old shards were moved aside, before the new ones moved in
+        let table_dir = temp_dir.path().join("users");
+        copy_dir(&table_dir, &temp_dir.path().join(".users.compact-new"));
+        fs::rename(&table_dir, temp_dir.path().join(".users.compact-old")).unwrap();
+
+        let db = DATABASE::init(path);
+        assert_eq!(db.count_rows("users".to_string()), 10);
+        assert!(!temp_dir.path().join(".users.compact-new").exists());
+        assert!(!temp_dir.path().join(".users.compact-old").exists());
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir(to).unwrap();
+        for entry in fs::read_dir(from).unwrap().flatten() {
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_vacuum_removes_only_empty_shards_and_temp_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        setup_users(&db, 30);
+
+        for i in 0..15 {
+            db.delete_row_by_id("users".to_string(), format!("u{}", i)).unwrap();
+        }
+        let table_dir = temp_dir.path().join("users");
+        fs::write(table_dir.join("123-456.txt.tmp"), "{\"half").unwrap();
+
+        let empty = db.shard_stats("users").iter().filter(|(_, rows)| *rows == 0).count();
+        assert!(empty > 0);
+        let total = shard_files(&table_dir).unwrap().len();
+
+        assert_eq!(db.vacuum("users").unwrap(), empty + 1);
+        assert_eq!(shard_files(&table_dir).unwrap().len(), total - empty);
+        assert!(db.shard_stats("users").iter().all(|(_, rows)| *rows > 0));
+        assert_eq!(db.count_rows("users".to_string()), 15);
+        assert!(temp_dir.path().join("users-type.txt").exists());
+
+        assert_eq!(db.vacuum("users").unwrap(), 0);
+    }
+
+    /// Keeps every record logged from any test thread; tests filter by the
+    /// paths in their own temp dir.
+    struct CapturingLogger;
+
+    static RECORDS: std::sync::Mutex<Vec<(log::Level, String)>> = std::sync::Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            RECORDS.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_corrupt_shard_logs_warning() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+        setup_users(&db, 10);
+        let corrupt = temp_dir.path().join("users").join("123-456.txt");
+        fs::write(&corrupt, "{not json").unwrap();
+
+        assert_eq!(db.query("users".to_string()).execute().len(), 10);
+
+        let corrupt = corrupt.display().to_string();
+        let records = RECORDS.lock().unwrap();
+        assert!(records
+            .iter()
+            .any(|(level, msg)| *level == log::Level::Warn && msg.contains(&corrupt)));
+        let shards_dir = temp_dir.path().join("users").display().to_string();
+        assert!(records
+            .iter()
+            .any(|(level, msg)| *level == log::Level::Debug && msg.starts_with("wrote") && msg.contains(&shards_dir)));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::crud::make::FieldError;
+
+/// Errors returned by the public API. Converts into `eyre::Report` with `?`
+/// like any other `std::error::Error`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A table, field or row that doesn't exist; the string names it.
+    #[error("{0} not found")]
+    NotFound(String),
+    /// A row or value doesn't fit the table schema.
+    #[error(transparent)]
+    SchemaMismatch(#[from] FieldError),
+    #[error("row with id '{id}' already exists")]
+    UniqueViolation { id: String },
+    /// Another row already holds these values in the columns of a composite
+    /// unique constraint (`DATABASE::add_unique`).
+    #[error("unique constraint '{constraint}' violated by ({})", .values.join(", "))]
+    UniqueConstraint { constraint: String, values: Vec<String> },
+    /// A foreign-key field names a row that doesn't exist.
+    #[error("field '{field}' references missing row '{value}' in '{table}'")]
+    ForeignKeyViolation { field: String, table: String, value: String },
+    /// A row can't be deleted while `table.field` still references it with
+    /// `OnDelete::Restrict`.
+    #[error("row '{id}' is still referenced from '{table}.{field}'")]
+    StillReferenced { id: String, table: String, field: String },
+    #[error("table '{0}' already exists")]
+    TableExists(String),
+    /// `QueryBuilder::one` matched more than one row.
+    #[error("expected at most one matching row in '{table}', found more")]
+    TooManyRows { table: String },
+    /// A `Data` value couldn't be converted to the requested form.
+    #[error("invalid value: {0}")]
+    InvalidValue(String),
+    /// Bad arguments or configuration, such as a table whose id column isn't
+    /// among its fields.
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    /// A write to a database opened with `DATABASE::open_read_only`.
+    #[error("database is open read-only")]
+    ReadOnly,
+    /// A migration file is malformed or can't be applied to the current data.
+    #[error("migration failed: {0}")]
+    Migration(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::crud::make::{Data, Type, DATABASE};
+
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, DATABASE) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        (temp_dir, db)
+    }
+
+    fn user(id: &str) -> crate::crud::make::Row {
+        HashMap::from([
+            ("id".to_string(), (Data::STRING(id.to_string()), String::new())),
+            ("age".to_string(), (Data::NUMBER(30.0), String::new())),
+        ])
+    }
+
+    #[test]
+    fn test_errors_match_on_variant() {
+        let (_temp_dir, db) = setup();
+
+        db.add_row("users".to_string(), user("u1"), false).unwrap();
+        assert!(matches!(
+            db.add_row("users".to_string(), user("u1"), false),
+            Err(Error::UniqueViolation { .. })
+        ));
+        assert!(matches!(
+            db.add_rows("users".to_string(), vec![user("u1")], false),
+            Err(Error::UniqueViolation { .. })
+        ));
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        assert!(matches!(
+            db.create_table(fields.clone(), "id".to_string(), "users".to_string()),
+            Err(Error::TableExists(name)) if name == "users"
+        ));
+        assert!(matches!(
+            db.create_table(fields, "missing".to_string(), "other".to_string()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        assert!(matches!(db.get_schema("nope"), Err(Error::NotFound(_))));
+        assert!(matches!(
+            db.set_required("users", "nope", true),
+            Err(Error::NotFound(_))
+        ));
+
+        let mut bad = user("u2");
+        bad.insert("age".to_string(), (Data::STRING("old".to_string()), String::new()));
+        assert!(matches!(
+            db.add_row("users".to_string(), bad, false),
+            Err(Error::SchemaMismatch(FieldError::Type { ref field, .. })) if field == "age"
+        ));
+
+        assert!(matches!(Data::NUMBER(1.5).get_number_as_i64(), Err(Error::InvalidValue(_))));
+    }
+
+    #[test]
+    fn test_malformed_migration_is_a_migration_error() {
+        let (_temp_dir, db) = setup();
+
+        let file = format!("{}/migrations/001_broken.json", db.path);
+        db.create_migration(&file, &json!({ "operation": "add_column", "table": "users" }))
+            .unwrap();
+        assert!(matches!(db.apply_migrations(), Err(Error::Migration(_))));
+    }
+
+    #[test]
+    fn test_converts_into_eyre() {
+        let (_temp_dir, db) = setup();
+
+        let lookup = || -> eyre::Result<()> {
+            db.get_schema("nope")?;
+            Ok(())
+        };
+        let report = lookup().unwrap_err();
+        assert!(matches!(report.downcast_ref::<Error>(), Some(Error::NotFound(_))));
+    }
+}
@@ -1,13 +1,34 @@
-use std::collections::HashMap;
-use std::fs;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
-use crate::crud::make::{Data, DATABASE};
+use crate::crud::make::{Data, DATABASE, Row};
+use crate::crud::stats::bump;
+use crate::crud::u::eq_ignore_case;
+use crate::crud::wal::shard_files;
+use crate::error::Error;
 
 pub mod crud;
+pub mod error;
 
-enum Operator {
+#[derive(Clone, Debug)]
+pub enum Operator {
     Eq,
+    /// Case-insensitive `Eq` for strings (see [`crud::u::eq_ignore_case`]).
+    EqCi,
+    /// String prefix match; only string values (`STRING` or a non-null
+    /// `STRINGNULL`) can match.
+    StartsWith,
+    /// Matches a missing field, `NULL` and the `*NULL(None)` variants; the
+    /// condition's value is ignored.
+    IsNull,
+    /// The opposite of `IsNull`: the field is present and holds a value.
+    IsNotNull,
+    /// Array membership: the field is an `ARRAY` (or a non-null `ARRAYNULL`)
+    /// with an element equal to the condition's value. Never matches other
+    /// field types.
+    Contains,
     Ne,
     Gt,
     Lt,
@@ -15,21 +36,29 @@ enum Operator {
     Lte,
 }
 
-enum LogicalOp {
+#[derive(Clone, Debug)]
+pub enum LogicalOp {
     And,
     Or,
 }
 
-struct Condition {
-    field: String,
-    op: Operator,
-    value: Data,
+#[derive(Clone, Debug)]
+pub struct Condition {
+    pub field: String,
+    pub op: Operator,
+    pub value: Data,
+    /// Matches the rows the condition alone would not. See `where_not`.
+    pub negated: bool,
 }
 
-struct QueryBuilder<'a> {
+type RowFilter<'a> = Box<dyn Fn(&Row) -> bool + Send + Sync + 'a>;
+
+pub struct QueryBuilder<'a> {
     db: &'a DATABASE,
     table: String,
     conditions: Vec<(LogicalOp, Condition)>,
+    /// Predicates that can't be written as a `Condition`, ANDed with them.
+    filters: Vec<RowFilter<'a>>,
     limit: Option<usize>,
     sort_field: Option<String>,
     sort_ascending: bool,
@@ -40,6 +69,7 @@ impl<'a> QueryBuilder<'a> {
             db,
             table: table.to_string(),
             conditions: vec![],
+            filters: vec![],
             limit:Option::None,
             sort_field:Option::None,
             sort_ascending:true
@@ -56,6 +86,7 @@ impl<'a> QueryBuilder<'a> {
             field: field.to_string(),
             op,
             value,
+            negated: false,
         }));
         self
     }
@@ -65,6 +96,7 @@ impl<'a> QueryBuilder<'a> {
             field: field.to_string(),
             op,
             value,
+            negated: false,
         }));
         self
     }
@@ -74,53 +106,137 @@ impl<'a> QueryBuilder<'a> {
             field: field.to_string(),
             op,
             value,
+            negated: false,
+        }));
+        self
+    }
+    /// Matches rows the condition `field op value` does not, e.g. "not
+    /// (status = 'archived')". The negation covers the condition's whole
+    /// result, including how it treats a missing field: a row without
+    /// `field` fails `Eq`, so it matches `where_not` with `Eq`, and it
+    /// passes `IsNull`, so it doesn't match `where_not` with `IsNull`.
+    pub fn where_not(mut self, field: &str, op: Operator, value: Data) -> Self {
+        self.conditions.push((LogicalOp::And, Condition {
+            field: field.to_string(),
+            op,
+            value,
+            negated: true,
         }));
         self
     }
+
+    /// Matches rows whose `field` is a string beginning with `prefix`. An
+    /// empty prefix matches every string value.
+    pub fn starts_with(self, field: &str, prefix: &str) -> Self {
+        self.and(field, Operator::StartsWith, Data::STRING(prefix.to_string()))
+    }
+
+    /// Matches rows where the value `compute` derives from the row compares
+    /// to `value` under `op`, e.g. `price * quantity` greater than 100. A row
+    /// for which `compute` returns `None` doesn't match. Queries with computed
+    /// conditions bypass the query cache.
+    pub fn where_computed(
+        mut self,
+        compute: impl Fn(&Row) -> Option<Data> + Send + Sync + 'a,
+        op: Operator,
+        value: Data,
+    ) -> Self {
+        self.filters.push(Box::new(move |row| {
+            compute(row).is_some_and(|computed| Self::compare(&op, &computed, &value))
+        }));
+        self
+    }
+
+    /// Matches rows for which `pred` returns `true`, for conditions the
+    /// `Operator`s can't express. Checked row by row while the shards are
+    /// scanned, ANDed with every other condition and `filter_fn`. Like
+    /// `where_computed`, this bypasses the query cache.
+    pub fn filter_fn(mut self, pred: impl Fn(&Row) -> bool + Send + Sync + 'a) -> Self {
+        self.filters.push(Box::new(pred));
+        self
+    }
+
+    /// Matches rows where `field` is missing or null.
+    pub fn is_null(self, field: &str) -> Self {
+        self.and(field, Operator::IsNull, Data::NULL)
+    }
+
+    /// Matches rows where `field` is present and not null.
+    pub fn is_not_null(self, field: &str) -> Self {
+        self.and(field, Operator::IsNotNull, Data::NULL)
+    }
+
     pub fn filter(mut self, field: &str, op: Operator, value: Data) -> Self {
         let cond = Condition {
             field: field.to_string(),
             op,
             value,
+            negated: false,
         };
         self.conditions.push((LogicalOp::And, cond)); // default to AND
         self
     }
     pub fn execute(&self) -> Vec<HashMap<String, (Data, String)>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "execute",
+            table = %self.table,
+            predicates = self.conditions.len()
+        )
+        .entered();
+
+        // closures can't be part of a cache key
+        let Some(cache) = self.db.query_cache.as_ref().filter(|_| self.filters.is_empty()) else {
+            return self.scan();
+        };
+        let key = self.cache_key();
+        let version = {
+            let cache = cache.lock().unwrap();
+            if let Some(rows) = cache.get(&self.table, &key) {
+                return rows;
+            }
+            cache.version(&self.table)
+        };
+
+        let results = self.scan();
+        cache.lock().unwrap().put(&self.table, key, version, results.clone());
+        results
+    }
+
+    /// What identifies this query in the query cache.
+    fn cache_key(&self) -> String {
+        format!(
+            "{:?}|{:?}|{:?}|{}",
+            self.conditions, self.sort_field, self.limit, self.sort_ascending
+        )
+    }
+
+    fn scan(&self) -> Vec<Row> {
         let mut results = vec![];
 
         let mut path = PathBuf::from(&self.db.path);
         path.push(&self.table);
 
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries.flatten() {
-                if let Ok(data_str) = fs::read_to_string(entry.path()) {
-                    let parsed: Result<HashMap<String, HashMap<String, (Data, String)>>, _> =
-                        serde_json::from_str(&data_str);
-
-                    if let Ok(map) = parsed {
-                        for (_id, row) in map {
-                            if self.matches_all(&row) {
-                                results.push(row);
-                            }
+        let lock = self.db.table_lock(&self.table);
+        let guard = lock.read().unwrap();
+
+        if let Ok(entries) = shard_files(&path) {
+            for entry in entries {
+                if let Some(map) = self.db.read_shard::<Row>(&entry) {
+                    for (_id, row) in map {
+                        if self.matches_all(&row) {
+                            results.push(row);
                         }
                     }
                 }
             }
         }
+        drop(guard);
 
         // Apply sorting if requested
         if let Some(field) = &self.sort_field {
             results.sort_by(|a, b| {
-                let a_val = a.get(field);
-                let b_val = b.get(field);
-
-                // Compare Data values, handle None cases
-                let ord = match (a_val, b_val) {
-                    (Some((Data::NUMBER(a_num), _)), Some((Data::NUMBER(b_num), _))) => a_num.partial_cmp(b_num).unwrap_or(std::cmp::Ordering::Equal),
-                    (Some((Data::STRING(a_str), _)), Some((Data::STRING(b_str), _))) => a_str.cmp(b_str),
-                    _ => std::cmp::Ordering::Equal,
-                };
+                let ord = sort_order(a.get(field).map(|(d, _)| d), b.get(field).map(|(d, _)| d));
 
                 if self.sort_ascending {
                     ord
@@ -135,6 +251,7 @@ impl<'a> QueryBuilder<'a> {
             results.truncate(max);
         }
 
+        bump(&self.db.counters.rows_read, results.len());
         results
     }
 
@@ -147,7 +264,7 @@ impl<'a> QueryBuilder<'a> {
     //     if let Ok(entries) = fs::read_dir(path) {
     //         for entry in entries.flatten() {
     //             if let Ok(data_str) = fs::read_to_string(entry.path()) {
-    //                 let parsed: Result<HashMap<String, HashMap<String, (Data, String)>>, _> =
+    //                 let parsed: Result<HashMap<String, Row>, _> =
     //                     serde_json::from_str(&data_str);
     //
     //                 if let Ok(map) = parsed {
@@ -166,19 +283,61 @@ impl<'a> QueryBuilder<'a> {
 
     fn matches_all(&self, row: &HashMap<String, (Data, String)>) -> bool {
         for (_, cond) in &self.conditions {
-            match row.get(&cond.field) {
-                Some((val, _)) => {
-                    if !Self::compare(&cond.op, val.clone(), cond.value.clone()) {
-                        return false;
-                    }
-                }
-                None => return false,
+            let matched = match Self::lookup(row, &cond.field) {
+                Some(val) => Self::compare(&cond.op, &val, &cond.value),
+                None => matches!(cond.op, Operator::IsNull),
+            };
+            if matched == cond.negated {
+                return false;
             }
         }
-        true
+        self.filters.iter().all(|filter| filter(row))
+    }
+
+    /// Resolves a condition field. A dotted name that isn't itself a column,
+    /// like `profile.address.city`, is read from inside the JSON column
+    /// `profile`; a missing key anywhere along the path gives `None`.
+    fn lookup<'r>(row: &'r Row, field: &str) -> Option<Cow<'r, Data>> {
+        if let Some((val, _)) = row.get(field) {
+            return Some(Cow::Borrowed(val));
+        }
+
+        let (column, path) = field.split_once('.')?;
+        let json = match &row.get(column)?.0 {
+            Data::JSON(text) | Data::JSONNULL(Some(text)) => text,
+            _ => return None,
+        };
+        let root: serde_json::Value = serde_json::from_str(json).ok()?;
+        let mut value = &root;
+        for key in path.split('.') {
+            value = match value {
+                serde_json::Value::Object(map) => map.get(key)?,
+                serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Data::from_json_value(value).ok().map(Cow::Owned)
     }
 
-    fn compare(op: &Operator, left: Data, right: Data) -> bool {
+    fn compare(op: &Operator, left: &Data, right: &Data) -> bool {
+        match op {
+            Operator::EqCi => return eq_ignore_case(left, right),
+            Operator::IsNull => return left.is_null(),
+            Operator::IsNotNull => return !left.is_null(),
+            Operator::StartsWith => {
+                return match (left.unwrap_nullable(), right) {
+                    (Some(Data::STRING(a)), Data::STRING(b)) => a.starts_with(b.as_str()),
+                    _ => false,
+                }
+            }
+            Operator::Contains => {
+                return match left.unwrap_nullable() {
+                    Some(Data::ARRAY(items)) => items.contains(right),
+                    _ => false,
+                }
+            }
+            _ => {}
+        }
         match (left, right) {
             (Data::STRING(a), Data::STRING(b)) => match op {
                 Operator::Eq => a == b,
@@ -187,6 +346,7 @@ impl<'a> QueryBuilder<'a> {
                 Operator::Lt => a < b,
                 Operator::Gte => a >= b,
                 Operator::Lte => a <= b,
+                _ => false,
             },
             (Data::NUMBER(a), Data::NUMBER(b)) => match op {
                 Operator::Eq => a == b,
@@ -195,6 +355,7 @@ impl<'a> QueryBuilder<'a> {
                 Operator::Lt => a < b,
                 Operator::Gte => a >= b,
                 Operator::Lte => a <= b,
+                _ => false,
             },
             _ => false, // Type mismatch
         }
@@ -209,38 +370,202 @@ impl<'a> QueryBuilder<'a> {
             .collect()
     }
 
-    pub fn update_row(self, new_row: HashMap<String, (Data, String)>) -> QueryBuilder<'a> {
-        if let Some(table_data) = self.db.get_table(&self.table) {
-            for (id, row) in table_data {
-                if self.matches_all(&row) {
-                    let _ = self.db.update_row_by_id(self.table.clone(), id, new_row.clone());
+    /// The distinct values of `field` across the matching rows, skipping rows
+    /// without it. Sorted ascending when every pair of values is comparable,
+    /// otherwise left in the order they were found.
+    pub fn distinct(&self, field: &str) -> Vec<Data> {
+        let mut seen = HashSet::new();
+        let mut values: Vec<Data> = vec![];
+        for row in self.select() {
+            if let Some(val) = Self::lookup(&row, field) {
+                if seen.insert(val.clone().into_owned()) {
+                    values.push(val.into_owned());
                 }
             }
         }
-        self
+        sort_if_comparable(values, |v| v)
     }
 
+    /// Groups the matching rows by the value of `field` and counts each group,
+    /// skipping rows without it. Narrow the groups down with
+    /// [`GroupCounts::having`].
+    pub fn group_count(&self, field: &str) -> GroupCounts {
+        // each value's position in `groups`, which keeps first-seen order
+        let mut positions: HashMap<Data, usize> = HashMap::new();
+        let mut groups: Vec<(Data, usize)> = vec![];
+        for row in self.select() {
+            if let Some(val) = Self::lookup(&row, field) {
+                match positions.get(val.as_ref()) {
+                    Some(&i) => groups[i].1 += 1,
+                    None => {
+                        positions.insert(val.clone().into_owned(), groups.len());
+                        groups.push((val.into_owned(), 1));
+                    }
+                }
+            }
+        }
+        GroupCounts {
+            groups: sort_if_comparable(groups, |(key, _)| key),
+        }
+    }
+
+    /// The `p`-th percentile (`0.0..=1.0`) of `field` over the matching rows,
+    /// interpolating linearly between the two nearest values. Rows where
+    /// `field` is missing, null or not a number are skipped; `Ok(None)` if no
+    /// values are left. A `p` outside `0.0..=1.0` is an
+    /// `Error::InvalidArgument`.
+    pub fn percentile(&self, field: &str, p: f64) -> Result<Option<f64>, Error> {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(Error::InvalidArgument(format!("percentile {} is not between 0 and 1", p)));
+        }
+
+        let mut values: Vec<f64> = self
+            .select()
+            .iter()
+            .filter_map(|row| match Self::lookup(row, field)?.unwrap_nullable()? {
+                Data::NUMBER(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        if values.is_empty() {
+            return Ok(None);
+        }
+        values.sort_by(f64::total_cmp);
+
+        let rank = p * (values.len() - 1) as f64;
+        let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+        Ok(Some(values[lo] + (values[hi] - values[lo]) * (rank - lo as f64)))
+    }
+
+    /// The median of `field` over the matching rows; see [`Self::percentile`].
+    pub fn median(&self, field: &str) -> Option<f64> {
+        self.percentile(field, 0.5).unwrap_or_default()
+    }
+
+    /// Describes how this query would run without running it: how many shard
+    /// files and rows a scan reads, whether a predicate has an index it could
+    /// go through, and whether sorting needs every matching row in memory
+    /// first.
+    pub fn explain(&self) -> QueryPlan {
+        let path = PathBuf::from(&self.db.path).join(&self.table);
+        let shards = shard_files(&path).unwrap_or_default();
+
+        // an equality on the id column names one shard; one on a field with a
+        // secondary index (`add_index`) names the matching rows
+        let index_available = self.db.get_schema(&self.table).is_ok_and(|schema| {
+            let indexed = |field: &str| {
+                field == schema.id_column || schema.indexes.values().any(|index| index.field == field)
+            };
+            self.conditions.iter().any(|(_, cond)| {
+                matches!(cond.op, Operator::Eq) && !cond.negated && indexed(&cond.field)
+            })
+        });
+
+        QueryPlan {
+            shards_read: shards.len(),
+            rows_scanned: self.db.count_rows(self.table.clone()),
+            index_available,
+            full_materialization: self.sort_field.is_some(),
+        }
+    }
+
+    /// Merges `new_row` into every matching row and returns how many were
+    /// updated. Stops at the first row that can't be updated, with the rows
+    /// before it already written.
+    pub fn update_row(&self, new_row: HashMap<String, (Data, String)>) -> Result<usize, Error> {
+        self.db.check_writable()?;
+        let schema = self.db.get_schema(&self.table)?;
+        let mut updated = 0;
+        for row in self.select() {
+            if let Some(id) = Self::row_id(&row, &schema.id_column) {
+                if self.db.update_row_by_id(self.table.clone(), id, new_row.clone())?.is_some() {
+                    updated += 1;
+                }
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Sets `fieldname` on every matching row and returns how many were
+    /// updated. Stops at the first row that can't be updated, with the rows
+    /// before it already written.
     pub fn update_field(
         &self,
         fieldname: &str,
         new_value: (Data, String),
-    ) -> &QueryBuilder<'a> {
-        let mut updated_count = 0;
-        let table = self.db.get_table(&self.table).unwrap_or_default();
-
-        for (id, row) in table {
-            if self.matches_all(&row) {
-                self.db.update_field_by_id(
+    ) -> Result<usize, Error> {
+        self.db.check_writable()?;
+        let schema = self.db.get_schema(&self.table)?;
+        let mut updated = 0;
+        for row in self.select() {
+            if let Some(id) = Self::row_id(&row, &schema.id_column) {
+                let done = self.db.update_field_by_id(
                     self.table.clone(),
                     id,
                     fieldname.to_string(),
                     new_value.clone(),
-                );
-                updated_count += 1;
+                )?;
+                if done.is_some() {
+                    updated += 1;
+                }
             }
         }
-        self
-        // updated_count
+        Ok(updated)
+    }
+
+    /// Sets every field in `fields` on every matching row, reading and writing
+    /// each affected shard once, and returns how many rows were updated. If
+    /// any field fails the schema nothing is written and 0 is returned.
+    pub fn update_fields(&self, fields: HashMap<String, (Data, String)>) -> usize {
+        let Ok(schema) = self.db.get_schema(&self.table) else {
+            return 0;
+        };
+        let updates = self
+            .select()
+            .iter()
+            .filter_map(|row| Self::row_id(row, &schema.id_column))
+            .map(|id| (id, fields.clone()))
+            .collect();
+        self.db
+            .update_many_by_id(self.table.clone(), updates)
+            .map_or(0, |updated| updated.len())
+    }
+
+    /// Replaces every matching row with `f` applied to it, and returns how many
+    /// were updated. Each affected shard is read and written once. Fields `f`
+    /// adds or changes are written back; fields it removes are kept. Every
+    /// output is checked against the schema before anything is written, and
+    /// `f` may not change a row's id.
+    pub fn map_update<F>(&self, mut f: F) -> Result<usize, Error>
+    where
+        F: FnMut(Row) -> Row,
+    {
+        let schema = self.db.get_schema(&self.table)?;
+        let mut updates = HashMap::new();
+        for row in self.select() {
+            let Some(id) = Self::row_id(&row, &schema.id_column) else {
+                continue;
+            };
+            let new_row = f(row);
+            if Self::row_id(&new_row, &schema.id_column).is_some_and(|new_id| new_id != id) {
+                return Err(Error::InvalidArgument(format!(
+                    "map_update may not change the id of row '{}'",
+                    id
+                )));
+            }
+            updates.insert(id, new_row);
+        }
+        Ok(self.db.update_many_by_id(self.table.clone(), updates)?.len())
+    }
+
+    /// The id-column value of a row, which the by-id operations take (not the
+    /// hashed shard key).
+    fn row_id(row: &Row, id_column: &str) -> Option<String> {
+        match &row.get(id_column)?.0 {
+            Data::STRING(id) => Some(id.clone()),
+            Data::NUMBER(id) => Some(id.to_string()),
+            _ => None,
+        }
     }
 
     pub fn insert(&self, table: &str, row: HashMap<String, (Data, String)>) -> Option<()> {
@@ -261,15 +586,51 @@ impl<'a> QueryBuilder<'a> {
         self
     }
 
-    pub fn delete(self) -> QueryBuilder<'a> {
-        if let Some(table_data) = self.db.get_table(&self.table) {
-            for (id, row) in table_data {
+    /// Deletes every matching row and returns how many were removed. Stops at
+    /// the first row that can't be deleted, a foreign key refusing it
+    /// included, with the rows before it already gone.
+    pub fn delete(&self) -> Result<usize, Error> {
+        self.db.check_writable()?;
+        let schema = self.db.get_schema(&self.table)?;
+        let mut deleted = 0;
+        for row in self.select() {
+            if let Some(id) = Self::row_id(&row, &schema.id_column) {
+                if self.db.delete_by_id(&self.table, &id)?.is_some() {
+                    deleted += 1;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// The single matching row: `Ok(None)` if nothing matches and
+    /// `Err(Error::TooManyRows)` if more than one does. Stops reading shards as
+    /// soon as a second match turns up.
+    pub fn one(&self) -> Result<Option<Row>, Error> {
+        let path = PathBuf::from(&self.db.path).join(&self.table);
+        let mut found = None;
+
+        let lock = self.db.table_lock(&self.table);
+        let _guard = lock.read().unwrap();
+
+        for shard in shard_files(&path).unwrap_or_default() {
+            let Some(map) = self.db.read_shard::<Row>(&shard) else {
+                continue;
+            };
+            for (_id, row) in map {
                 if self.matches_all(&row) {
-                    let _ = self.db.delete_row_by_id(self.table.clone(), id);
+                    if found.is_some() {
+                        return Err(Error::TooManyRows {
+                            table: self.table.clone(),
+                        });
+                    }
+                    found = Some(row);
                 }
             }
         }
-        self
+
+        bump(&self.db.counters.rows_read, usize::from(found.is_some()));
+        Ok(found)
     }
 
     pub fn first(self) -> Option<HashMap<String, (Data, String)>> {
@@ -286,17 +647,12 @@ impl<'a> QueryBuilder<'a> {
         let mut path = PathBuf::from(&self.db.path);
         path.push(&self.table);
 
-        if let Ok(entries) = fs::read_dir(path) {
-            for entry in entries.flatten() {
-                if let Ok(data_str) = fs::read_to_string(entry.path()) {
-                    let parsed: Result<HashMap<String, HashMap<String, (Data, String)>>, _> =
-                        serde_json::from_str(&data_str);
-
-                    if let Ok(map) = parsed {
-                        for (_id, row) in map {
-                            if self.matches_all(&row) {
-                                return true;
-                            }
+        if let Ok(entries) = shard_files(&path) {
+            for entry in entries {
+                if let Some(map) = self.db.read_shard::<Row>(&entry) {
+                    for (_id, row) in map {
+                        if self.matches_all(&row) {
+                            return true;
                         }
                     }
                 }
@@ -306,19 +662,872 @@ impl<'a> QueryBuilder<'a> {
     }
 
 }
+/// What [`QueryBuilder::explain`] reports about a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    /// Shard files a scan of the table reads.
+    pub shards_read: usize,
+    /// Rows a scan of the table checks against the conditions.
+    pub rows_scanned: usize,
+    /// Whether a condition is an equality on the id column or on a field with
+    /// a secondary index, which `DATABASE::get_by_id` or
+    /// `DATABASE::find_by_index` could answer without a scan. `execute` itself
+    /// always scans, so this doesn't change `shards_read` or `rows_scanned`.
+    pub index_available: bool,
+    /// Whether sorting forces all matching rows to be loaded before any is
+    /// returned, even with a limit.
+    pub full_materialization: bool,
+}
+
+/// The result of [`QueryBuilder::group_count`]: each distinct value with the
+/// number of rows holding it, sorted by value when the values are comparable.
+#[derive(Debug, PartialEq)]
+pub struct GroupCounts {
+    pub groups: Vec<(Data, usize)>,
+}
+
+impl GroupCounts {
+    /// Keeps the groups with at least `min_count` rows, like SQL's
+    /// `HAVING COUNT(*) >= min_count`.
+    pub fn having(self, min_count: usize) -> Self {
+        self.having_by(|_, count| count >= min_count)
+    }
+
+    /// Keeps the groups for which `pred(value, count)` holds.
+    pub fn having_by(self, pred: impl Fn(&Data, usize) -> bool) -> Self {
+        Self {
+            groups: self
+                .groups
+                .into_iter()
+                .filter(|(key, count)| pred(key, *count))
+                .collect(),
+        }
+    }
+}
+
+/// The ascending order used by `sort_by`. Nulls, including a missing field
+/// and the `*NULL(None)` variants, come first. Nullable values compare like
+/// their non-null type, so `NUMBERNULL(Some(1.0))` sorts with `NUMBER(1.0)`.
+/// Values of different types are ordered by type: BOOLEAN, NUMBER, STRING,
+/// ARRAY, JSON, HASHMAP, HASHSET. Maps and sets are unordered among
+/// themselves and keep their relative order.
+fn sort_order(a: Option<&Data>, b: Option<&Data>) -> Ordering {
+    fn rank(data: &Data) -> u8 {
+        match data {
+            Data::BOOLEAN(_) => 0,
+            Data::NUMBER(_) => 1,
+            Data::STRING(_) => 2,
+            Data::ARRAY(_) => 3,
+            Data::JSON(_) => 4,
+            Data::HASHMAP(_) => 5,
+            _ => 6,
+        }
+    }
+
+    match (a.and_then(Data::unwrap_nullable), b.and_then(Data::unwrap_nullable)) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(a), Some(b)) => rank(&a)
+            .cmp(&rank(&b))
+            .then_with(|| a.partial_cmp(&b).unwrap_or(Ordering::Equal)),
+    }
+}
+
+/// Sorts `items` ascending by `key` if every pair of keys is comparable,
+/// otherwise returns them in their original order.
+fn sort_if_comparable<T: Clone>(items: Vec<T>, key: impl Fn(&T) -> &Data) -> Vec<T> {
+    let mut sorted = items.clone();
+    let mut comparable = true;
+    sorted.sort_by(|a, b| {
+        key(a).partial_cmp(key(b)).unwrap_or_else(|| {
+            comparable = false;
+            Ordering::Equal
+        })
+    });
+
+    if comparable {
+        sorted
+    } else {
+        items
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
 
-    use rand::Rng;
-
-    use crate::crud::make::DATABASE;
+    use crate::crud::make::{Type, DATABASE};
 
     use super::*;
 
+    #[test]
+    fn test_distinct_statuses() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("status".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+
+        let statuses = ["shipped", "pending", "shipped", "cancelled", "pending", "shipped"];
+        for (i, status) in statuses.iter().enumerate() {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("o{}", i)), String::new()));
+            row.insert("status".to_string(), (Data::STRING(status.to_string()), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+            db.add_row("orders".to_string(), row, false).unwrap();
+        }
+
+        let all = db.query("orders".to_string()).distinct("status");
+        assert_eq!(
+            all,
+            vec![
+                Data::STRING("cancelled".to_string()),
+                Data::STRING("pending".to_string()),
+                Data::STRING("shipped".to_string()),
+            ]
+        );
+
+        let later = db
+            .query("orders".to_string())
+            .where_("age", Operator::Gte, Data::NUMBER(4.0))
+            .distinct("status");
+        assert_eq!(
+            later,
+            vec![Data::STRING("pending".to_string()), Data::STRING("shipped".to_string())]
+        );
+
+        assert!(db.query("orders".to_string()).distinct("missing").is_empty());
+    }
+
+    #[test]
+    fn test_median_and_percentile() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("latency".to_string(), (Type::NUMBERNULL, String::new()));
+        fields.insert("bucket".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "requests".to_string()).unwrap();
+
+        // 1..=100, plus a null that is skipped
+        for i in 0..=100 {
+            let latency = if i == 0 { None } else { Some(i as f64) };
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("r{}", i)), String::new()));
+            row.insert("latency".to_string(), (Data::NUMBERNULL(latency), String::new()));
+            let bucket = if i <= 4 { "low" } else { "high" };
+            row.insert("bucket".to_string(), (Data::STRING(bucket.to_string()), String::new()));
+            db.add_row("requests".to_string(), row, false).unwrap();
+        }
+
+        let all = db.query("requests".to_string());
+        assert_eq!(all.median("latency"), Some(50.5));
+        let p90 = all.percentile("latency", 0.9).unwrap().unwrap();
+        assert!((p90 - 90.1).abs() < 1e-9);
+        assert_eq!(all.percentile("latency", 0.0).unwrap(), Some(1.0));
+        assert_eq!(all.percentile("latency", 1.0).unwrap(), Some(100.0));
+        assert!(all.percentile("latency", 1.5).is_err());
+        assert!(all.percentile("latency", -0.1).is_err());
+
+        let low = db
+            .query("requests".to_string())
+            .where_("bucket", Operator::Eq, Data::STRING("low".to_string()));
+        assert_eq!(low.median("latency"), Some(2.5));
+
+        assert_eq!(db.query("requests".to_string()).median("missing"), None);
+    }
+
+    #[test]
+    fn test_where_computed_product() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("price".to_string(), (Type::NUMBER, String::new()));
+        fields.insert("quantity".to_string(), (Type::NUMBERNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "lines".to_string()).unwrap();
+
+        for (id, price, quantity) in [("l1", 10.0, Some(20.0)), ("l2", 30.0, Some(2.0)), ("l3", 99.0, None), ("l4", 60.0, Some(2.0))] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("price".to_string(), (Data::NUMBER(price), String::new()));
+            row.insert("quantity".to_string(), (Data::NUMBERNULL(quantity), String::new()));
+            db.add_row("lines".to_string(), row, false).unwrap();
+        }
+
+        let total = |row: &Row| {
+            let price = row.get("price")?.0.unwrap_nullable()?.try_get_number()?;
+            let quantity = row.get("quantity")?.0.unwrap_nullable()?.try_get_number()?;
+            Some(Data::NUMBER(price * quantity))
+        };
+
+        // l3 has no quantity, so no total, and never matches
+        let big = db
+            .query("lines".to_string())
+            .where_computed(total, Operator::Gt, Data::NUMBER(100.0))
+            .sort_by("id", true)
+            .execute();
+        let ids: Vec<&Data> = big.iter().map(|row| &row["id"].0).collect();
+        assert_eq!(ids, vec![&Data::STRING("l1".to_string()), &Data::STRING("l4".to_string())]);
+
+        let cheap_big = db
+            .query("lines".to_string())
+            .where_("price", Operator::Lt, Data::NUMBER(50.0))
+            .where_computed(total, Operator::Gt, Data::NUMBER(100.0))
+            .execute();
+        assert_eq!(cheap_big.len(), 1);
+        assert_eq!(cheap_big[0]["id"].0, Data::STRING("l1".to_string()));
+    }
+
+    #[test]
+    fn test_filter_fn_with_where() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for (id, name, age) in [("u1", "anna", 30.0), ("u2", "bob", 40.0), ("u3", "otto", 50.0), ("u4", "eve", 20.0)] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(age), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let palindrome = |row: &Row| match &row["name"].0 {
+            Data::STRING(name) => name.chars().eq(name.chars().rev()),
+            _ => false,
+        };
+
+        let rows = db
+            .query("users".to_string())
+            .where_("age", Operator::Gte, Data::NUMBER(25.0))
+            .filter_fn(palindrome)
+            .sort_by("age", true)
+            .execute();
+        let names: Vec<&Data> = rows.iter().map(|row| &row["name"].0).collect();
+        assert_eq!(names, vec![&Data::STRING("anna".to_string()), &Data::STRING("bob".to_string()), &Data::STRING("otto".to_string())]);
+
+        // several closures all have to hold
+        let rows = db
+            .query("users".to_string())
+            .filter_fn(palindrome)
+            .filter_fn(|row| row["age"].0 != Data::NUMBER(40.0))
+            .count();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn test_contains_on_array_column() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("tags".to_string(), (Type::ARRAYNULL, String::new()));
+        fields.insert("lang".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "posts".to_string()).unwrap();
+
+        let tags = |tags: &[&str]| Data::ARRAYNULL(Some(tags.iter().map(|t| Data::STRING(t.to_string())).collect()));
+        for (id, tags) in [
+            ("p1", tags(&["rust", "db"])),
+            ("p2", tags(&["go"])),
+            ("p3", tags(&["db", "rust", "async"])),
+            ("p4", tags(&[])),
+            ("p5", Data::ARRAYNULL(None)),
+        ] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("tags".to_string(), (tags, String::new()));
+            row.insert("lang".to_string(), (Data::STRING("rust".to_string()), String::new()));
+            db.add_row("posts".to_string(), row, false).unwrap();
+        }
+
+        let tagged = |tag: &str| {
+            let mut ids: Vec<String> = db
+                .query("posts".to_string())
+                .where_("tags", Operator::Contains, Data::STRING(tag.to_string()))
+                .execute()
+                .into_iter()
+                .map(|row| row["id"].0.to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(tagged("rust"), ["p1", "p3"]);
+        assert_eq!(tagged("go"), ["p2"]);
+        assert!(tagged("java").is_empty());
+
+        // a string field holding the value isn't a match
+        let on_string = db
+            .query("posts".to_string())
+            .where_("lang", Operator::Contains, Data::STRING("rust".to_string()))
+            .count();
+        assert_eq!(on_string, 0);
+    }
+
+    #[test]
+    fn test_is_null_on_stringnull_column() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("middle_name".to_string(), (Type::STRINGNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "people".to_string()).unwrap();
+
+        for (id, middle) in [("p1", Some("Ann")), ("p2", None), ("p3", Some("Lee"))] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("middle_name".to_string(), (Data::STRINGNULL(middle.map(str::to_string)), String::new()));
+            db.add_row("people".to_string(), row, false).unwrap();
+        }
+        // the field left out entirely counts as null
+        let row = HashMap::from([("id".to_string(), (Data::STRING("p4".to_string()), String::new()))]);
+        db.add_row("people".to_string(), row, false).unwrap();
+
+        let ids = |rows: Vec<Row>| {
+            let mut ids: Vec<Data> = rows.into_iter().map(|row| row["id"].0.clone()).collect();
+            ids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            ids
+        };
+        let strings = |ids: &[&str]| ids.iter().map(|id| Data::STRING(id.to_string())).collect::<Vec<_>>();
+
+        let null = db.query("people".to_string()).is_null("middle_name").execute();
+        assert_eq!(ids(null), strings(&["p2", "p4"]));
+
+        let set = db
+            .query("people".to_string())
+            .where_("middle_name", Operator::IsNotNull, Data::NULL)
+            .execute();
+        assert_eq!(ids(set), strings(&["p1", "p3"]));
+
+        assert_eq!(db.query("people".to_string()).is_not_null("id").count(), 4);
+    }
+
+    #[test]
+    fn test_group_count_having() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("status".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+
+        let counts = [("shipped", 7), ("pending", 6), ("cancelled", 2)];
+        let mut i = 0;
+        for (status, n) in counts {
+            for _ in 0..n {
+                let mut row = HashMap::new();
+                row.insert("id".to_string(), (Data::STRING(format!("o{}", i)), String::new()));
+                row.insert("status".to_string(), (Data::STRING(status.to_string()), String::new()));
+                db.add_row("orders".to_string(), row, false).unwrap();
+                i += 1;
+            }
+        }
+
+        let groups = db.query("orders".to_string()).group_count("status");
+        assert_eq!(groups.groups.len(), 3);
+
+        let busy = groups.having(6);
+        assert_eq!(
+            busy.groups,
+            vec![
+                (Data::STRING("pending".to_string()), 6),
+                (Data::STRING("shipped".to_string()), 7),
+            ]
+        );
+
+        let over_five_not_pending = db
+            .query("orders".to_string())
+            .group_count("status")
+            .having_by(|status, count| count > 5 && *status != Data::STRING("pending".to_string()));
+        assert_eq!(over_five_not_pending.groups, vec![(Data::STRING("shipped".to_string()), 7)]);
+    }
+
+    #[test]
+    fn test_sort_by_boolean_and_nulls() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("active".to_string(), (Type::BOOLEAN, String::new()));
+        fields.insert("score".to_string(), (Type::NUMBERNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        let rows = [
+            ("a", true, Some(3.0)),
+            ("b", false, None),
+            ("c", true, Some(1.0)),
+            ("d", false, Some(2.0)),
+        ];
+        for (id, active, score) in rows {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("active".to_string(), (Data::BOOLEAN(active), String::new()));
+            row.insert("score".to_string(), (Data::NUMBERNULL(score), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let active: Vec<Data> = db
+            .query("users".to_string())
+            .sort_by("active", true)
+            .execute()
+            .into_iter()
+            .map(|row| row["active"].0.clone())
+            .collect();
+        assert_eq!(
+            active,
+            vec![Data::BOOLEAN(false), Data::BOOLEAN(false), Data::BOOLEAN(true), Data::BOOLEAN(true)]
+        );
+
+        let ids: Vec<Data> = db
+            .query("users".to_string())
+            .sort_by("score", true)
+            .execute()
+            .into_iter()
+            .map(|row| row["id"].0.clone())
+            .collect();
+        let expected: Vec<Data> = ["b", "c", "d", "a"].iter().map(|id| Data::STRING(id.to_string())).collect();
+        assert_eq!(ids, expected);
+
+        let descending = db.query("users".to_string()).sort_by("score", false).first().unwrap();
+        assert_eq!(descending["id"].0, Data::STRING("a".to_string()));
+    }
+
+    #[test]
+    fn test_explain_reports_shards_and_rows() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("age".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..30 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("age".to_string(), (Data::NUMBER(i as f64), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let shard_count = fs::read_dir(temp_dir.path().join("users")).unwrap().count();
+        let plan = db
+            .query("users".to_string())
+            .where_("age", Operator::Gt, Data::NUMBER(10.0))
+            .explain();
+        assert_eq!(plan.shards_read, shard_count);
+        assert_eq!(plan.rows_scanned, 30);
+        assert!(!plan.index_available);
+        assert!(!plan.full_materialization);
+
+        let plan = db
+            .query("users".to_string())
+            .where_("id", Operator::Eq, Data::STRING("u1".to_string()))
+            .sort_by("age", true)
+            .explain();
+        assert!(plan.index_available);
+        assert!(plan.full_materialization);
+
+        db.add_index("users", "by_age", "age", false).unwrap();
+        let by_age = |op| db.query("users".to_string()).where_("age", op, Data::NUMBER(3.0)).explain();
+        assert!(by_age(Operator::Eq).index_available);
+        assert!(!by_age(Operator::Gt).index_available);
+    }
+
+    #[test]
+    fn test_case_insensitive_equality() {
+        use crate::crud::u::CMP;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for (id, name) in [("u1", "Alice"), ("u2", "ALICE"), ("u3", "Bob")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let alice = Data::STRING("alice".to_string());
+        assert!(CMP::EQUALCI.calculate(&alice, &Data::STRING("Alice".to_string())));
+        assert!(!CMP::EQUAL.calculate(&alice, &Data::STRING("Alice".to_string())));
+
+        let found = db.get_where("users".to_string(), "name".to_string(), alice.clone(), true, CMP::EQUALCI).unwrap();
+        assert_eq!(found.len(), 2);
+
+        assert_eq!(db.query("users".to_string()).where_("name", Operator::EqCi, alice.clone()).count(), 2);
+        assert_eq!(db.query("users".to_string()).where_("name", Operator::Eq, alice).count(), 0);
+    }
+
+    #[test]
+    fn test_one_zero_one_many() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("name".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for (id, name) in [("u1", "Alice"), ("u2", "Bob"), ("u3", "Bob")] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("name".to_string(), (Data::STRING(name.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let by_name = |name: &str| {
+            db.query("users".to_string())
+                .where_("name", Operator::Eq, Data::STRING(name.to_string()))
+                .one()
+        };
+
+        assert!(by_name("Carol").unwrap().is_none());
+        let alice = by_name("Alice").unwrap().unwrap();
+        assert_eq!(alice["id"].0, Data::STRING("u1".to_string()));
+        assert!(matches!(by_name("Bob"), Err(Error::TooManyRows { table }) if table == "users"));
+    }
+
+    #[test]
+    fn test_map_update_increments_matching_rows() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("team".to_string(), (Type::STRING, String::new()));
+        fields.insert("visits".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for i in 0..20 {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("u{}", i)), String::new()));
+            row.insert("team".to_string(), (Data::STRING(if i % 2 == 0 { "red" } else { "blue" }.to_string()), String::new()));
+            row.insert("visits".to_string(), (Data::NUMBER(i as f64), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        let red = || db.query("users".to_string()).where_("team", Operator::Eq, Data::STRING("red".to_string()));
+        let updated = red()
+            .map_update(|mut row| {
+                if let Some((Data::NUMBER(n), _)) = row.get_mut("visits") {
+                    *n += 1.0;
+                }
+                row
+            })
+            .unwrap();
+        assert_eq!(updated, 10);
+
+        for i in 0..20 {
+            let row = db.get_by_id("users".to_string(), format!("u{}", i)).unwrap().unwrap();
+            let expected = if i % 2 == 0 { i as f64 + 1.0 } else { i as f64 };
+            assert_eq!(row["visits"].0, Data::NUMBER(expected));
+        }
+
+        // a bad output fails the whole update before anything is written
+        let bad = red().map_update(|mut row| {
+            if row["id"].0 == Data::STRING("u18".to_string()) {
+                row.insert("visits".to_string(), (Data::STRING("lots".to_string()), String::new()));
+            } else {
+                row.insert("visits".to_string(), (Data::NUMBER(0.0), String::new()));
+            }
+            row
+        });
+        assert!(matches!(bad, Err(Error::SchemaMismatch(_))));
+        assert_eq!(red().where_("visits", Operator::Eq, Data::NUMBER(0.0)).count(), 0);
+
+        let moved = red().map_update(|mut row| {
+            row.insert("id".to_string(), (Data::STRING("other".to_string()), String::new()));
+            row
+        });
+        assert!(matches!(moved, Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_update_fields_sets_several_columns() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("status".to_string(), (Type::STRING, String::new()));
+        fields.insert("carrier".to_string(), (Type::STRING, String::new()));
+        fields.insert("weight".to_string(), (Type::NUMBER, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+
+        for i in 0..10 {
+            let status = if i < 4 { "pending" } else { "shipped" };
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("o{}", i)), String::new()));
+            row.insert("status".to_string(), (Data::STRING(status.to_string()), String::new()));
+            row.insert("carrier".to_string(), (Data::STRING("none".to_string()), String::new()));
+            row.insert("weight".to_string(), (Data::NUMBER(1.0), String::new()));
+            db.add_row("orders".to_string(), row, false).unwrap();
+        }
+
+        let pending = db
+            .query("orders".to_string())
+            .where_("status", Operator::Eq, Data::STRING("pending".to_string()));
+        let mut update = HashMap::new();
+        update.insert("status".to_string(), (Data::STRING("packed".to_string()), String::new()));
+        update.insert("carrier".to_string(), (Data::STRING("ups".to_string()), String::new()));
+        update.insert("weight".to_string(), (Data::NUMBER(2.5), String::new()));
+        assert_eq!(pending.update_fields(update), 4);
+
+        for i in 0..10 {
+            let row = db.get_by_id("orders".to_string(), format!("o{}", i)).unwrap().unwrap();
+            if i < 4 {
+                assert_eq!(row["status"].0, Data::STRING("packed".to_string()));
+                assert_eq!(row["carrier"].0, Data::STRING("ups".to_string()));
+                assert_eq!(row["weight"].0, Data::NUMBER(2.5));
+            } else {
+                assert_eq!(row["carrier"].0, Data::STRING("none".to_string()));
+            }
+        }
+
+        // one bad field and nothing is written
+        let packed = db
+            .query("orders".to_string())
+            .where_("status", Operator::Eq, Data::STRING("packed".to_string()));
+        let mut bad = HashMap::new();
+        bad.insert("carrier".to_string(), (Data::STRING("dhl".to_string()), String::new()));
+        bad.insert("weight".to_string(), (Data::STRING("heavy".to_string()), String::new()));
+        assert_eq!(packed.update_fields(bad), 0);
+        assert_eq!(packed.where_("carrier", Operator::Eq, Data::STRING("ups".to_string())).count(), 4);
+    }
+
+    #[test]
+    fn test_update_and_delete_return_affected_counts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("status".to_string(), (Type::STRING, String::new()));
+        db.create_table(fields, "id".to_string(), "orders".to_string()).unwrap();
+
+        for i in 0..10 {
+            let status = if i < 4 { "pending" } else { "shipped" };
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("o{}", i)), String::new()));
+            row.insert("status".to_string(), (Data::STRING(status.to_string()), String::new()));
+            db.add_row("orders".to_string(), row, false).unwrap();
+        }
+
+        let pending = db
+            .query("orders".to_string())
+            .where_("status", Operator::Eq, Data::STRING("pending".to_string()));
+        assert_eq!(pending.update_field("status", (Data::STRING("late".to_string()), String::new())).unwrap(), 4);
+
+        let late = db
+            .query("orders".to_string())
+            .where_("status", Operator::Eq, Data::STRING("late".to_string()));
+        let mut new_row = HashMap::new();
+        new_row.insert("status".to_string(), (Data::STRING("cancelled".to_string()), String::new()));
+        assert_eq!(late.update_row(new_row).unwrap(), 4);
+        assert_eq!(late.update_field("status", (Data::STRING("x".to_string()), String::new())).unwrap(), 0);
+
+        let cancelled = db
+            .query("orders".to_string())
+            .where_("status", Operator::Eq, Data::STRING("cancelled".to_string()));
+        assert_eq!(cancelled.delete().unwrap(), 4);
+        assert_eq!(db.count_rows("orders".to_string()), 6);
+    }
+
+    #[test]
+    fn test_starts_with() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("sku".to_string(), (Type::STRINGNULL, String::new()));
+        db.create_table(fields, "id".to_string(), "items".to_string()).unwrap();
+
+        let skus = [Some("AB-100"), Some("AB-200"), Some("CD-100"), None];
+        for (i, sku) in skus.iter().enumerate() {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(format!("i{}", i)), String::new()));
+            if let Some(sku) = sku {
+                row.insert("sku".to_string(), (Data::STRINGNULL(Some(sku.to_string())), String::new()));
+            }
+            db.add_row("items".to_string(), row, false).unwrap();
+        }
+
+        assert_eq!(db.query("items".to_string()).starts_with("sku", "AB-").count(), 2);
+        assert_eq!(db.query("items".to_string()).starts_with("sku", "CD-1").count(), 1);
+        assert_eq!(db.query("items".to_string()).starts_with("sku", "ab-").count(), 0);
+        // every string matches the empty prefix; the row without a sku doesn't
+        assert_eq!(db.query("items".to_string()).starts_with("sku", "").count(), 3);
+    }
+
+    fn setup_profiles() -> (tempfile::TempDir, DATABASE) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().to_str().unwrap().to_string());
+
+        let mut fields = HashMap::new();
+        fields.insert("id".to_string(), (Type::STRING, String::new()));
+        fields.insert("profile".to_string(), (Type::JSON, String::new()));
+        db.create_table(fields, "id".to_string(), "users".to_string()).unwrap();
+
+        for (id, profile) in [
+            ("u1", r#"{"address":{"city":"NYC","zip":10001}}"#),
+            ("u2", r#"{"address":{"city":"LA"}}"#),
+            ("u3", r#"{"name":"no address"}"#),
+        ] {
+            let mut row = HashMap::new();
+            row.insert("id".to_string(), (Data::STRING(id.to_string()), String::new()));
+            row.insert("profile".to_string(), (Data::JSON(profile.to_string()), String::new()));
+            db.add_row("users".to_string(), row, false).unwrap();
+        }
+
+        (temp_dir, db)
+    }
+
+    #[test]
+    fn test_where_nested_json_path() {
+        let (_temp_dir, db) = setup_profiles();
+
+        let results = db
+            .query("users".to_string())
+            .where_("profile.address.city", Operator::Eq, Data::STRING("NYC".to_string()))
+            .execute();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["id"].0, Data::STRING("u1".to_string()));
+
+        let count = db
+            .query("users".to_string())
+            .where_("profile.address.zip", Operator::Gt, Data::NUMBER(10000.0))
+            .count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_where_nested_json_missing_key() {
+        let (_temp_dir, db) = setup_profiles();
+
+        // u3 has no `address`, so it never matches, not even with Ne
+        let results = db
+            .query("users".to_string())
+            .where_("profile.address.city", Operator::Ne, Data::STRING("NYC".to_string()))
+            .execute();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["id"].0, Data::STRING("u2".to_string()));
+
+        assert!(!db
+            .query("users".to_string())
+            .where_("profile.missing.city", Operator::Eq, Data::STRING("NYC".to_string()))
+            .exists());
+    }
+
+    #[test]
+    fn test_where_not_and_missing_fields() {
+        let (_temp_dir, db) = setup_profiles();
+        let ids = |rows: Vec<Row>| {
+            let mut ids = rows.into_iter().map(|row| row["id"].0.to_string()).collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let nyc = Data::STRING("NYC".to_string());
+
+        // u3 has no city: it isn't in NYC, so it matches the negation...
+        let rows = db.query("users".to_string()).where_not("profile.address.city", Operator::Eq, nyc.clone()).execute();
+        assert_eq!(ids(rows), ["u2", "u3"]);
+        // ...while a plain Ne, like every comparison, skips the missing field
+        let rows = db.query("users".to_string()).where_("profile.address.city", Operator::Ne, nyc.clone()).execute();
+        assert_eq!(ids(rows), ["u2"]);
+
+        let rows = db
+            .query("users".to_string())
+            .where_not("profile.address.city", Operator::Eq, nyc)
+            .and("id", Operator::Ne, Data::STRING("u2".to_string()))
+            .execute();
+        assert_eq!(ids(rows), ["u3"]);
+
+        let rows = db.query("users".to_string()).where_not("profile.address", Operator::IsNull, Data::NULL).execute();
+        assert_eq!(ids(rows), ["u1", "u2"]);
+    }
+
+    /// Records the name and fields of every span opened while it's the
+    /// default subscriber.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanRecorder {
+        spans: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            struct Fields(String);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0.push_str(&format!(" {}={:?}", field.name(), value));
+                }
+            }
+
+            let mut fields = Fields(attrs.metadata().name().to_string());
+            attrs.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields.0);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {}
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_execute_and_migrations_open_spans() {
+        let recorder = std::sync::Arc::new(SpanRecorder::default());
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = DATABASE::init(temp_dir.path().join("db").to_str().unwrap().to_string());
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            db.generate_create_table_migration("create_users", "users", "id", vec![("id", "STRING")])
+                .unwrap();
+            db.apply_migrations().unwrap();
+            db.query("users".to_string())
+                .where_("id", Operator::Eq, Data::STRING("u1".to_string()))
+                .and("id", Operator::Ne, Data::STRING("u2".to_string()))
+                .execute();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        assert!(spans.iter().any(|s| s.starts_with("migration file=") && s.contains("create_users")));
+        assert!(spans.contains(&"execute table=users predicates=2".to_string()));
+    }
+
     #[cfg(test)]
-    mod tests {
-        use serde_json::{json, Number, Value};
+    mod bench {
+        use serde_json::{Number, Value};
 
         use super::*;
 